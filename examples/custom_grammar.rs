@@ -26,9 +26,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     // Example 2: Create a grammar manually
-    let mut custom_config = GrammarConfig::default();
-    custom_config.auto_spacing = true;
-    custom_config.max_recursion_depth = 30;
+    let custom_config = GrammarConfig {
+        auto_spacing: true,
+        max_recursion_depth: 30,
+        ..GrammarConfig::default()
+    };
 
     let mut grammar = Grammar::with_config(custom_config);
 
@@ -59,8 +61,10 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut code_grammar = Grammar::new();
 
     // Set a reasonable recursion limit
-    let mut config = GrammarConfig::default();
-    config.max_recursion_depth = 5;
+    let config = GrammarConfig {
+        max_recursion_depth: 5,
+        ..GrammarConfig::default()
+    };
     code_grammar.set_config(config);
 
     // Base case for program
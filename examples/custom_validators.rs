@@ -90,9 +90,7 @@ struct ParenthesesValidator;
 impl GrammarValidator for ParenthesesValidator {
     fn validate(&self, text: &str) -> String {
         // Add spaces before and after parentheses for readability
-        let text = text.replace("(", " ( ").replace(")", " ) ");
-
-        text
+        text.replace("(", " ( ").replace(")", " ) ")
     }
 
     fn name(&self) -> &str {
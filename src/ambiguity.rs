@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::grammar::{Element, Grammar, QueryAstNode};
+use crate::utils::{GrammarError, Result};
+
+/// Options for [`Grammar::find_ambiguity`]
+#[derive(Debug, Clone)]
+pub struct AmbiguityConfig {
+    /// Longest sentence searched, in words
+    pub max_length: usize,
+    /// Most sentences kept per symbol and length; the search skips the rest
+    pub max_sentences: usize,
+}
+
+impl Default for AmbiguityConfig {
+    fn default() -> Self {
+        AmbiguityConfig {
+            max_length: 8,
+            max_sentences: 10_000,
+        }
+    }
+}
+
+/// A sentence with two distinct derivations
+#[derive(Debug, Clone)]
+pub struct Ambiguity {
+    /// The symbol both derivations start from
+    pub symbol: String,
+    /// The sentence, its words separated by single spaces
+    pub sentence: String,
+    /// The two derivation trees
+    pub derivations: [QueryAstNode; 2],
+    /// Child indices from the roots to the first node where the derivations
+    /// choose a different production or split the sentence differently
+    pub path: Vec<usize>,
+}
+
+impl Ambiguity {
+    /// The node of each derivation where they first differ
+    pub fn divergence(&self) -> [&QueryAstNode; 2] {
+        self.derivations
+            .each_ref()
+            .map(|root| self.path.iter().fold(root, |node, &i| &node.children[i]))
+    }
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [first, second] = self.divergence();
+        let production = |node: &QueryAstNode| {
+            node.production
+                .map_or_else(|| "-".to_string(), |p| format!("#{}", p))
+        };
+        writeln!(
+            f,
+            "<{}> derives \"{}\" in two ways, differing at <{}> {} and {}:",
+            self.symbol,
+            self.sentence,
+            first.value,
+            production(first),
+            production(second)
+        )?;
+        writeln!(f, "  {}", first.to_debug_string())?;
+        write!(f, "  {}", second.to_debug_string())
+    }
+}
+
+/// A derivation found by the search, with the derivations of its
+/// non-terminal children by index
+struct Derivation<'g> {
+    symbol: &'g str,
+    production: usize,
+    children: Vec<Option<usize>>,
+    sentence: Vec<String>,
+}
+
+/// Derivations of every defined symbol, built up by sentence length
+struct Search<'g> {
+    grammar: &'g Grammar,
+    config: &'g AmbiguityConfig,
+    derivations: Vec<Derivation<'g>>,
+    /// Up to two derivations of each sentence, by symbol
+    found: HashMap<&'g str, HashMap<Vec<String>, Vec<usize>>>,
+    /// Sentences of each symbol and length, in the order they were found
+    by_length: HashMap<(&'g str, usize), Vec<Vec<String>>>,
+}
+
+/// The words an element that is not a defined non-terminal stands for.
+///
+/// Regexes, variables, picks and undefined symbols stand for one word each,
+/// written as in the grammar, so sentences they could share with other
+/// terminals are not found.
+fn words(element: &Element) -> Vec<String> {
+    match element {
+        Element::Terminal(text) => text.split_whitespace().map(str::to_string).collect(),
+        Element::NonTerminal(name) => vec![format!("<{}>", name)],
+        Element::Regex(regex) => vec![format!("/{}/", regex.pattern())],
+        Element::Bind(binding) => words(&binding.element),
+        Element::Variable(name) => vec![format!("${}", name)],
+        Element::Pick(name) => vec![format!("@pick(${})", name)],
+        Element::Classified(element, _) => words(element),
+        Element::Layout(_) => Vec::new(),
+    }
+}
+
+/// The node the search puts in a tree for an element that is not a defined
+/// non-terminal
+fn leaf(element: &Element) -> QueryAstNode {
+    match element {
+        Element::NonTerminal(name) => QueryAstNode::new("undefined", name),
+        Element::Bind(binding) => leaf(&binding.element),
+        Element::Classified(element, class) => {
+            let mut node = leaf(element);
+            node.class = Some(*class);
+            node
+        }
+        Element::Layout(layout) => QueryAstNode::new("layout", &layout.to_string()),
+        Element::Terminal(text) => QueryAstNode::new("terminal", text),
+        other => QueryAstNode::new("terminal", &words(other).join(" ")),
+    }
+}
+
+impl<'g> Search<'g> {
+    fn new(grammar: &'g Grammar, config: &'g AmbiguityConfig) -> Self {
+        Search {
+            grammar,
+            config,
+            derivations: Vec::new(),
+            found: HashMap::new(),
+            by_length: HashMap::new(),
+        }
+    }
+
+    /// The defined non-terminal an element refers to
+    fn symbol_of(&self, element: &'g Element) -> Option<&'g str> {
+        match element {
+            Element::NonTerminal(name) if self.grammar.has_non_terminal(name) => Some(name),
+            Element::Bind(binding) => self.symbol_of(&binding.element),
+            _ => None,
+        }
+    }
+
+    /// Find every derivation of `length` words, returning the first sentence
+    /// of `start` that gained a second derivation
+    fn extend(&mut self, length: usize, start: &str) -> Option<Vec<String>> {
+        let mut symbols: Vec<&'g String> = self.grammar.rules().keys().collect();
+        symbols.sort();
+
+        // Unit and epsilon productions derive sentences of this length from
+        // others of the same length, so repeat until nothing new is found
+        loop {
+            let mut changed = false;
+            for &symbol in &symbols {
+                for (p, production) in self.grammar.rules()[symbol].iter().enumerate() {
+                    let mut combinations = Vec::new();
+                    self.combine(
+                        &production.elements,
+                        length,
+                        Vec::new(),
+                        Vec::new(),
+                        &mut combinations,
+                    );
+                    for (sentence, children) in combinations {
+                        if self.add(symbol, p, children, sentence.clone()) {
+                            changed = true;
+                            if symbol == start && self.found[symbol.as_str()][&sentence].len() == 2
+                            {
+                                return Some(sentence);
+                            }
+                        }
+                    }
+                }
+            }
+            if !changed {
+                return None;
+            }
+        }
+    }
+
+    /// Every way `elements` derive exactly `remaining` more words
+    fn combine(
+        &self,
+        elements: &'g [Element],
+        remaining: usize,
+        sentence: Vec<String>,
+        children: Vec<Option<usize>>,
+        out: &mut Vec<(Vec<String>, Vec<Option<usize>>)>,
+    ) {
+        let Some((element, rest)) = elements.split_first() else {
+            if remaining == 0 {
+                out.push((sentence, children));
+            }
+            return;
+        };
+
+        let Some(symbol) = self.symbol_of(element) else {
+            let words = words(element);
+            if words.len() <= remaining {
+                let mut sentence = sentence;
+                sentence.extend(words.iter().cloned());
+                let mut children = children;
+                children.push(None);
+                self.combine(rest, remaining - words.len(), sentence, children, out);
+            }
+            return;
+        };
+
+        for length in 0..=remaining {
+            let Some(sentences) = self.by_length.get(&(symbol, length)) else {
+                continue;
+            };
+            for words in sentences {
+                for &id in &self.found[symbol][words] {
+                    let mut sentence = sentence.clone();
+                    sentence.extend(words.iter().cloned());
+                    let mut children = children.clone();
+                    children.push(Some(id));
+                    self.combine(rest, remaining - length, sentence, children, out);
+                }
+            }
+        }
+    }
+
+    /// Record a derivation unless it is already known, the sentence already
+    /// has two or the symbol has too many sentences of this length
+    fn add(
+        &mut self,
+        symbol: &'g str,
+        production: usize,
+        children: Vec<Option<usize>>,
+        sentence: Vec<String>,
+    ) -> bool {
+        let known = self.found.entry(symbol).or_default();
+        let ids = known.get(&sentence).map(Vec::as_slice).unwrap_or_default();
+        let derivations = &self.derivations;
+        if ids.len() >= 2
+            || ids.iter().any(|&id| {
+                derivations[id].production == production && derivations[id].children == children
+            })
+        {
+            return false;
+        }
+
+        if ids.is_empty() {
+            let sentences = self.by_length.entry((symbol, sentence.len())).or_default();
+            if sentences.len() >= self.config.max_sentences {
+                return false;
+            }
+            sentences.push(sentence.clone());
+        }
+
+        let id = self.derivations.len();
+        known.entry(sentence.clone()).or_default().push(id);
+        self.derivations.push(Derivation {
+            symbol,
+            production,
+            children,
+            sentence,
+        });
+        true
+    }
+
+    /// The derivation tree of derivation `id`
+    fn tree(&self, id: usize) -> QueryAstNode {
+        let derivation = &self.derivations[id];
+        let production = &self.grammar.rules()[derivation.symbol][derivation.production];
+        let mut node = QueryAstNode::new("non_terminal", derivation.symbol);
+        node.production = Some(derivation.production);
+        node.children = production
+            .elements
+            .iter()
+            .zip(&derivation.children)
+            .map(|(element, child)| match child {
+                Some(child) => self.tree(*child),
+                None => leaf(element),
+            })
+            .collect();
+        node
+    }
+
+    /// Child indices to the first place derivations `a` and `b` differ
+    fn diverge(&self, a: usize, b: usize) -> Vec<usize> {
+        let (x, y) = (&self.derivations[a], &self.derivations[b]);
+        let same_split = x.production == y.production
+            && x.children.iter().zip(&y.children).all(|pair| match pair {
+                (Some(c), Some(d)) => {
+                    self.derivations[*c].sentence == self.derivations[*d].sentence
+                }
+                _ => true,
+            });
+        if !same_split {
+            return Vec::new();
+        }
+
+        let differing = x
+            .children
+            .iter()
+            .zip(&y.children)
+            .enumerate()
+            .find_map(|(i, pair)| match pair {
+                (Some(c), Some(d)) if c != d => Some((i, *c, *d)),
+                _ => None,
+            });
+        match differing {
+            Some((i, c, d)) => {
+                let mut path = vec![i];
+                path.extend(self.diverge(c, d));
+                path
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Grammar {
+    /// Search for a sentence of `start_symbol` with two distinct derivations,
+    /// shortest first, up to `config.max_length` words.
+    ///
+    /// Terminals are compared word by word and predicates are ignored, so a
+    /// sentence may be reported even though guards rule out one of its
+    /// derivations. `None` means no ambiguity was found within the bounds,
+    /// not that the grammar is unambiguous.
+    pub fn find_ambiguity(
+        &self,
+        start_symbol: &str,
+        config: &AmbiguityConfig,
+    ) -> Result<Option<Ambiguity>> {
+        if !self.has_non_terminal(start_symbol) {
+            return Err(GrammarError::UnknownNonTerminal(start_symbol.to_string()));
+        }
+
+        let mut search = Search::new(self, config);
+        for length in 0..=config.max_length {
+            let Some(sentence) = search.extend(length, start_symbol) else {
+                continue;
+            };
+            let [a, b] = search.found[start_symbol][&sentence][..] else {
+                unreachable!("an ambiguous sentence has two derivations");
+            };
+            return Ok(Some(Ambiguity {
+                symbol: start_symbol.to_string(),
+                sentence: sentence.join(" "),
+                derivations: [search.tree(a), search.tree(b)],
+                path: search.diverge(a, b),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition_grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "id", "WHERE", "<condition>"])
+            .unwrap();
+        grammar
+            .add_rule("condition", vec!["<condition>", "AND", "<condition>"])
+            .unwrap();
+        grammar
+            .add_rule("condition", vec!["<condition>", "OR", "<condition>"])
+            .unwrap();
+        grammar.add_rule("condition", vec!["id", "=", "1"]).unwrap();
+        grammar
+    }
+
+    #[test]
+    fn test_finds_minimal_witness() {
+        let grammar = condition_grammar();
+        let config = AmbiguityConfig {
+            max_length: 14,
+            ..AmbiguityConfig::default()
+        };
+        let ambiguity = grammar.find_ambiguity("query", &config).unwrap().unwrap();
+
+        assert_eq!(ambiguity.symbol, "query");
+        assert_eq!(
+            ambiguity.sentence,
+            "SELECT id WHERE id = 1 AND id = 1 AND id = 1"
+        );
+        assert_eq!(ambiguity.path, vec![3]);
+
+        let [first, second] = ambiguity.divergence();
+        assert_eq!(first.value, "condition");
+        assert_eq!(first.production, Some(0));
+        assert_ne!(
+            first.children[0].to_debug_string(),
+            second.children[0].to_debug_string()
+        );
+        for tree in &ambiguity.derivations {
+            assert_eq!(tree.to_string(), ambiguity.sentence);
+        }
+        assert!(ambiguity.to_string().contains("differing at <condition>"));
+    }
+
+    #[test]
+    fn test_unambiguous_within_bound() {
+        let mut grammar = Grammar::new();
+        grammar.add_rule("list", vec!["<item>"]).unwrap();
+        grammar
+            .add_rule("list", vec!["<item>", ",", "<list>"])
+            .unwrap();
+        grammar.add_rule("item", vec!["id"]).unwrap();
+        grammar.add_rule("item", vec!["/[a-z]+/"]).unwrap();
+
+        let config = AmbiguityConfig {
+            max_length: 7,
+            ..AmbiguityConfig::default()
+        };
+        assert!(grammar.find_ambiguity("list", &config).unwrap().is_none());
+        assert!(grammar.find_ambiguity("missing", &config).is_err());
+
+        // The condition grammar needs three operands to be ambiguous
+        let config = AmbiguityConfig {
+            max_length: 10,
+            ..AmbiguityConfig::default()
+        };
+        assert!(
+            condition_grammar()
+                .find_ambiguity("query", &config)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_unit_cycle_and_split_terminals() {
+        let mut grammar = Grammar::new();
+        grammar.add_rule("a", vec!["x"]).unwrap();
+        grammar.add_rule("a", vec!["<b>"]).unwrap();
+        grammar.add_rule("b", vec!["<a>"]).unwrap();
+        let ambiguity = grammar
+            .find_ambiguity("a", &AmbiguityConfig::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ambiguity.sentence, "x");
+        assert!(ambiguity.path.is_empty());
+
+        let mut grammar = Grammar::new();
+        grammar.add_rule("join", vec!["LEFT JOIN"]).unwrap();
+        grammar.add_rule("join", vec!["LEFT", "JOIN"]).unwrap();
+        let ambiguity = grammar
+            .find_ambiguity("join", &AmbiguityConfig::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ambiguity.sentence, "LEFT JOIN");
+    }
+}
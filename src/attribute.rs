@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::grammar::{Grammar, QueryAstNode};
+use crate::render::Piece;
+
+/// A value computed for a non-terminal during generation, serialized as the
+/// plain JSON value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AttrValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<AttrValue>),
+}
+
+impl AttrValue {
+    /// The value as a bool, if it is one
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            AttrValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, if it is one
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            AttrValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, widening integers
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            AttrValue::Float(value) => Some(*value),
+            AttrValue::Int(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, if it is one
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            AttrValue::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value as a list, if it is one
+    pub fn as_list(&self) -> Option<&[AttrValue]> {
+        match self {
+            AttrValue::List(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AttrValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttrValue::Bool(value) => write!(f, "{}", value),
+            AttrValue::Int(value) => write!(f, "{}", value),
+            AttrValue::Float(value) => write!(f, "{}", value),
+            AttrValue::Str(value) => write!(f, "{:?}", value),
+            AttrValue::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl From<bool> for AttrValue {
+    fn from(value: bool) -> Self {
+        AttrValue::Bool(value)
+    }
+}
+
+impl From<i64> for AttrValue {
+    fn from(value: i64) -> Self {
+        AttrValue::Int(value)
+    }
+}
+
+impl From<f64> for AttrValue {
+    fn from(value: f64) -> Self {
+        AttrValue::Float(value)
+    }
+}
+
+impl From<&str> for AttrValue {
+    fn from(value: &str) -> Self {
+        AttrValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(value: String) -> Self {
+        AttrValue::Str(value)
+    }
+}
+
+impl<T: Into<AttrValue>> From<Vec<T>> for AttrValue {
+    fn from(values: Vec<T>) -> Self {
+        AttrValue::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// When an attribute is evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeKind {
+    /// Computed from the parent before the symbol expands, so parents can
+    /// constrain their children
+    Inherited,
+    /// Computed from the children once the symbol has expanded
+    Synthesized,
+}
+
+/// An attribute declared on a symbol together with its evaluation rule
+#[derive(Clone)]
+pub struct AttributeRule {
+    pub name: String,
+    pub kind: AttributeKind,
+    rule: Arc<dyn Fn(&AttributeContext) -> AttrValue + Send + Sync>,
+}
+
+impl AttributeRule {
+    /// Declare attribute `name` computed by `rule`
+    pub fn new<F>(name: &str, kind: AttributeKind, rule: F) -> Self
+    where
+        F: Fn(&AttributeContext) -> AttrValue + Send + Sync + 'static,
+    {
+        AttributeRule {
+            name: name.to_string(),
+            kind,
+            rule: Arc::new(rule),
+        }
+    }
+
+    /// Evaluate the rule
+    pub fn evaluate(&self, context: &AttributeContext) -> AttrValue {
+        (self.rule)(context)
+    }
+}
+
+impl fmt::Debug for AttributeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AttributeRule({:?} {})", self.kind, self.name)
+    }
+}
+
+/// What an attribute rule can see.
+///
+/// Inherited rules run before the symbol expands: `node` carries only the
+/// attributes computed so far, and `parent` is the partially built parent
+/// with its attributes and the siblings to the left. Synthesized rules run
+/// after expansion, with `node` complete and no parent.
+pub struct AttributeContext<'a> {
+    pub(crate) grammar: &'a Grammar,
+    pub(crate) node: &'a QueryAstNode,
+    pub(crate) parent: Option<&'a QueryAstNode>,
+    pub(crate) tokens: &'a [Piece],
+}
+
+impl AttributeContext<'_> {
+    /// The node the attribute is computed for
+    pub fn node(&self) -> &QueryAstNode {
+        self.node
+    }
+
+    /// The parent node, for inherited attributes of a non-root symbol
+    pub fn parent(&self) -> Option<&QueryAstNode> {
+        self.parent
+    }
+
+    /// Text generated by the node (empty for inherited attributes)
+    pub fn text(&self) -> String {
+        self.grammar.render_pieces(self.tokens)
+    }
+
+    /// An attribute of the parent, for inherited attributes
+    pub fn inherited(&self, name: &str) -> Option<&AttrValue> {
+        self.parent.and_then(|parent| parent.attribute(name))
+    }
+
+    /// Values of an attribute on the node's children that define it
+    pub fn children(&self, name: &str) -> Vec<&AttrValue> {
+        self.node
+            .children
+            .iter()
+            .filter_map(|child| child.attribute(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attr_value_conversions() {
+        let columns = AttrValue::from(vec!["id", "name"]);
+        assert_eq!(columns.to_string(), r#"["id", "name"]"#);
+        assert_eq!(columns.as_list().map(<[_]>::len), Some(2));
+
+        assert_eq!(AttrValue::from(3).as_float(), Some(3.0));
+        assert_eq!(AttrValue::from(true).as_int(), None);
+        assert_eq!(AttrValue::from("int").as_str(), Some("int"));
+    }
+}
@@ -0,0 +1,117 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::any::Any;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use crate::grammar::{Grammar, QueryAst};
+use crate::utils::{GrammarError, Result};
+
+/// Seed for item `index` of a batch, mixed with SplitMix64 so neighbouring
+/// items get unrelated random streams
+pub fn item_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The random generator used for item `index` of a batch
+pub fn item_rng(seed: u64, index: usize) -> StdRng {
+    StdRng::seed_from_u64(item_seed(seed, index as u64))
+}
+
+/// Number of worker threads to use for `jobs`, where 0 means one per CPU
+pub fn worker_count(jobs: usize) -> usize {
+    if jobs == 0 {
+        thread::available_parallelism().map_or(1, NonZeroUsize::get)
+    } else {
+        jobs
+    }
+}
+
+/// Queries a worker generates before the chunks of a round are handed on
+const CHUNK_SIZE: usize = 256;
+
+/// Generate `count` queries on `jobs` scoped threads, passing each to `each`
+/// in index order.
+///
+/// Work proceeds in rounds of one chunk per worker, so at most that many
+/// queries are held at once whatever `count` is. An invalid grammar is
+/// reported before any work starts.
+pub fn for_each_in_batch<F>(
+    grammar: &Grammar,
+    start_symbol: &str,
+    count: usize,
+    seed: u64,
+    jobs: usize,
+    mut each: F,
+) -> Result<()>
+where
+    F: FnMut(usize, QueryAst) -> Result<()>,
+{
+    grammar.check_predicates()?;
+    let generate = |index| grammar.generate_with_rng(start_symbol, &mut item_rng(seed, index));
+
+    let workers = worker_count(jobs).min(count.max(1));
+    if workers == 1 {
+        // A panic is reported like one on a worker thread
+        for index in 0..count {
+            let ast = panic::catch_unwind(AssertUnwindSafe(|| generate(index)))
+                .map_err(worker_panicked)?;
+            each(index, ast)?;
+        }
+        return Ok(());
+    }
+
+    let round = workers * CHUNK_SIZE;
+    for first in (0..count).step_by(round) {
+        let last = (first + round).min(count);
+        let chunks = thread::scope(|scope| {
+            let handles: Vec<_> = (first..last)
+                .step_by(CHUNK_SIZE)
+                .map(|start| {
+                    let end = (start + CHUNK_SIZE).min(last);
+                    scope.spawn(move || (start..end).map(generate).collect::<Vec<_>>())
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().map_err(worker_panicked))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        for (index, ast) in (first..).zip(chunks.into_iter().flatten()) {
+            each(index, ast)?;
+        }
+    }
+    Ok(())
+}
+
+/// Generate `count` queries on `jobs` scoped threads, returned in index order
+pub fn generate_batch(
+    grammar: &Grammar,
+    start_symbol: &str,
+    count: usize,
+    seed: u64,
+    jobs: usize,
+) -> Result<Vec<QueryAst>> {
+    let mut generated = Vec::with_capacity(count);
+    for_each_in_batch(grammar, start_symbol, count, seed, jobs, |_, ast| {
+        generated.push(ast);
+        Ok(())
+    })?;
+    Ok(generated)
+}
+
+/// The error for a generation that panicked, with its panic message
+fn worker_panicked(payload: Box<dyn Any + Send>) -> GrammarError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    GrammarError::WorkerPanicked(message)
+}
@@ -0,0 +1,343 @@
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+use crate::attribute::AttrValue;
+use crate::grammar::{QueryAst, QueryAstNode};
+use crate::token::TokenClass;
+use crate::utils::{GrammarError, Result};
+
+/// Version of the serialized AST schema, written into every document.
+///
+/// A document is an object with `version`, `type_name`, `text` and `root`.
+/// Each node has `kind` (`non_terminal`, `terminal`, `layout`, `undefined`
+/// or `error`), `value` (the symbol name of a non-terminal or the text of a
+/// terminal), `production`, `class`, `span`, `attributes` and `children`.
+/// `span` is the `[start, end)` byte range of the node in `text`, or null
+/// when the text does not match the tree. Spans are only written for a whole
+/// [`QueryAst`] and are ignored when reading.
+pub const AST_SCHEMA_VERSION: u32 = 1;
+
+/// Leading bytes of the binary encoding, followed by the schema version
+const BINARY_MAGIC: &[u8; 4] = b"QAST";
+
+#[derive(Serialize, Deserialize)]
+struct AstDocument {
+    version: u32,
+    type_name: String,
+    text: String,
+    root: NodeRecord,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeRecord {
+    kind: String,
+    value: String,
+    #[serde(default)]
+    production: Option<usize>,
+    #[serde(default)]
+    class: Option<TokenClass>,
+    #[serde(default)]
+    span: Option<(usize, usize)>,
+    #[serde(default)]
+    attributes: BTreeMap<String, AttrValue>,
+    #[serde(default)]
+    children: Vec<NodeRecord>,
+}
+
+/// Just the version of a document, read before the rest so a newer schema
+/// is reported as such rather than as a malformed document
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+type Span = Option<(usize, usize)>;
+
+impl NodeRecord {
+    /// Record `node`, taking spans in preorder from `spans` when given
+    fn new(node: &QueryAstNode, spans: &mut Option<std::vec::IntoIter<Span>>) -> Self {
+        let span = spans.as_mut().and_then(|spans| spans.next().flatten());
+        NodeRecord {
+            kind: node.element_type.clone(),
+            value: node.value.clone(),
+            production: node.production,
+            class: node.class,
+            span,
+            attributes: node.attributes.clone(),
+            children: node
+                .children
+                .iter()
+                .map(|child| NodeRecord::new(child, spans))
+                .collect(),
+        }
+    }
+
+    fn into_node(self) -> QueryAstNode {
+        let mut node = QueryAstNode::new(&self.kind, &self.value);
+        node.production = self.production;
+        node.class = self.class;
+        node.attributes = self.attributes;
+        node.children = self
+            .children
+            .into_iter()
+            .map(NodeRecord::into_node)
+            .collect();
+        node
+    }
+}
+
+/// Byte ranges of every node of `root` in `text`, in preorder.
+///
+/// Renderers only add whitespace between terminals, so each terminal is
+/// found after the previous one with nothing but whitespace in between.
+/// Returns `None` when the text was not rendered from this tree.
+fn spans(root: &QueryAstNode, text: &str) -> Option<Vec<Span>> {
+    fn visit(node: &QueryAstNode, text: &str, cursor: &mut usize, out: &mut Vec<Span>) -> bool {
+        let token = match node.element_type.as_str() {
+            "terminal" => node.value.clone(),
+            "undefined" | "error" => format!("<{}>", node.value),
+            "layout" => {
+                out.push(Some((*cursor, *cursor)));
+                return true;
+            }
+            _ => {
+                let index = out.len();
+                out.push(None);
+                let start = *cursor;
+                let mut first = None;
+                for child in &node.children {
+                    let child_index = out.len();
+                    if !visit(child, text, cursor, out) {
+                        return false;
+                    }
+                    if first.is_none() && out[child_index].is_some_and(|(start, end)| start < end) {
+                        first = out[child_index].map(|(start, _)| start);
+                    }
+                }
+                out[index] = Some((first.unwrap_or(start).min(*cursor), *cursor));
+                return true;
+            }
+        };
+
+        let token = token.trim();
+        if token.is_empty() {
+            out.push(Some((*cursor, *cursor)));
+            return true;
+        }
+        let rest = &text[*cursor..];
+        let Some(offset) = rest.find(token) else {
+            return false;
+        };
+        if !rest[..offset].trim().is_empty() {
+            return false;
+        }
+        let start = *cursor + offset;
+        *cursor = start + token.len();
+        out.push(Some((start, *cursor)));
+        true
+    }
+
+    let mut out = Vec::new();
+    let mut cursor = 0;
+    if !visit(root, text, &mut cursor, &mut out) || !text[cursor..].trim().is_empty() {
+        return None;
+    }
+    Some(out)
+}
+
+impl Serialize for QueryAstNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        NodeRecord::new(self, &mut None).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryAstNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(NodeRecord::deserialize(deserializer)?.into_node())
+    }
+}
+
+impl Serialize for QueryAst {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut spans = Some(
+            spans(&self.root, &self.text)
+                .unwrap_or_default()
+                .into_iter(),
+        );
+        AstDocument {
+            version: AST_SCHEMA_VERSION,
+            type_name: self.type_name.clone(),
+            text: self.text.clone(),
+            root: NodeRecord::new(&self.root, &mut spans),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryAst {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let document = AstDocument::deserialize(deserializer)?;
+        if document.version != AST_SCHEMA_VERSION {
+            return Err(D::Error::custom(unsupported(document.version)));
+        }
+        Ok(QueryAst {
+            text: document.text,
+            type_name: document.type_name,
+            root: document.root.into_node(),
+        })
+    }
+}
+
+fn unsupported(version: u32) -> String {
+    format!(
+        "unsupported AST schema version {}, expected {}",
+        version, AST_SCHEMA_VERSION
+    )
+}
+
+impl QueryAst {
+    /// Serialize the AST as a single line of JSON
+    pub fn to_json_string(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(GrammarError::Json)
+    }
+
+    /// Serialize the AST as pretty-printed JSON
+    pub fn to_json_string_pretty(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(GrammarError::Json)
+    }
+
+    /// Load an AST from JSON written by [`QueryAst::to_json_string`]
+    pub fn from_json_str(json: &str) -> Result<Self> {
+        let probe: VersionProbe = serde_json::from_str(json)?;
+        if probe.version != AST_SCHEMA_VERSION {
+            return Err(GrammarError::Parse(unsupported(probe.version)));
+        }
+        serde_json::from_str(json).map_err(GrammarError::Json)
+    }
+
+    /// Encode the AST compactly for large corpora.
+    ///
+    /// The encoding is the JSON schema as MessagePack with fields in order
+    /// rather than named, after a `QAST` marker and the schema version byte.
+    pub fn to_binary(&self) -> Result<Vec<u8>> {
+        let mut bytes = BINARY_MAGIC.to_vec();
+        bytes.push(AST_SCHEMA_VERSION as u8);
+        rmp_serde::encode::write(&mut bytes, self)
+            .map_err(|e| GrammarError::Binary(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    /// Decode an AST written by [`QueryAst::to_binary`]
+    pub fn from_binary(bytes: &[u8]) -> Result<Self> {
+        let Some(body) = bytes.strip_prefix(BINARY_MAGIC.as_slice()) else {
+            return Err(GrammarError::Binary("missing QAST marker".to_string()));
+        };
+        match body.split_first() {
+            Some((&version, body)) if u32::from(version) == AST_SCHEMA_VERSION => {
+                rmp_serde::from_slice(body).map_err(|e| GrammarError::Binary(e.to_string()))
+            }
+            Some((&version, _)) => Err(GrammarError::Binary(unsupported(version.into()))),
+            None => Err(GrammarError::Binary("missing schema version".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ast() -> QueryAst {
+        let mut column = QueryAstNode::new("non_terminal", "column");
+        column.production = Some(1);
+        column
+            .attributes
+            .insert("name".to_string(), AttrValue::from("id"));
+        column
+            .attributes
+            .insert("width".to_string(), AttrValue::Int(4));
+        let mut id = QueryAstNode::new("terminal", "id");
+        id.class = Some(TokenClass::Identifier);
+        column.children = vec![id];
+
+        let mut root = QueryAstNode::new("non_terminal", "query");
+        root.production = Some(0);
+        root.children = vec![
+            QueryAstNode::new("terminal", "SELECT"),
+            QueryAstNode::new("layout", "@newline"),
+            column,
+            QueryAstNode::new("terminal", ","),
+            QueryAstNode::new("terminal", "'a b'"),
+        ];
+        QueryAst {
+            text: "SELECT\nid, 'a b'".to_string(),
+            type_name: "query".to_string(),
+            root,
+        }
+    }
+
+    #[test]
+    fn test_json_schema_and_round_trip() {
+        let ast = ast();
+        let value: serde_json::Value =
+            serde_json::from_str(&ast.to_json_string().unwrap()).unwrap();
+        assert_eq!(value["version"], json!(AST_SCHEMA_VERSION));
+        assert_eq!(value["root"]["span"], json!([0, 16]));
+        assert_eq!(value["root"]["production"], json!(0));
+
+        let column = &value["root"]["children"][2];
+        assert_eq!(column["kind"], json!("non_terminal"));
+        assert_eq!(column["value"], json!("column"));
+        assert_eq!(column["span"], json!([7, 9]));
+        assert_eq!(column["attributes"], json!({"name": "id", "width": 4}));
+        assert_eq!(column["children"][0]["class"], json!("identifier"));
+        assert_eq!(value["root"]["children"][1]["span"], json!([6, 6]));
+        assert_eq!(value["root"]["children"][4]["span"], json!([11, 16]));
+
+        let loaded = QueryAst::from_json_str(&ast.to_json_string_pretty().unwrap()).unwrap();
+        assert_eq!(loaded.text, ast.text);
+        assert_eq!(loaded.root.to_debug_string(), ast.root.to_debug_string());
+        assert_eq!(
+            loaded.root.children[2].attributes,
+            ast.root.children[2].attributes
+        );
+        assert_eq!(loaded.root.children[2].production, Some(1));
+
+        // Text edited away from the tree leaves spans out
+        let mut edited = ast.clone();
+        edited.text = "SELECT name".to_string();
+        let value = serde_json::to_value(&edited).unwrap();
+        assert_eq!(value["root"]["span"], json!(null));
+
+        let newer = json!({"version": 99, "type_name": "q"}).to_string();
+        assert!(matches!(
+            QueryAst::from_json_str(&newer),
+            Err(GrammarError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let ast = ast();
+        let bytes = ast.to_binary().unwrap();
+        assert!(bytes.len() < ast.to_json_string().unwrap().len());
+
+        let loaded = QueryAst::from_binary(&bytes).unwrap();
+        assert_eq!(loaded.text, ast.text);
+        assert_eq!(loaded.root.to_debug_string(), ast.root.to_debug_string());
+        assert_eq!(
+            loaded.root.children[2].attributes,
+            ast.root.children[2].attributes
+        );
+        assert_eq!(
+            loaded.root.children[2].children[0].class,
+            Some(TokenClass::Identifier)
+        );
+
+        assert!(QueryAst::from_binary(&bytes[1..]).is_err());
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(QueryAst::from_binary(&newer).is_err());
+    }
+}
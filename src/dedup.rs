@@ -0,0 +1,325 @@
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::grammar::{Grammar, QueryAst, QueryAstNode};
+use crate::token::is_number;
+use crate::utils::GrammarError;
+
+/// What makes two generated queries the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupKey {
+    /// The rendered text
+    Text,
+    /// The shape of the AST: symbols and productions, with numbers and
+    /// quoted strings normalised so queries differing only in literals match
+    Structure,
+}
+
+/// How seen queries are remembered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeenFilter {
+    /// A set of 64-bit hashes; never reports a new query as seen except on
+    /// a hash collision
+    Exact,
+    /// A Bloom filter with fixed memory, sized for `capacity` queries at the
+    /// given false positive rate. A false positive only skips a new query.
+    Bloom {
+        capacity: usize,
+        false_positive_rate: f64,
+    },
+}
+
+/// Configuration for deduplicated generation
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    pub key: DedupKey,
+    pub filter: SeenFilter,
+    /// Consecutive duplicates or rejected generations after which the
+    /// language is considered exhausted
+    pub max_attempts: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            key: DedupKey::Text,
+            filter: SeenFilter::Exact,
+            max_attempts: 1000,
+        }
+    }
+}
+
+/// Remembers hashes of generated queries
+#[derive(Debug, Clone)]
+pub enum SeenSet {
+    Exact(HashSet<u64>),
+    Bloom(BloomFilter),
+}
+
+impl SeenSet {
+    /// Create an empty set of the given kind
+    pub fn new(filter: SeenFilter) -> Self {
+        match filter {
+            SeenFilter::Exact => SeenSet::Exact(HashSet::new()),
+            SeenFilter::Bloom {
+                capacity,
+                false_positive_rate,
+            } => SeenSet::Bloom(BloomFilter::new(capacity, false_positive_rate)),
+        }
+    }
+
+    /// Record a hash, returning whether it was new
+    pub fn insert(&mut self, hash: u64) -> bool {
+        match self {
+            SeenSet::Exact(set) => set.insert(hash),
+            SeenSet::Bloom(bloom) => bloom.insert(hash),
+        }
+    }
+}
+
+/// A fixed-size Bloom filter over 64-bit hashes
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    size: u64,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Size a filter for `capacity` items at the given false positive rate
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let size = (-capacity * rate.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let hashes = ((size as f64 / capacity) * ln2).round().clamp(1.0, 32.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; size.div_ceil(64) as usize],
+            size,
+            hashes,
+        }
+    }
+
+    /// Set the bits for `hash`, returning whether any of them was unset
+    pub fn insert(&mut self, hash: u64) -> bool {
+        let mut new = false;
+        for (word, mask) in self.positions(hash) {
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        new
+    }
+
+    /// Whether `hash` may have been inserted
+    pub fn contains(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+
+    /// The (word, mask) of each bit for `hash`, derived from its two halves
+    /// by double hashing
+    fn positions(&self, hash: u64) -> impl Iterator<Item = (usize, u64)> + use<> {
+        let (h1, h2, size) = (hash, hash.rotate_left(32) | 1, self.size);
+        (0..u64::from(self.hashes)).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % size;
+            ((bit / 64) as usize, 1u64 << (bit % 64))
+        })
+    }
+
+    /// Memory used by the bit array, in bytes
+    pub fn memory(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+/// Hash a query by the configured key
+pub fn dedup_hash(ast: &QueryAst, key: DedupKey) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match key {
+        DedupKey::Text => ast.text.hash(&mut hasher),
+        DedupKey::Structure => hash_structure(&ast.root, &mut false, &mut hasher),
+    }
+    hasher.finish()
+}
+
+fn hash_structure(node: &QueryAstNode, in_quotes: &mut bool, hasher: &mut DefaultHasher) {
+    match node.element_type.as_str() {
+        "terminal" if node.value == "'" || node.value == "\"" => {
+            *in_quotes = !*in_quotes;
+            node.value.hash(hasher);
+        }
+        "terminal" if *in_quotes || is_literal(&node.value) => "<literal>".hash(hasher),
+        "non_terminal" => {
+            node.value.hash(hasher);
+            node.production.hash(hasher);
+            node.children.len().hash(hasher);
+            for child in &node.children {
+                hash_structure(child, in_quotes, hasher);
+            }
+        }
+        _ => {
+            node.element_type.hash(hasher);
+            node.value.hash(hasher);
+        }
+    }
+}
+
+/// Numbers and quoted strings
+fn is_literal(text: &str) -> bool {
+    let quoted = text.len() >= 2
+        && ((text.starts_with('\'') && text.ends_with('\''))
+            || (text.starts_with('"') && text.ends_with('"')));
+    quoted || is_number(text)
+}
+
+/// Iterator over generated queries that skips ones already produced.
+///
+/// Generations that predicates reject are skipped too, rather than yielding
+/// `<no_valid_derivation>`. Ends once `max_attempts` generations in a row
+/// were all duplicates or rejected, which suggests the grammar's language is
+/// exhausted.
+///
+/// # Panics
+///
+/// When the grammar is invalid, like [`Grammar::generate`].
+pub struct UniqueGenerator<'g> {
+    grammar: &'g Grammar,
+    start_symbol: String,
+    config: DedupConfig,
+    seen: SeenSet,
+    duplicates: usize,
+    failures: usize,
+    exhausted: bool,
+}
+
+impl<'g> UniqueGenerator<'g> {
+    /// Generate unique queries for `start_symbol`
+    pub fn new(grammar: &'g Grammar, start_symbol: &str, config: DedupConfig) -> Self {
+        UniqueGenerator {
+            grammar,
+            start_symbol: start_symbol.to_string(),
+            seen: SeenSet::new(config.filter),
+            config,
+            duplicates: 0,
+            failures: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Whether generation stopped because no new query was found
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Total duplicates skipped so far
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Total generations skipped so far because no derivation was valid
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+}
+
+impl Iterator for UniqueGenerator<'_> {
+    type Item = QueryAst;
+
+    fn next(&mut self) -> Option<QueryAst> {
+        if self.exhausted {
+            return None;
+        }
+
+        for _ in 0..self.config.max_attempts.max(1) {
+            let ast = match self.grammar.try_generate(&self.start_symbol) {
+                Ok(ast) => ast,
+                Err(GrammarError::NoValidDerivation(_)) => {
+                    self.failures += 1;
+                    continue;
+                }
+                Err(error) => panic!("{}", error),
+            };
+            if self.seen.insert(dedup_hash(&ast, self.config.key)) {
+                return Some(ast);
+            }
+            self.duplicates += 1;
+        }
+
+        self.exhausted = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter_remembers_inserted_hashes() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for hash in 0..1000u64 {
+            bloom.insert(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        }
+        for hash in 0..1000u64 {
+            assert!(!bloom.insert(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+        }
+
+        let false_positives = (1000..11000u64)
+            .filter(|hash| bloom.contains(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+            .count();
+        assert!(false_positives < 300, "{}", false_positives);
+        assert!(bloom.memory() < 2048);
+    }
+
+    #[test]
+    fn test_structure_ignores_literals() {
+        let query = |limit: &str, name: &str| {
+            let mut root = QueryAstNode::new("non_terminal", "query");
+            root.production = Some(0);
+            for value in ["LIMIT", limit, "'", name, "'"] {
+                root.children.push(QueryAstNode::new("terminal", value));
+            }
+            QueryAst {
+                text: root.to_string(),
+                type_name: "query".to_string(),
+                root,
+            }
+        };
+
+        let (a, b) = (query("10", "bob"), query("2.5", "alice"));
+        assert_eq!(
+            dedup_hash(&a, DedupKey::Structure),
+            dedup_hash(&b, DedupKey::Structure)
+        );
+        assert_ne!(
+            dedup_hash(&a, DedupKey::Text),
+            dedup_hash(&b, DedupKey::Text)
+        );
+
+        // Words that parse as floats are not literals
+        assert_ne!(
+            dedup_hash(&query("inf", "bob"), DedupKey::Structure),
+            dedup_hash(&query("NaN", "bob"), DedupKey::Structure)
+        );
+    }
+
+    #[test]
+    fn test_rejected_generations_are_skipped() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_guarded_rule("query", vec!["SELECT", "1"], "never")
+            .unwrap();
+        grammar.add_predicate("never", |_| false);
+
+        let config = DedupConfig {
+            max_attempts: 5,
+            ..DedupConfig::default()
+        };
+        let mut unique = grammar.generate_unique("query", config);
+        assert!(unique.next().is_none());
+        assert!(unique.exhausted());
+        assert_eq!((unique.failures(), unique.duplicates()), (5, 0));
+    }
+}
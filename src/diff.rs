@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::grammar::{Grammar, Production, elements_text};
+use crate::slice::reference;
+use crate::weights::WeightContext;
+
+/// A change to the weight of a production present in both grammars
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightChange {
+    /// The production, as written in the new grammar
+    pub production: String,
+    /// The context of a context weight, indexed like the new grammar when
+    /// it still sets weights there, or `None` for the unconditioned weight
+    pub context: Option<WeightContext>,
+    /// The old weight, `None` when the context set no weights
+    pub old: Option<f64>,
+    /// The new weight, `None` when the context sets no weights
+    pub new: Option<f64>,
+}
+
+/// How the productions of a symbol defined by both grammars differ
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolDiff {
+    /// Productions only in the new grammar
+    pub added: Vec<String>,
+    /// Productions only in the old grammar
+    pub removed: Vec<String>,
+    /// Productions edited between the grammars, old then new: similar
+    /// elements, or the same elements with a different predicate
+    pub changed: Vec<(String, String)>,
+    /// Weight changes of productions in both grammars
+    pub weights: Vec<WeightChange>,
+}
+
+impl SymbolDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.weights.is_empty()
+    }
+}
+
+/// The differences between two versions of a grammar, independent of the
+/// order of their rules and of their formatting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GrammarDiff {
+    /// Symbols only the new grammar defines
+    pub added: Vec<String>,
+    /// Symbols only the old grammar defines
+    pub removed: Vec<String>,
+    /// Symbols both grammars define whose productions differ
+    pub changed: BTreeMap<String, SymbolDiff>,
+    /// Symbols the new grammar defines but no longer reaches from the roots
+    /// of the old grammar, the symbols no other symbol refers to
+    pub unreachable: Vec<String>,
+    /// Symbols the new grammar refers to without defining them, where the
+    /// old grammar did not, each with the symbols referring to them
+    pub undefined: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl GrammarDiff {
+    /// Whether the grammars have the same rules and weights
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.unreachable.is_empty()
+            && self.undefined.is_empty()
+    }
+}
+
+/// A production as written in the text format, with its predicate
+fn production_text(production: &Production) -> String {
+    let mut text = elements_text(&production.elements);
+    if let Some(guard) = &production.guard {
+        text.push_str(&format!(" if {}", guard));
+    }
+    text
+}
+
+/// A weight context with the parent's production given by its elements
+/// instead of its index, so that it matches across reordering
+type ContextKey = (String, Option<String>, Option<usize>);
+
+/// The weight contexts of `symbol`, by key
+fn contexts<'g>(
+    grammar: &'g Grammar,
+    symbol: &str,
+) -> BTreeMap<ContextKey, (&'g WeightContext, &'g [f64])> {
+    grammar
+        .contexts_of(symbol)
+        .into_iter()
+        .map(|(context, weights)| {
+            let production = context.production.map(|p| {
+                grammar
+                    .rules()
+                    .get(&context.parent)
+                    .and_then(|productions| productions.get(p))
+                    .map_or_else(|| format!("#{}", p), |p| elements_text(&p.elements))
+            });
+            let key = (context.parent.clone(), production, context.position);
+            (key, (context, weights))
+        })
+        .collect()
+}
+
+/// Length of the longest common subsequence of two element lists
+fn common_length(a: &[String], b: &[String]) -> usize {
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            lengths[i + 1][j + 1] = if a[i] == b[j] {
+                lengths[i][j] + 1
+            } else {
+                lengths[i][j + 1].max(lengths[i + 1][j])
+            };
+        }
+    }
+    lengths[a.len()][b.len()]
+}
+
+/// Pair the old productions with the new ones: first those with the same
+/// elements, then those sharing at least half of the longer one's elements
+fn pair(old: &[Production], new: &[Production]) -> Vec<(usize, usize)> {
+    let elements = |productions: &[Production]| -> Vec<Vec<String>> {
+        productions
+            .iter()
+            .map(|p| {
+                p.elements
+                    .iter()
+                    .map(|e| elements_text(std::slice::from_ref(e)))
+                    .collect()
+            })
+            .collect()
+    };
+    let (old, new) = (elements(old), elements(new));
+
+    let mut pairs = Vec::new();
+    let mut taken = vec![false; new.len()];
+    let mut unpaired = Vec::new();
+    for (i, elements) in old.iter().enumerate() {
+        match (0..new.len()).find(|&j| !taken[j] && new[j] == *elements) {
+            Some(j) => {
+                taken[j] = true;
+                pairs.push((i, j));
+            }
+            None => unpaired.push(i),
+        }
+    }
+    for i in unpaired {
+        let mut best: Option<(usize, usize)> = None;
+        for j in (0..new.len()).filter(|&j| !taken[j]) {
+            let common = common_length(&old[i], &new[j]);
+            if 2 * common >= old[i].len().max(new[j].len())
+                && best.is_none_or(|(_, most)| common > most)
+            {
+                best = Some((j, common));
+            }
+        }
+        if let Some((j, _)) = best {
+            taken[j] = true;
+            pairs.push((i, j));
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+/// How the productions of `symbol` differ
+fn symbol_diff(old: &Grammar, new: &Grammar, symbol: &str) -> SymbolDiff {
+    let (old_productions, new_productions) = (&old.rules()[symbol], &new.rules()[symbol]);
+    let pairs = pair(old_productions, new_productions);
+    let (old_contexts, new_contexts) = (contexts(old, symbol), contexts(new, symbol));
+    let mut diff = SymbolDiff::default();
+
+    for (i, production) in old_productions.iter().enumerate() {
+        if !pairs.iter().any(|&(o, _)| o == i) {
+            diff.removed.push(production_text(production));
+        }
+    }
+    for (j, production) in new_productions.iter().enumerate() {
+        if !pairs.iter().any(|&(_, n)| n == j) {
+            diff.added.push(production_text(production));
+        }
+    }
+
+    for &(i, j) in &pairs {
+        let (before, after) = (&old_productions[i], &new_productions[j]);
+        let (old_text, new_text) = (production_text(before), production_text(after));
+        if old_text != new_text {
+            diff.changed.push((old_text, new_text.clone()));
+        }
+
+        let change = |context, old, new| WeightChange {
+            production: new_text.clone(),
+            context,
+            old,
+            new,
+        };
+        if before.weight != after.weight {
+            let change = change(None, Some(before.weight), Some(after.weight));
+            diff.weights.push(change);
+        }
+        let keys: BTreeSet<_> = old_contexts.keys().chain(new_contexts.keys()).collect();
+        for key in keys {
+            let (was, is) = (old_contexts.get(key), new_contexts.get(key));
+            let old_weight = was.map(|w| w.1[i]);
+            let new_weight = is.map(|w| w.1[j]);
+            if old_weight != new_weight {
+                let context = is.or(was).map(|w| w.0.clone());
+                diff.weights.push(change(context, old_weight, new_weight));
+            }
+        }
+    }
+    diff
+}
+
+/// Every symbol a grammar refers to, with the symbols referring to it
+fn references(grammar: &Grammar) -> BTreeMap<&str, BTreeSet<&str>> {
+    let mut references: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (symbol, productions) in grammar.rules() {
+        for element in productions.iter().flat_map(|p| &p.elements) {
+            if let Some(target) = reference(element) {
+                references.entry(target).or_default().insert(symbol);
+            }
+        }
+    }
+    references
+}
+
+/// The defined symbols reachable from `roots`, which are included
+fn reachable<'g>(grammar: &'g Grammar, roots: &[&str]) -> BTreeSet<&'g str> {
+    let mut reached = BTreeSet::new();
+    let mut stack: Vec<&str> = roots
+        .iter()
+        .filter_map(|root| grammar.rules().get_key_value(*root))
+        .map(|(root, _)| root.as_str())
+        .collect();
+    while let Some(symbol) = stack.pop() {
+        if !reached.insert(symbol) {
+            continue;
+        }
+        let elements = grammar.rules()[symbol].iter().flat_map(|p| &p.elements);
+        for target in elements.filter_map(reference) {
+            if let Some((target, _)) = grammar.rules().get_key_value(target) {
+                stack.push(target);
+            }
+        }
+    }
+    reached
+}
+
+impl Grammar {
+    /// The differences from this grammar to `new`.
+    ///
+    /// Productions are matched by their elements whatever their order, and
+    /// productions with similar elements are reported as changed rather
+    /// than as removed and added. Context weights are matched by the
+    /// elements of the parent's production, so reordering it is not a
+    /// change either. Configuration, predicates and attributes registered
+    /// in code are not compared.
+    pub fn diff(&self, new: &Grammar) -> GrammarDiff {
+        let mut diff = GrammarDiff::default();
+        let mut symbols: Vec<&String> = self.rules().keys().chain(new.rules().keys()).collect();
+        symbols.sort();
+        symbols.dedup();
+
+        for symbol in symbols {
+            match (self.has_non_terminal(symbol), new.has_non_terminal(symbol)) {
+                (true, false) => diff.removed.push(symbol.clone()),
+                (false, true) => diff.added.push(symbol.clone()),
+                _ => {
+                    let changes = symbol_diff(self, new, symbol);
+                    if !changes.is_empty() {
+                        diff.changed.insert(symbol.clone(), changes);
+                    }
+                }
+            }
+        }
+
+        let (old_references, new_references) = (references(self), references(new));
+        let roots: Vec<&str> = self
+            .rules()
+            .keys()
+            .map(String::as_str)
+            .filter(|symbol| {
+                old_references
+                    .get(symbol)
+                    .is_none_or(|users| users.iter().all(|user| user == symbol))
+            })
+            .collect();
+        let (was_reached, is_reached) = (reachable(self, &roots), reachable(new, &roots));
+        let mut unreachable: Vec<String> = new
+            .rules()
+            .keys()
+            .filter(|symbol| !is_reached.contains(symbol.as_str()))
+            .filter(|symbol| {
+                was_reached.contains(symbol.as_str()) || !self.has_non_terminal(symbol)
+            })
+            .cloned()
+            .collect();
+        unreachable.sort();
+        diff.unreachable = unreachable;
+
+        for (target, users) in new_references {
+            let was_undefined =
+                old_references.contains_key(target) && !self.has_non_terminal(target);
+            if !new.has_non_terminal(target) && !was_undefined {
+                let users = users.into_iter().map(str::to_string).collect();
+                diff.undefined.insert(target.to_string(), users);
+            }
+        }
+        diff
+    }
+}
+
+fn weight(weight: Option<f64>) -> String {
+    weight.map_or_else(|| "unset".to_string(), |w| w.to_string())
+}
+
+impl fmt::Display for WeightChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "weight {}", self.production)?;
+        if let Some(context) = &self.context {
+            write!(f, " when <{}>", context.parent)?;
+            if let Some(production) = context.production {
+                write!(f, " production {}", production)?;
+            }
+            if let Some(position) = context.position {
+                write!(f, " position {}", position)?;
+            }
+        }
+        write!(f, ": {} -> {}", weight(self.old), weight(self.new))
+    }
+}
+
+/// One line per difference: `+`, `-` and `~` for added, removed and changed
+/// symbols, with their changed productions indented below
+impl fmt::Display for GrammarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in &self.added {
+            writeln!(f, "+ <{}>", symbol)?;
+        }
+        for symbol in &self.removed {
+            writeln!(f, "- <{}>", symbol)?;
+        }
+        for (symbol, changes) in &self.changed {
+            writeln!(f, "~ <{}>", symbol)?;
+            for production in &changes.added {
+                writeln!(f, "    + {}", production)?;
+            }
+            for production in &changes.removed {
+                writeln!(f, "    - {}", production)?;
+            }
+            for (old, new) in &changes.changed {
+                writeln!(f, "    ~ {} -> {}", old, new)?;
+            }
+            for change in &changes.weights {
+                writeln!(f, "    {}", change)?;
+            }
+        }
+        for symbol in &self.unreachable {
+            writeln!(f, "unreachable <{}>", symbol)?;
+        }
+        for (symbol, users) in &self.undefined {
+            let users: Vec<String> = users.iter().map(|user| format!("<{}>", user)).collect();
+            writeln!(f, "undefined <{}> used by {}", symbol, users.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"
+<query> ::= [SELECT, <columns>, FROM, <table>]
+<query> ::= [DELETE, FROM, <table>]
+<columns> ::= [<column>] weight 3
+<columns> ::= [<column>, ",", <columns>]
+<column> ::= [id] weight 2 when <columns> production 1 position 0
+<column> ::= [name]
+<table> ::= [users]
+<legacy> ::= [x]
+"#;
+
+    #[test]
+    fn test_reordering_and_formatting_are_not_changes() {
+        let old: Grammar = OLD.parse().unwrap();
+        let new: Grammar = r#"
+<table>  ::= ["users"]
+<columns> ::= [<column>, ',', <columns>]
+<columns> ::= [<column>]   weight 3
+<legacy> ::= ['x']
+<column> ::= [name]
+<column> ::= [id] weight 2 when <columns> production 0 position 0
+<query> ::= [DELETE, FROM, <table>]
+<query> ::= [SELECT, <columns>, FROM, <table>]
+"#
+        .parse()
+        .unwrap();
+
+        let diff = old.diff(&new);
+        assert!(diff.is_empty(), "{}", diff);
+        assert_eq!(diff.to_string(), "");
+    }
+
+    #[test]
+    fn test_changes_are_reported() {
+        let old: Grammar = OLD.parse().unwrap();
+        let new: Grammar = r#"
+<query> ::= [SELECT, <columns>, FROM, <table>, <where>]
+<query> ::= [INSERT, INTO, <table>]
+<columns> ::= [<column>] weight 1
+<columns> ::= [<column>, ",", <columns>]
+<column> ::= [id] weight 5 when <columns> production 1 position 0
+<column> ::= [name] if visible
+<column> ::= [email]
+<table> ::= [users]
+<legacy> ::= [x]
+"#
+        .parse()
+        .unwrap();
+
+        let diff = old.diff(&new);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(
+            diff.changed["query"].changed,
+            [(
+                "[SELECT, <columns>, FROM, <table>]".to_string(),
+                "[SELECT, <columns>, FROM, <table>, <where>]".to_string()
+            )]
+        );
+        assert_eq!(diff.changed["query"].added, ["[INSERT, INTO, <table>]"]);
+        assert_eq!(diff.changed["query"].removed, ["[DELETE, FROM, <table>]"]);
+        assert_eq!(
+            diff.changed["columns"].weights,
+            [WeightChange {
+                production: "[<column>]".to_string(),
+                context: None,
+                old: Some(3.0),
+                new: Some(1.0),
+            }]
+        );
+
+        let column = &diff.changed["column"];
+        assert_eq!(column.added, ["[email]"]);
+        assert_eq!(column.changed[0].1, "[name] if visible");
+        assert_eq!(column.weights.len(), 1);
+        assert_eq!(
+            column.weights[0].to_string(),
+            "weight [id] when <columns> production 1 position 0: 2 -> 5"
+        );
+
+        assert!(diff.unreachable.is_empty());
+        assert_eq!(
+            diff.undefined,
+            BTreeMap::from([("where".to_string(), BTreeSet::from(["query".to_string()]))])
+        );
+        assert!(
+            diff.to_string()
+                .contains("~ <query>\n    + [INSERT, INTO, <table>]\n")
+        );
+    }
+
+    #[test]
+    fn test_symbols_added_removed_and_unreachable() {
+        let old: Grammar = OLD.parse().unwrap();
+        let new: Grammar = r#"
+<query> ::= [SELECT, <columns>, FROM, users]
+<columns> ::= [<column>] weight 3
+<columns> ::= [<column>, ",", <columns>]
+<column> ::= [id] weight 2 when <columns> production 1 position 0
+<column> ::= [name]
+<table> ::= [users]
+<helper> ::= [y]
+"#
+        .parse()
+        .unwrap();
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, ["helper"]);
+        assert_eq!(diff.removed, ["legacy"]);
+        assert_eq!(diff.unreachable, ["helper", "table"]);
+        assert!(diff.undefined.is_empty());
+        assert!(diff.to_string().starts_with("+ <helper>\n- <legacy>\n"));
+    }
+
+    #[test]
+    fn test_round_trip_is_not_a_change() {
+        let mut grammar: Grammar = OLD.parse().unwrap();
+        grammar.add_rule("column", vec!["age"]).unwrap();
+        let reread: Grammar = grammar.to_text().parse().unwrap();
+        assert!(grammar.diff(&reread).is_empty());
+        assert!(reread.diff(&grammar).is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::grammar::{Element, Grammar, Production};
+use crate::utils::{GrammarError, Result};
+
+/// Options for [`Grammar::to_html`]
+#[derive(Debug, Clone)]
+pub struct DocConfig {
+    /// Page title
+    pub title: String,
+    /// Sample expansions shown per non-terminal
+    pub samples: usize,
+    /// Seed for the sample expansions, so the page is reproducible
+    pub seed: u64,
+    /// Samples longer than this many characters are cut short
+    pub max_sample_len: usize,
+}
+
+impl Default for DocConfig {
+    fn default() -> Self {
+        DocConfig {
+            title: "Grammar".to_string(),
+            samples: 3,
+            seed: 0,
+            max_sample_len: 200,
+        }
+    }
+}
+
+/// Height of a box in a railroad diagram
+const BOX_HEIGHT: usize = 24;
+/// Vertical distance between the tracks of two productions
+const TRACK_HEIGHT: usize = 36;
+/// Horizontal space between boxes and around a track
+const GAP: usize = 16;
+/// Approximate width of a character of the diagram font
+const CHAR_WIDTH: usize = 8;
+
+/// One box on a railroad track
+struct Station {
+    label: String,
+    /// Symbol the box links to, for non-terminals
+    link: Option<String>,
+    class: &'static str,
+}
+
+impl Station {
+    fn width(&self) -> usize {
+        self.label.chars().count() * CHAR_WIDTH + GAP
+    }
+}
+
+/// The boxes a production's elements are drawn as
+fn stations(production: &Production, defined: &BTreeSet<&str>) -> Vec<Station> {
+    fn station(element: &Element, defined: &BTreeSet<&str>) -> Option<Station> {
+        let terminal = |label: String| Station {
+            label,
+            link: None,
+            class: "terminal",
+        };
+        Some(match element {
+            Element::Terminal(text) => terminal(text.clone()),
+            Element::NonTerminal(name) if defined.contains(name.as_str()) => Station {
+                label: name.clone(),
+                link: Some(name.clone()),
+                class: "non-terminal",
+            },
+            Element::NonTerminal(name) => Station {
+                label: name.clone(),
+                link: None,
+                class: "undefined",
+            },
+            Element::Regex(regex) => terminal(format!("/{}/", regex.pattern())),
+            Element::Bind(binding) => {
+                let mut station = station(&binding.element, defined)?;
+                let _ = write!(station.label, " as ${}", binding.variable);
+                station
+            }
+            Element::Variable(name) => terminal(format!("${}", name)),
+            Element::Pick(name) => terminal(format!("@pick(${})", name)),
+            Element::Classified(element, _) => station(element, defined)?,
+            Element::Layout(_) => return None,
+        })
+    }
+
+    production
+        .elements
+        .iter()
+        .filter_map(|element| station(element, defined))
+        .collect()
+}
+
+/// Escape text for HTML and SVG
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Anchor of a symbol's section. Characters other than ASCII letters,
+/// digits and `_` are percent-encoded byte by byte, so distinct symbols get
+/// distinct anchors.
+fn anchor(symbol: &str) -> String {
+    let mut anchor = String::from("sym-");
+    for byte in symbol.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' {
+            anchor.push(byte as char);
+        } else {
+            let _ = write!(anchor, "%{:02X}", byte);
+        }
+    }
+    anchor
+}
+
+/// Draw a symbol's productions as a railroad diagram: one track per
+/// production between a shared entry and exit, boxes for terminals and
+/// linked boxes for non-terminals
+fn railroad(productions: &[Production], defined: &BTreeSet<&str>) -> String {
+    let tracks: Vec<Vec<Station>> = productions
+        .iter()
+        .map(|production| stations(production, defined))
+        .collect();
+    let track_width = |track: &[Station]| -> usize {
+        track
+            .iter()
+            .map(|station| station.width() + GAP)
+            .sum::<usize>()
+            + GAP
+    };
+    let inner = tracks
+        .iter()
+        .map(|track| track_width(track))
+        .max()
+        .unwrap_or(0)
+        .max(GAP * 2);
+
+    let left = GAP * 2;
+    let right = left + inner;
+    let width = right + GAP * 2;
+    let first = GAP + BOX_HEIGHT / 2;
+    let height = first + TRACK_HEIGHT * tracks.len().saturating_sub(1) + BOX_HEIGHT / 2 + GAP;
+    let last = first + TRACK_HEIGHT * tracks.len().saturating_sub(1);
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg class=\"railroad\" xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+        width, height, width, height
+    );
+    // Entry and exit with the bars joining the tracks
+    let _ = writeln!(
+        svg,
+        "<path class=\"rail\" d=\"M{} {}h{}M{} {}V{}M{} {}V{}M{} {}h{}\"/>",
+        GAP / 2,
+        first,
+        left - GAP / 2,
+        left,
+        first,
+        last,
+        right,
+        first,
+        last,
+        right,
+        first,
+        GAP * 3 / 2
+    );
+    let _ = writeln!(
+        svg,
+        "<circle class=\"end\" cx=\"{}\" cy=\"{}\" r=\"4\"/><circle class=\"end\" cx=\"{}\" cy=\"{}\" r=\"4\"/>",
+        GAP / 2,
+        first,
+        width - GAP / 2,
+        first
+    );
+
+    for (i, track) in tracks.iter().enumerate() {
+        let y = first + TRACK_HEIGHT * i;
+        let _ = writeln!(
+            svg,
+            "<path class=\"rail\" d=\"M{} {}H{}\"/>",
+            left, y, right
+        );
+        let mut x = left + GAP;
+        for station in track {
+            let box_width = station.width();
+            let top = y - BOX_HEIGHT / 2;
+            let radius = if station.class == "terminal" { 10 } else { 0 };
+            let shape = format!(
+                "<rect class=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\"/>\
+                 <text x=\"{}\" y=\"{}\">{}</text>",
+                station.class,
+                x,
+                top,
+                box_width,
+                BOX_HEIGHT,
+                radius,
+                x + box_width / 2,
+                y + 4,
+                escape(&station.label)
+            );
+            match &station.link {
+                Some(symbol) => {
+                    let _ = writeln!(svg, "<a href=\"#{}\">{}</a>", anchor(symbol), shape);
+                }
+                None => {
+                    let _ = writeln!(svg, "{}", shape);
+                }
+            }
+            x += box_width + GAP;
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+nav a { margin-right: 0.8em; }
+section { margin-top: 2.5em; }
+.railroad text { font: 13px monospace; text-anchor: middle; }
+.railroad .rail { fill: none; stroke: #333; stroke-width: 1.5; }
+.railroad .end { fill: #333; }
+.railroad rect { stroke: #333; stroke-width: 1.5; }
+.railroad .terminal { fill: #e8f0fe; }
+.railroad .non-terminal { fill: #fff8dc; }
+.railroad .undefined { fill: #f8d7da; stroke: #c00; }
+.railroad a:hover rect { fill: #ffe58f; }
+pre { background: #f6f8fa; padding: 0.5em; white-space: pre-wrap; }";
+
+impl Grammar {
+    /// Write a static HTML page documenting the grammar.
+    ///
+    /// Each non-terminal gets a railroad diagram of its productions, with
+    /// non-terminal boxes linking to their own sections, the symbols whose
+    /// productions refer to it and sample expansions from the generator.
+    /// Fails on an invalid grammar, such as one guarded by predicates that
+    /// were never registered, before anything is written.
+    pub fn write_html<W: Write>(&self, config: &DocConfig, out: &mut W) -> Result<()> {
+        self.check_predicates()?;
+
+        let mut symbols: Vec<_> = self.rules().iter().collect();
+        symbols.sort_by(|a, b| a.0.cmp(b.0));
+        let defined: BTreeSet<&str> = symbols.iter().map(|(name, _)| name.as_str()).collect();
+
+        let mut referenced_by: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for (symbol, productions) in &symbols {
+            for production in productions.iter() {
+                for element in &production.elements {
+                    let target = match element {
+                        Element::NonTerminal(name) => name,
+                        Element::Bind(binding) => match binding.element.as_ref() {
+                            Element::NonTerminal(name) => name,
+                            _ => continue,
+                        },
+                        _ => continue,
+                    };
+                    referenced_by
+                        .entry(target.as_str())
+                        .or_default()
+                        .insert(symbol.as_str());
+                }
+            }
+        }
+
+        let title = escape(&config.title);
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+        writeln!(out, "<title>{}</title>", title)?;
+        writeln!(out, "<style>\n{}\n</style>\n</head>\n<body>", STYLE)?;
+        writeln!(out, "<h1>{}</h1>", title)?;
+
+        writeln!(out, "<nav>")?;
+        for (symbol, _) in &symbols {
+            writeln!(
+                out,
+                "<a href=\"#{}\">{}</a>",
+                anchor(symbol),
+                escape(symbol)
+            )?;
+        }
+        writeln!(out, "</nav>")?;
+
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        for (symbol, productions) in &symbols {
+            writeln!(out, "<section id=\"{}\">", anchor(symbol))?;
+            writeln!(out, "<h2>&lt;{}&gt;</h2>", escape(symbol))?;
+            writeln!(out, "{}", railroad(productions, &defined))?;
+
+            match referenced_by.get(symbol.as_str()) {
+                Some(users) => {
+                    let links: Vec<String> = users
+                        .iter()
+                        .map(|user| format!("<a href=\"#{}\">{}</a>", anchor(user), escape(user)))
+                        .collect();
+                    writeln!(out, "<p>Referenced by: {}</p>", links.join(", "))?;
+                }
+                None => writeln!(out, "<p>Not referenced by any rule.</p>")?,
+            }
+
+            if config.samples > 0 {
+                writeln!(out, "<p>Examples:</p>")?;
+                for _ in 0..config.samples {
+                    let text = match self.try_generate_with_rng(symbol, &mut rng) {
+                        Ok(ast) => ast.text,
+                        Err(GrammarError::NoValidDerivation(_)) => {
+                            "<no_valid_derivation>".to_string()
+                        }
+                        Err(error) => return Err(error),
+                    };
+                    let sample = match text.char_indices().nth(config.max_sample_len) {
+                        Some((end, _)) => format!("{}…", &text[..end]),
+                        None => text,
+                    };
+                    writeln!(out, "<pre>{}</pre>", escape(&sample))?;
+                }
+            }
+            writeln!(out, "</section>")?;
+        }
+
+        writeln!(out, "</body>\n</html>")?;
+        Ok(())
+    }
+
+    /// The HTML page written by [`Grammar::write_html`]
+    pub fn to_html(&self, config: &DocConfig) -> Result<String> {
+        let mut out = Vec::new();
+        self.write_html(config, &mut out)?;
+        Ok(String::from_utf8(out).expect("the page is written from strings"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<column>", "FROM", "<table>"])
+            .unwrap();
+        grammar.add_rule("column", vec!["id"]).unwrap();
+        grammar.add_rule("column", vec!["a<b"]).unwrap();
+        grammar.add_rule("table", vec!["<missing>"]).unwrap();
+        grammar
+    }
+
+    #[test]
+    fn test_html_sections_and_links() {
+        let html = grammar()
+            .to_html(&DocConfig {
+                samples: 2,
+                ..DocConfig::default()
+            })
+            .unwrap();
+
+        for symbol in ["column", "query", "table"] {
+            assert!(html.contains(&format!("<section id=\"sym-{}\">", symbol)));
+        }
+        assert!(html.contains("<a href=\"#sym-column\"><rect class=\"non-terminal\""));
+        assert!(html.contains("<rect class=\"undefined\""));
+        assert!(html.contains(">a&lt;b</text>"));
+        assert!(html.contains("Referenced by: <a href=\"#sym-query\">query</a>"));
+        assert!(html.contains("Not referenced by any rule."));
+        assert_eq!(html.matches("<pre>").count(), 6);
+        assert_eq!(html.matches("<svg").count(), 3);
+
+        // Two productions give two tracks
+        let column = &html[html.find("id=\"sym-column\"").unwrap()..];
+        let svg = &column[..column.find("</svg>").unwrap()];
+        assert_eq!(svg.matches("<path class=\"rail\"").count(), 3);
+
+        assert_eq!(
+            html,
+            grammar()
+                .to_html(&DocConfig {
+                    samples: 2,
+                    ..DocConfig::default()
+                })
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_anchors_are_distinct() {
+        assert_eq!(anchor("select_list"), "sym-select_list");
+        assert_eq!(anchor("a-b"), "sym-a%2Db");
+        assert_ne!(anchor("a-b"), anchor("a.b"));
+        assert_ne!(anchor("a-b"), anchor("a%2Db"));
+    }
+}
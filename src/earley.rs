@@ -0,0 +1,491 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::grammar::{Element, Grammar, QueryAstNode};
+use crate::probability::log_sum_exp;
+use crate::render::Layout;
+use crate::utils::{GrammarError, Result};
+
+/// What a production element matches when parsing
+enum Symbol<'g> {
+    /// Terminal text, matched ignoring ASCII case and the amount of whitespace
+    Literal(&'g str),
+    /// A regex terminal, anchored at both ends
+    Pattern(Regex),
+    /// A variable or pick, whose value is not known while parsing, so any
+    /// run of word characters is accepted
+    Word,
+    /// A layout directive, which matches without consuming input
+    Layout(Layout),
+    NonTerminal(&'g str),
+}
+
+/// A production, dotted at `dot`, started at position `origin`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Item<'g> {
+    symbol: &'g str,
+    production: usize,
+    dot: usize,
+    origin: usize,
+}
+
+/// One way an item was derived, used to rebuild the parse tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Link {
+    Predicted,
+    /// Advanced over a terminal matched from `start`
+    Scanned {
+        prev: (usize, usize),
+        start: usize,
+    },
+    /// Advanced over a completed non-terminal
+    Completed {
+        prev: (usize, usize),
+        child: (usize, usize),
+    },
+}
+
+#[derive(Default)]
+struct ItemSet<'g> {
+    items: Vec<Item<'g>>,
+    /// Every way each item was derived, the first one first
+    links: Vec<Vec<Link>>,
+    index: HashMap<Item<'g>, usize>,
+    /// Items waiting on a non-terminal, by that non-terminal
+    waiting: HashMap<&'g str, Vec<usize>>,
+    /// Non-terminals completed without consuming input, by their items
+    nullable: HashMap<&'g str, Vec<usize>>,
+}
+
+/// The item sets at every byte position of the input
+type Chart<'g> = Vec<ItemSet<'g>>;
+
+/// A scannerless Earley parser recovering derivations from generated text.
+///
+/// Parsing works on characters, so terminals do not need to be separated
+/// by whitespace in the input. Whitespace between terminals is optional,
+/// and a terminal ending in a word character must not be followed directly
+/// by another word character. When the text is ambiguous, the first
+/// derivation found is returned, while probabilities sum over them all.
+pub struct EarleyParser<'g> {
+    grammar: &'g Grammar,
+    rules: HashMap<&'g str, Vec<Vec<Symbol<'g>>>>,
+}
+
+impl<'g> EarleyParser<'g> {
+    /// Prepare a parser for `grammar`
+    pub fn new(grammar: &'g Grammar) -> Result<Self> {
+        let mut rules = HashMap::new();
+
+        for (name, productions) in grammar.rules() {
+            let compiled = productions
+                .iter()
+                .map(|p| p.elements.iter().map(compile).collect::<Result<Vec<_>>>())
+                .collect::<Result<Vec<_>>>()?;
+            rules.insert(name.as_str(), compiled);
+        }
+
+        Ok(EarleyParser { grammar, rules })
+    }
+
+    /// Parse `text` as `start_symbol`, returning the derivation tree.
+    ///
+    /// Non-terminal nodes record which production was used.
+    pub fn parse(&self, start_symbol: &str, text: &str) -> Result<QueryAstNode> {
+        let (chart, accepted) = self.accept(start_symbol, text)?;
+        Ok(self.build(&chart, text, accepted[0]))
+    }
+
+    /// Natural log of the total probability of every derivation of `text`
+    /// as `start_symbol` under the grammar's production and context
+    /// weights.
+    ///
+    /// Predicates are ignored, so derivations that generation would reject
+    /// are counted too.
+    pub fn log_probability(&self, start_symbol: &str, text: &str) -> Result<f64> {
+        let (chart, accepted) = self.accept(start_symbol, text)?;
+        let inside = self.inside(&chart)?;
+        Ok(log_sum_exp(accepted.iter().map(|&(position, index)| {
+            let item = chart[position].items[index];
+            inside[position][index]
+                + self
+                    .grammar
+                    .choice_log_probability(item.symbol, item.production, None)
+        })))
+    }
+
+    /// The chart of `text` and its complete derivations of `start_symbol`,
+    /// in order of the position they end at
+    fn accept(&self, start_symbol: &str, text: &str) -> Result<(Chart<'g>, Vec<(usize, usize)>)> {
+        let Some((start, _)) = self.rules.get_key_value(start_symbol) else {
+            return Err(GrammarError::UnknownNonTerminal(start_symbol.to_string()));
+        };
+
+        let chart = self.recognize(start, text);
+        let end = text.trim_end().len();
+        let accepted: Vec<_> = (end..=text.len())
+            .flat_map(|position| {
+                chart[position]
+                    .items
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, item)| {
+                        item.symbol == *start && item.origin == 0 && self.is_complete(item)
+                    })
+                    .map(move |(index, _)| (position, index))
+            })
+            .collect();
+
+        if accepted.is_empty() {
+            let reached = chart
+                .iter()
+                .rposition(|set| !set.items.is_empty())
+                .unwrap_or(0);
+            return Err(GrammarError::Parse(format!(
+                "No derivation of <{}> matches the input; parsing stopped at byte {}: {:?}",
+                start_symbol,
+                reached,
+                &text[reached..]
+            )));
+        }
+        Ok((chart, accepted))
+    }
+
+    fn is_complete(&self, item: &Item) -> bool {
+        item.dot == self.rules[item.symbol][item.production].len()
+    }
+
+    fn next_symbol(&self, item: &Item) -> Option<&Symbol<'g>> {
+        self.rules[item.symbol][item.production].get(item.dot)
+    }
+
+    fn recognize(&self, start: &'g str, text: &str) -> Chart<'g> {
+        let mut chart: Chart = (0..=text.len()).map(|_| ItemSet::default()).collect();
+        self.predict(&mut chart, start, 0);
+
+        for position in 0..=text.len() {
+            let mut next = 0;
+            while next < chart[position].items.len() {
+                let item = chart[position].items[next];
+                let from = (position, next);
+                next += 1;
+
+                match self.next_symbol(&item) {
+                    None => self.complete(&mut chart, item, from),
+                    Some(Symbol::NonTerminal(name)) => {
+                        self.predict(&mut chart, name, position);
+                        let nullable = chart[position].nullable.get(name).cloned();
+                        for child in nullable.into_iter().flatten() {
+                            let link = Link::Completed {
+                                prev: from,
+                                child: (position, child),
+                            };
+                            self.add(&mut chart, position, advance(item), link);
+                        }
+                    }
+                    Some(symbol) => {
+                        let start = skip_whitespace(text, position);
+                        for end in scan(symbol, text, start) {
+                            let link = Link::Scanned { prev: from, start };
+                            self.add(&mut chart, end, advance(item), link);
+                        }
+                    }
+                }
+            }
+        }
+
+        chart
+    }
+
+    fn predict(&self, chart: &mut [ItemSet<'g>], name: &'g str, position: usize) {
+        let Some(productions) = self.rules.get(name) else {
+            return;
+        };
+        for production in 0..productions.len() {
+            let item = Item {
+                symbol: name,
+                production,
+                dot: 0,
+                origin: position,
+            };
+            self.add(chart, position, item, Link::Predicted);
+        }
+    }
+
+    fn complete(&self, chart: &mut [ItemSet<'g>], item: Item<'g>, child: (usize, usize)) {
+        let position = child.0;
+        if item.origin == position {
+            chart[position]
+                .nullable
+                .entry(item.symbol)
+                .or_default()
+                .push(child.1);
+        }
+
+        let waiting = chart[item.origin]
+            .waiting
+            .get(item.symbol)
+            .cloned()
+            .unwrap_or_default();
+        for index in waiting {
+            let parent = chart[item.origin].items[index];
+            let link = Link::Completed {
+                prev: (item.origin, index),
+                child,
+            };
+            self.add(chart, position, advance(parent), link);
+        }
+    }
+
+    fn add(&self, chart: &mut [ItemSet<'g>], position: usize, item: Item<'g>, link: Link) {
+        let set = &mut chart[position];
+        if let Some(&index) = set.index.get(&item) {
+            // Another derivation of an item already processed
+            if !set.links[index].contains(&link) {
+                set.links[index].push(link);
+            }
+            return;
+        }
+
+        let index = set.items.len();
+        set.items.push(item);
+        set.links.push(vec![link]);
+        set.index.insert(item, index);
+        if let Some(Symbol::NonTerminal(name)) = self.next_symbol(&item) {
+            set.waiting.entry(name).or_default().push(index);
+        }
+    }
+
+    /// Rebuild the derivation tree of a completed item by following its links
+    fn build(
+        &self,
+        chart: &[ItemSet],
+        text: &str,
+        (position, index): (usize, usize),
+    ) -> QueryAstNode {
+        let item = chart[position].items[index];
+        let mut node = QueryAstNode::new("non_terminal", item.symbol);
+        node.production = Some(item.production);
+
+        let mut at = (position, index);
+        loop {
+            match chart[at.0].links[at.1][0] {
+                Link::Predicted => break,
+                Link::Scanned { prev, start } => {
+                    let scanned = chart[prev.0].items[prev.1];
+                    let child = match self.next_symbol(&scanned) {
+                        Some(Symbol::Layout(layout)) => {
+                            QueryAstNode::new("layout", &layout.to_string())
+                        }
+                        _ => QueryAstNode::new("terminal", &text[start..at.0]),
+                    };
+                    node.children.push(child);
+                    at = prev;
+                }
+                Link::Completed { prev, child } => {
+                    node.children.push(self.build(chart, text, child));
+                    at = prev;
+                }
+            }
+        }
+
+        node.children.reverse();
+        node
+    }
+
+    /// Log inside probability of every item: the total probability of the
+    /// choices made below the item's dot, by position and index.
+    ///
+    /// Items only depend on items at earlier positions, except through
+    /// completions without input, which can be cyclic, so each position is
+    /// solved by passes that stop once no score changes. Cycles that are
+    /// almost certain to repeat converge too slowly and fail after
+    /// `MAX_PASSES` rather than returning a truncated sum.
+    fn inside(&self, chart: &[ItemSet]) -> Result<Vec<Vec<f64>>> {
+        const MAX_PASSES: usize = 100_000;
+
+        let mut inside: Vec<Vec<f64>> = chart
+            .iter()
+            .map(|set| vec![f64::NEG_INFINITY; set.items.len()])
+            .collect();
+        for position in 0..chart.len() {
+            let mut passes = 0;
+            loop {
+                if passes == MAX_PASSES {
+                    return Err(GrammarError::InvalidGrammar(format!(
+                        "Derivation probabilities at byte {} did not converge within {} passes",
+                        position, MAX_PASSES
+                    )));
+                }
+                passes += 1;
+                let mut changed = false;
+                for index in 0..chart[position].items.len() {
+                    let score =
+                        log_sum_exp(chart[position].links[index].iter().map(|link| match *link {
+                            Link::Predicted => 0.0,
+                            Link::Scanned { prev, .. } => inside[prev.0][prev.1],
+                            Link::Completed { prev, child } => {
+                                let parent = chart[prev.0].items[prev.1];
+                                let item = chart[child.0].items[child.1];
+                                let within = (parent.symbol, parent.production, parent.dot);
+                                inside[prev.0][prev.1]
+                                    + inside[child.0][child.1]
+                                    + self.grammar.choice_log_probability(
+                                        item.symbol,
+                                        item.production,
+                                        Some(within),
+                                    )
+                            }
+                        }));
+                    // Scores only grow, and one that was impossible grows
+                    // by infinity
+                    changed |= score - inside[position][index] > 1e-12;
+                    inside[position][index] = score;
+                }
+                if !changed {
+                    break;
+                }
+            }
+        }
+        Ok(inside)
+    }
+}
+
+fn compile(element: &Element) -> Result<Symbol<'_>> {
+    Ok(match element {
+        Element::Terminal(text) => Symbol::Literal(text),
+        Element::NonTerminal(name) => Symbol::NonTerminal(name),
+        Element::Regex(regex) => {
+            let anchored = format!("^(?:{})$", regex.pattern());
+            Symbol::Pattern(Regex::new(&anchored).map_err(|e| {
+                GrammarError::Parse(format!("Invalid regex /{}/: {}", regex.pattern(), e))
+            })?)
+        }
+        Element::Bind(binding) => compile(&binding.element)?,
+        Element::Classified(element, _) => compile(element)?,
+        Element::Layout(layout) => Symbol::Layout(*layout),
+        Element::Variable(_) | Element::Pick(_) => Symbol::Word,
+    })
+}
+
+fn advance(item: Item) -> Item {
+    Item {
+        dot: item.dot + 1,
+        ..item
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn skip_whitespace(text: &str, position: usize) -> usize {
+    text.len() - text[position..].trim_start().len()
+}
+
+/// A terminal may end here without splitting a word in two
+fn is_boundary(text: &str, position: usize) -> bool {
+    let before = text[..position].chars().next_back();
+    let after = text[position..].chars().next();
+    match (before, after) {
+        (Some(b), Some(a)) => !(is_word(b) && is_word(a)),
+        _ => true,
+    }
+}
+
+/// End positions of every match of a terminal symbol starting at `start`
+fn scan(symbol: &Symbol, text: &str, start: usize) -> Vec<usize> {
+    let rest = &text[start..];
+    match symbol {
+        Symbol::Literal(literal) => match_literal(literal, rest)
+            .map(|len| start + len)
+            .filter(|&end| is_boundary(text, end))
+            .into_iter()
+            .collect(),
+        Symbol::Pattern(regex) => rest
+            .char_indices()
+            .map(|(i, c)| start + i + c.len_utf8())
+            .chain(regex.is_match("").then_some(start))
+            .filter(|&end| is_boundary(text, end) && regex.is_match(&text[start..end]))
+            .collect(),
+        Symbol::Word => {
+            let len = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+            (len > 0).then_some(start + len).into_iter().collect()
+        }
+        Symbol::Layout(_) => vec![start],
+        Symbol::NonTerminal(_) => Vec::new(),
+    }
+}
+
+/// Length of the prefix of `input` matching `literal`, ignoring ASCII case
+/// and treating any run of whitespace in the literal as one or more spaces
+fn match_literal(literal: &str, input: &str) -> Option<usize> {
+    let mut chars = input.char_indices().peekable();
+    let mut expected = literal.trim().chars().peekable();
+
+    while let Some(c) = expected.next() {
+        if c.is_whitespace() {
+            while expected.next_if(|c| c.is_whitespace()).is_some() {}
+            chars.next_if(|(_, a)| a.is_whitespace())?;
+            while chars.next_if(|(_, a)| a.is_whitespace()).is_some() {}
+        } else {
+            chars.next_if(|(_, a)| a.eq_ignore_ascii_case(&c))?;
+        }
+    }
+
+    Some(chars.peek().map_or(input.len(), |&(i, _)| i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql_grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<columns>", "FROM", "<table>"])
+            .unwrap();
+        grammar.add_rule("columns", vec!["<column>"]).unwrap();
+        grammar
+            .add_rule("columns", vec!["<columns>", ",", "<column>"])
+            .unwrap();
+        grammar.add_rule("column", vec!["id"]).unwrap();
+        grammar.add_rule("column", vec!["COUNT(*)"]).unwrap();
+        grammar.add_rule("table", vec!["/[a-z]+[0-9]?/"]).unwrap();
+        grammar
+    }
+
+    #[test]
+    fn test_parse_recovers_productions() {
+        let grammar = sql_grammar();
+        let parser = EarleyParser::new(&grammar).unwrap();
+
+        let tree = parser
+            .parse("query", "select id,count(*),  id FROM users1 ")
+            .unwrap();
+        assert_eq!(tree.value, "query");
+        assert_eq!(tree.production, Some(0));
+
+        let columns = &tree.children[1];
+        assert_eq!(columns.production, Some(1));
+        assert_eq!(columns.children[2].children[0].value, "id");
+        assert_eq!(tree.children[3].children[0].value, "users1");
+    }
+
+    #[test]
+    fn test_parse_rejects_non_matching_text() {
+        let grammar = sql_grammar();
+        let parser = EarleyParser::new(&grammar).unwrap();
+
+        assert!(parser.parse("query", "SELECT idx FROM users").is_err());
+        assert!(parser.parse("query", "SELECT id FROM users WHERE").is_err());
+        assert!(parser.parse("missing", "SELECT id FROM users").is_err());
+    }
+
+    #[test]
+    fn test_match_literal() {
+        assert_eq!(match_literal("IS NULL", "is   null)"), Some(9));
+        assert_eq!(match_literal("IS NULL", "isnull"), None);
+        assert_eq!(match_literal("(", "(id"), Some(1));
+    }
+}
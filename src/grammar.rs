@@ -1,46 +1,110 @@
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 use std::iter::Peekable;
 use std::path::Path;
-use std::str::Chars;
-
+use std::str::{Chars, FromStr};
+
+use crate::attribute::{AttrValue, AttributeContext, AttributeKind, AttributeRule};
+use crate::batch;
+use crate::dedup::{DedupConfig, UniqueGenerator};
+use crate::earley::EarleyParser;
+use crate::predicate::{Predicate, PredicateContext};
+use crate::regex_gen::RegexGenerator;
+use crate::render::{self, Layout, Piece, Renderer, RuleRenderer};
+use crate::stream::TokenSink;
+use crate::symbol_table::{Binding, SymbolTable};
+use crate::token::{QueryToken, TokenClass};
 use crate::utils::{GrammarError, GrammarValidator, NoopValidator, Result};
+use crate::weights::{ProductionWeights, WeightContext};
 #[derive(Debug, Clone)]
 pub struct QueryAstNode {
     pub element_type: String,
     pub value: String,
     pub children: Vec<QueryAstNode>,
+    /// Attributes computed by the grammar's attribute rules
+    pub attributes: BTreeMap<String, AttrValue>,
+    /// Index of the production a non-terminal was expanded with
+    pub production: Option<usize>,
+    /// Class a terminal was tagged with in the grammar
+    pub class: Option<TokenClass>,
+}
+
+/// Render a node with the default renderer
+impl fmt::Display for QueryAstNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(&RuleRenderer::default(), true))
+    }
 }
 
 impl QueryAstNode {
-    /// Convert a node to its string representation
-    pub fn to_string(&self) -> String {
+    /// Create a node without children
+    pub fn new(element_type: &str, value: &str) -> Self {
+        QueryAstNode {
+            element_type: element_type.to_string(),
+            value: value.to_string(),
+            children: Vec::new(),
+            attributes: BTreeMap::new(),
+            production: None,
+            class: None,
+        }
+    }
+
+    /// Re-render the text below this node, including its layout directives
+    pub fn render(&self, renderer: &dyn Renderer, auto_spacing: bool) -> String {
+        let mut pieces = Vec::new();
+        self.collect_pieces(&mut pieces);
+        render::render(renderer, auto_spacing, &pieces)
+    }
+
+    fn collect_pieces(&self, pieces: &mut Vec<Piece>) {
         match self.element_type.as_str() {
-            "terminal" => self.value.clone(),
-            "non_terminal" => {
-                let mut result = String::new();
+            "terminal" => pieces.push(Piece::Token(self.value.clone())),
+            "undefined" | "error" => pieces.push(Piece::Token(format!("<{}>", self.value))),
+            "layout" => pieces.extend(self.value.parse().ok().map(Piece::Layout)),
+            _ => {
                 for child in &self.children {
-                    let child_str = child.to_string();
-                    if !result.is_empty()
-                        && !result.ends_with('(')
-                        && !child_str.starts_with(')')
-                        && !child_str.starts_with(',')
-                        && !result.ends_with(',')
-                    {
-                        result.push(' ');
-                    }
-                    result.push_str(&child_str);
+                    child.collect_pieces(pieces);
                 }
-                result
             }
-            "undefined" => format!("<{}>", self.value),
-            "error" => format!("<{}>", self.value),
-            _ => format!("<unknown:{}>", self.value),
         }
     }
 
+    /// The terminals below this node in output order, with their classes.
+    ///
+    /// Untagged terminals get the class [`TokenClass::infer`] guesses, and
+    /// placeholders for undefined symbols or errors are included as they
+    /// appear in the text.
+    pub fn tokens(&self) -> Vec<QueryToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens(&self, tokens: &mut Vec<QueryToken>) {
+        let text = match self.element_type.as_str() {
+            "terminal" => self.value.clone(),
+            "undefined" | "error" => format!("<{}>", self.value),
+            "layout" => return,
+            _ => {
+                for child in &self.children {
+                    child.collect_tokens(tokens);
+                }
+                return;
+            }
+        };
+
+        let class = self.class.unwrap_or_else(|| TokenClass::infer(&text));
+        tokens.push(QueryToken { text, class });
+    }
+
+    /// Get an attribute computed for this node
+    pub fn attribute(&self, name: &str) -> Option<&AttrValue> {
+        self.attributes.get(name)
+    }
+
     /// Get a debug representation showing node types
     pub fn to_debug_string(&self) -> String {
         match self.element_type.as_str() {
@@ -58,6 +122,7 @@ impl QueryAstNode {
             }
             "undefined" => format!("UNDEF({})", self.value),
             "error" => format!("ERR({})", self.value),
+            "layout" => format!("L({})", self.value),
             _ => format!("UNKNOWN({})", self.value),
         }
     }
@@ -80,13 +145,17 @@ impl QueryAst {
     fn print_node(&self, node: &QueryAstNode, depth: usize) {
         let indent = "  ".repeat(depth);
         println!("{}└─ {} : '{}'", indent, node.element_type, node.value);
+        for (name, value) in &node.attributes {
+            println!("{}     @{} = {}", indent, name, value);
+        }
 
         for child in &node.children {
             self.print_node(child, depth + 1);
         }
     }
 
-    /// Helper to get all nodes of a specific type
+    /// Helper to get all nodes of a specific type; see [`QueryAst::select`]
+    /// for matching by symbol, ancestry and position
     pub fn find_nodes(&self, element_type: &str) -> Vec<&QueryAstNode> {
         let mut result = Vec::new();
         self.find_nodes_recursive(&self.root, element_type, &mut result);
@@ -114,7 +183,8 @@ impl QueryAst {
         self.root.to_string()
     }
 
-    /// Transforms the AST using a custom transformation function
+    /// Transforms the AST using a custom transformation function, called on
+    /// the root only; see [`crate::visit`] for walks over every node
     pub fn transform_with<F>(&self, transformer: F) -> String
     where
         F: Fn(&QueryAstNode) -> String,
@@ -131,6 +201,11 @@ impl QueryAst {
         transformer(node)
     }
 
+    /// Re-render the text from the AST with `renderer`
+    pub fn render(&self, renderer: &dyn Renderer) -> String {
+        self.root.render(renderer, true)
+    }
+
     /// Creates a new QueryAst from an existing AST but with a different root
     pub fn with_root(&self, new_root: QueryAstNode) -> Self {
         QueryAst {
@@ -153,6 +228,11 @@ impl QueryAst {
     pub fn to_debug_string(&self) -> String {
         self.root.to_debug_string()
     }
+
+    /// The generated terminals in output order, with their classes
+    pub fn tokens(&self) -> Vec<QueryToken> {
+        self.root.tokens()
+    }
 }
 
 /// Represents an element in the grammar, either a terminal or a non-terminal
@@ -162,6 +242,20 @@ pub enum Element {
     Terminal(String),
     /// A non-terminal symbol (reference to another rule)
     NonTerminal(String),
+    /// A terminal generated from a regular expression, written as `/pattern/`
+    Regex(RegexGenerator),
+    /// An element whose generated text is captured, written as `<symbol> as $name`
+    Bind(Binding),
+    /// The most recent visible value of a variable, written as `$name`
+    Variable(String),
+    /// A random visible value of a variable, written as `@pick($name)`
+    Pick(String),
+    /// A terminal, regex, variable or pick tagged with its token class,
+    /// written as `element :class`
+    Classified(Box<Element>, TokenClass),
+    /// A layout directive for the renderer: `~`, `@space`, `@newline`,
+    /// `@indent` or `@dedent`
+    Layout(Layout),
 }
 
 /// Represents a production rule in the grammar
@@ -169,17 +263,28 @@ pub enum Element {
 pub struct Production {
     /// The sequence of elements in this production
     pub elements: Vec<Element>,
+    /// Name of the predicate that must accept this production's expansion
+    pub guard: Option<String>,
+    /// Relative weight when choosing among the symbol's productions
+    pub weight: f64,
 }
 
 /// Configuration options for grammar behavior
 #[derive(Debug, Clone)]
 pub struct GrammarConfig {
-    /// Whether to add spaces between elements automatically
+    /// Whether the renderer decides where spaces go; otherwise every two
+    /// terminals are separated by one space unless layout directives say otherwise
     pub auto_spacing: bool,
     /// Whether to trim whitespace from output
     pub trim_output: bool,
     /// Maximum recursion depth for expansion (to prevent infinite recursion)
     pub max_recursion_depth: usize,
+    /// Maximum number of extra repetitions for unbounded regex quantifiers (`*`, `+`, `{n,}`)
+    pub regex_max_repeat: u32,
+    /// Maximum attempts at a single choice point when predicates reject its expansion
+    pub max_retries: usize,
+    /// Maximum rejected attempts over a whole generation before giving up
+    pub max_backtracks: usize,
 }
 
 impl Default for GrammarConfig {
@@ -188,6 +293,9 @@ impl Default for GrammarConfig {
             auto_spacing: true,
             trim_output: true,
             max_recursion_depth: 100,
+            regex_max_repeat: 8,
+            max_retries: 10,
+            max_backtracks: 1000,
         }
     }
 }
@@ -201,6 +309,14 @@ pub struct Grammar {
     config: GrammarConfig,
     /// Optional validator for post-processing generated text
     validator: Box<dyn GrammarValidator>,
+    /// Joins generated terminals into text
+    renderer: Box<dyn Renderer>,
+    /// Predicates guarding productions, by name
+    predicates: HashMap<String, Predicate>,
+    /// Attributes declared per non-terminal, in evaluation order
+    attributes: HashMap<String, Vec<AttributeRule>>,
+    /// Production weights that apply when a symbol is expanded in a given context
+    context_weights: HashMap<WeightContext, HashMap<String, Vec<f64>>>,
 }
 
 /// Token types for the grammar parser
@@ -208,15 +324,22 @@ pub struct Grammar {
 enum Token {
     NonTerminal(String),
     Terminal(String),
-    RuleSeparator, // ::=
-    ListStart,     // [
-    ListEnd,       // ]
-    Quote,         // '
-    Comma,         // ,
+    Quoted(String),   // 'text' or "text", never a keyword
+    Regex(String),    // /pattern/
+    Variable(String), // $name
+    Pick(String),     // @pick($name)
+    Class(String),    // :class
+    Layout(Layout),   // ~ @space @newline @indent @dedent
+    RuleSeparator,    // ::=
+    ListStart,        // [
+    ListEnd,          // ]
+    Quote,            // '
+    Comma,            // ,
     EndOfFile,
 }
 
 /// Tokenizer for the grammar parser
+#[derive(Clone)]
 struct Tokenizer<'a> {
     chars: Peekable<Chars<'a>>,
     current_line: String,
@@ -242,37 +365,42 @@ impl<'a> Tokenizer<'a> {
                 return Ok(Token::EndOfFile);
             }
 
-            if let Some(&c) = self.chars.peek() {
-                if c.is_whitespace() || c == '\n' {
-                    self.chars.next();
-                    continue;
-                }
+            if let Some(&c) = self.chars.peek()
+                && (c.is_whitespace() || c == '\n')
+            {
+                self.chars.next();
+                continue;
             }
 
             break;
         }
 
-        match self.chars.peek() {
-            Some(&'<') => self.parse_non_terminal(),
-            Some(&'[') => {
+        match self.chars.peek().copied() {
+            Some('<') => self.parse_non_terminal(),
+            Some('[') => {
                 self.chars.next();
                 Ok(Token::ListStart)
             }
-            Some(&']') => {
+            Some(']') => {
                 self.chars.next();
                 Ok(Token::ListEnd)
             }
-            Some(&'\\') => {
+            Some('\\') => {
                 self.chars.next();
                 self.chars.next();
                 Ok(Token::Quote)
             }
-            Some(&',') => {
+            Some(',') => {
                 self.chars.next();
                 Ok(Token::Comma)
             }
-            Some(&':') => self.parse_rule_separator(),
-            Some(&_) => self.parse_terminal(),
+            Some(':') if self.starts_class() => self.parse_class(),
+            Some(':') => self.parse_rule_separator(),
+            Some('/') if self.starts_regex() => self.parse_regex(),
+            Some('$') if self.starts_variable() => self.parse_variable(),
+            Some('@') if self.starts_pick() => self.parse_pick(),
+            Some('~' | '@') => self.parse_layout_or_terminal(),
+            Some(_) => self.parse_terminal(),
             None => Ok(Token::EndOfFile),
         }
     }
@@ -346,20 +474,34 @@ impl<'a> Tokenizer<'a> {
         let mut quote_char = None;
 
         // Check if we're starting with a quote
-        if let Some(&c) = self.chars.peek() {
-            if c == '"' || c == '\'' {
-                in_quotes = true;
-                quote_char = Some(c);
-                self.current_line.push(c);
-                self.chars.next();
-            }
+        if let Some(&c) = self.chars.peek()
+            && (c == '"' || c == '\'')
+        {
+            in_quotes = true;
+            quote_char = Some(c);
+            self.current_line.push(c);
+            self.chars.next();
         }
 
         while let Some(&c) = self.chars.peek() {
             match c {
                 c if in_quotes && Some(c) == quote_char => {
                     self.chars.next();
-                    return Ok(Token::Terminal(value));
+                    return Ok(Token::Quoted(value));
+                }
+                // A backslash escapes the closing quote or another
+                // backslash, and is otherwise part of the text
+                '\\' if in_quotes => {
+                    self.current_line.push(c);
+                    self.chars.next();
+                    match self.chars.peek() {
+                        Some(&next) if next == '\\' || Some(next) == quote_char => {
+                            value.push(next);
+                            self.current_line.push(next);
+                            self.chars.next();
+                        }
+                        _ => value.push(c),
+                    }
                 }
                 c if in_quotes => {
                     value.push(c);
@@ -385,6 +527,128 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// A `/` opens a `/pattern/` regex terminal only when an unescaped `/`
+    /// closes it before the element ends at a `,`, `]` or the end of the
+    /// line, so terminals like `/` and `/=` stay plain. Commas and brackets
+    /// inside character classes, groups and repetitions belong to the
+    /// pattern.
+    fn starts_regex(&self) -> bool {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        if !matches!(ahead.peek(), Some(&c) if !c.is_whitespace() && !matches!(c, ',' | ']' | '/'))
+        {
+            return false;
+        }
+
+        let (mut class, mut depth) = (false, 0usize);
+        while let Some(c) = ahead.next() {
+            match c {
+                '/' => return true,
+                '\\' => {
+                    ahead.next();
+                }
+                '\n' => return false,
+                ']' if class => class = false,
+                _ if class => {}
+                '[' => class = true,
+                '(' | '{' => depth += 1,
+                ')' | '}' => depth = depth.saturating_sub(1),
+                ',' | ']' if depth == 0 => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn parse_regex(&mut self) -> Result<Token> {
+        self.chars.next(); // Consume '/'
+        let mut pattern = String::new();
+
+        while let Some(c) = self.chars.next() {
+            self.current_line.push(c);
+            match c {
+                '/' => return Ok(Token::Regex(pattern)),
+                '\\' => {
+                    pattern.push(c);
+                    if let Some(escaped) = self.chars.next() {
+                        self.current_line.push(escaped);
+                        pattern.push(escaped);
+                    }
+                }
+                _ => pattern.push(c),
+            }
+        }
+
+        Err(GrammarError::Parse(format!(
+            "Unclosed regex at line {}: {}",
+            self.line_number, self.current_line
+        )))
+    }
+
+    fn starts_variable(&self) -> bool {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        matches!(ahead.next(), Some(c) if c.is_alphabetic() || c == '_')
+    }
+
+    fn parse_identifier(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_alphanumeric() && c != '_' {
+                break;
+            }
+            name.push(c);
+            self.current_line.push(c);
+            self.chars.next();
+        }
+        name
+    }
+
+    fn parse_variable(&mut self) -> Result<Token> {
+        self.chars.next(); // Consume '$'
+        Ok(Token::Variable(self.parse_identifier()))
+    }
+
+    fn starts_pick(&self) -> bool {
+        self.chars.clone().take(7).collect::<String>() == "@pick($"
+    }
+
+    fn parse_pick(&mut self) -> Result<Token> {
+        for _ in 0..7 {
+            self.chars.next(); // Consume '@pick($'
+        }
+        let name = self.parse_identifier();
+
+        if self.chars.next() == Some(')') {
+            Ok(Token::Pick(name))
+        } else {
+            Err(GrammarError::Parse(format!(
+                "Unclosed @pick at line {}: {}",
+                self.line_number, self.current_line
+            )))
+        }
+    }
+
+    /// An unquoted `~` or `@word` matching a directive is layout, anything
+    /// else a plain terminal
+    fn parse_layout_or_terminal(&mut self) -> Result<Token> {
+        match self.parse_terminal()? {
+            Token::Terminal(text) => Ok(text.parse().map_or(Token::Terminal(text), Token::Layout)),
+            token => Ok(token),
+        }
+    }
+
+    fn starts_class(&self) -> bool {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        matches!(ahead.next(), Some(c) if c.is_alphabetic())
+    }
+
+    fn parse_class(&mut self) -> Result<Token> {
+        self.chars.next(); // Consume ':'
+        Ok(Token::Class(self.parse_identifier()))
+    }
+
     fn parse_rule_separator(&mut self) -> Result<Token> {
         let mut chars = String::new();
         for _ in 0..3 {
@@ -406,6 +670,9 @@ impl<'a> Tokenizer<'a> {
     }
 }
 
+/// Weights a production declares with `weight W when <parent> ...`
+type DeclaredWeights = Vec<(WeightContext, f64)>;
+
 /// Parser for the grammar rules
 struct Parser<'a> {
     tokenizer: Tokenizer<'a>,
@@ -428,6 +695,11 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// The token after the current one, without consuming either
+    fn peek(&self) -> Result<Token> {
+        self.tokenizer.clone().next_token()
+    }
+
     fn expect(&mut self, expected: Token) -> Result<()> {
         if self.current_token == expected {
             self.advance()?;
@@ -440,7 +712,7 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_rule(&mut self) -> Result<(String, Production)> {
+    fn parse_rule(&mut self) -> Result<(String, Production, DeclaredWeights)> {
         let non_terminal = match &self.current_token {
             Token::NonTerminal(name) => name.clone(),
             _ => return Err(GrammarError::Parse("Expected non-terminal".to_string())),
@@ -454,31 +726,35 @@ impl<'a> Parser<'a> {
 
         self.expect(Token::ListEnd)?;
 
-        Ok((non_terminal, production))
+        let mut production = production;
+        let context_weights = self.parse_annotations(&mut production)?;
+
+        Ok((non_terminal, production, context_weights))
     }
 
     fn parse_production(&mut self) -> Result<Production> {
         let mut elements = Vec::new();
 
         loop {
-            match &self.current_token {
-                Token::NonTerminal(name) => {
-                    elements.push(Element::NonTerminal(name.clone()));
-                    self.advance()?;
-                }
-                Token::Terminal(value) => {
-                    elements.push(Element::Terminal(value.clone()));
-                    self.advance()?;
-                }
+            let element = match &self.current_token {
+                Token::NonTerminal(name) => Element::NonTerminal(name.clone()),
+                Token::Terminal(value) | Token::Quoted(value) => Element::Terminal(value.clone()),
+                Token::Regex(pattern) => Element::Regex(RegexGenerator::new(pattern)?),
+                Token::Variable(name) => Element::Variable(name.clone()),
+                Token::Pick(name) => Element::Pick(name.clone()),
+                Token::Quote => Element::Terminal("'".to_string()),
+                Token::Layout(layout) => Element::Layout(*layout),
                 Token::Comma => {
                     self.advance()?;
-                }
-                Token::Quote => {
-                    elements.push(Element::Terminal("'".to_string()));
-                    self.advance()?;
+                    continue;
                 }
                 _ => break, // Allow other tokens to end the production
-            }
+            };
+            self.advance()?;
+
+            let element = self.parse_class(element)?;
+            let element = self.parse_binding(element)?;
+            elements.push(element);
         }
 
         if elements.is_empty() {
@@ -487,7 +763,175 @@ impl<'a> Parser<'a> {
             ));
         }
 
-        Ok(Production { elements })
+        Ok(Production {
+            elements,
+            guard: None,
+            weight: 1.0,
+        })
+    }
+
+    /// Parse the `if predicate_name` and `weight W [when <parent> ...]`
+    /// clauses that may follow a production, returning its context weights
+    fn parse_annotations(&mut self, production: &mut Production) -> Result<DeclaredWeights> {
+        let mut context_weights = Vec::new();
+
+        loop {
+            match &self.current_token {
+                Token::Terminal(word) if word == "if" => {
+                    self.advance()?;
+                    production.guard = Some(self.parse_word("predicate name after 'if'")?);
+                }
+                Token::Terminal(word) if word == "weight" => {
+                    self.advance()?;
+                    let weight: f64 = self.parse_number("number after 'weight'")?;
+                    if !weight.is_finite() || weight < 0.0 {
+                        return Err(GrammarError::Parse(format!(
+                            "Weight must be finite and non-negative, found {}",
+                            weight
+                        )));
+                    }
+
+                    if self.current_token == Token::Terminal("when".to_string()) {
+                        context_weights.push((self.parse_weight_context()?, weight));
+                    } else {
+                        production.weight = weight;
+                    }
+                }
+                _ => return Ok(context_weights),
+            }
+        }
+    }
+
+    /// Parse `when <parent> [production P [position N]]`
+    fn parse_weight_context(&mut self) -> Result<WeightContext> {
+        self.advance()?; // Consume 'when'
+
+        let mut context = match &self.current_token {
+            Token::NonTerminal(name) => WeightContext::parent(name),
+            other => {
+                return Err(GrammarError::Parse(format!(
+                    "Expected <symbol> after 'when', found {:?}",
+                    other
+                )));
+            }
+        };
+        self.advance()?;
+
+        if self.current_token == Token::Terminal("production".to_string()) {
+            self.advance()?;
+            context.production = Some(self.parse_number("index after 'production'")?);
+
+            if self.current_token == Token::Terminal("position".to_string()) {
+                self.advance()?;
+                context.position = Some(self.parse_number("index after 'position'")?);
+            }
+        }
+
+        Ok(context)
+    }
+
+    /// Consume a plain terminal, such as a name or number in an annotation
+    fn parse_word(&mut self, expected: &str) -> Result<String> {
+        match &self.current_token {
+            Token::Terminal(word) | Token::Quoted(word) => {
+                let word = word.clone();
+                self.advance()?;
+                Ok(word)
+            }
+            other => Err(GrammarError::Parse(format!(
+                "Expected {}, found {:?}",
+                expected, other
+            ))),
+        }
+    }
+
+    fn parse_number<T: std::str::FromStr>(&mut self, expected: &str) -> Result<T> {
+        let word = self.parse_word(expected)?;
+        word.parse()
+            .map_err(|_| GrammarError::Parse(format!("Expected {}, found {:?}", expected, word)))
+    }
+
+    /// Parse an optional `:class` suffix following an element
+    fn parse_class(&mut self, element: Element) -> Result<Element> {
+        let Token::Class(name) = &self.current_token else {
+            return Ok(element);
+        };
+        let class = name.parse()?;
+        self.advance()?;
+
+        classify(element, class)
+    }
+
+    /// Parse an optional `as $name [in <symbol>]` suffix following an element.
+    ///
+    /// `as` and `in` are only keywords when followed by a `$variable` and a
+    /// `<symbol>` respectively, so grammars can still use them as terminals,
+    /// as in SQL aliases.
+    fn parse_binding(&mut self, element: Element) -> Result<Element> {
+        if self.current_token != Token::Terminal("as".to_string()) {
+            return Ok(element);
+        }
+        let Token::Variable(variable) = self.peek()? else {
+            return Ok(element);
+        };
+        self.advance()?;
+        self.advance()?;
+
+        let mut scope = None;
+        if self.current_token == Token::Terminal("in".to_string())
+            && let Token::NonTerminal(name) = self.peek()?
+        {
+            scope = Some(name);
+            self.advance()?;
+            self.advance()?;
+        }
+
+        Ok(Element::Bind(Binding {
+            element: Box::new(element),
+            variable,
+            scope,
+        }))
+    }
+}
+
+/// Parse a grammar in the text format
+impl FromStr for Grammar {
+    type Err = GrammarError;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut grammar = Grammar::new();
+        let mut parser = Parser::new(text)?;
+        let mut declared = Vec::new();
+
+        while parser.current_token != Token::EndOfFile {
+            let (non_terminal, production, context_weights) = parser.parse_rule()?;
+
+            let productions = grammar.rules.entry(non_terminal.clone()).or_default();
+            for (context, weight) in context_weights {
+                declared.push((context, non_terminal.clone(), productions.len(), weight));
+            }
+            productions.push(production);
+        }
+
+        // Productions without a weight for a context keep their unconditioned weight
+        for (context, symbol, index, weight) in declared {
+            let productions = &grammar.rules[&symbol];
+            let weights = grammar
+                .context_weights
+                .entry(context)
+                .or_default()
+                .entry(symbol)
+                .or_insert_with(|| productions.iter().map(|p| p.weight).collect());
+            weights[index] = weight;
+        }
+
+        Ok(grammar)
+    }
+}
+
+impl Default for Grammar {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -498,6 +942,10 @@ impl Grammar {
             rules: HashMap::new(),
             config: GrammarConfig::default(),
             validator: Box::new(NoopValidator),
+            renderer: Box::new(RuleRenderer::default()),
+            predicates: HashMap::new(),
+            attributes: HashMap::new(),
+            context_weights: HashMap::new(),
         }
     }
 
@@ -507,9 +955,33 @@ impl Grammar {
             rules: HashMap::new(),
             config,
             validator: Box::new(NoopValidator),
+            renderer: Box::new(RuleRenderer::default()),
+            predicates: HashMap::new(),
+            attributes: HashMap::new(),
+            context_weights: HashMap::new(),
         }
     }
 
+    /// Set the renderer that joins generated terminals into text
+    pub fn with_renderer(mut self, renderer: Box<dyn Renderer>) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// The renderer that joins generated terminals into text
+    pub fn renderer(&self) -> &dyn Renderer {
+        self.renderer.as_ref()
+    }
+
+    /// Re-render the text below an AST node the way generation renders it
+    pub fn render(&self, node: &QueryAstNode) -> String {
+        node.render(self.renderer(), self.config.auto_spacing)
+    }
+
+    pub(crate) fn render_pieces(&self, pieces: &[Piece]) -> String {
+        render::render(self.renderer(), self.config.auto_spacing, pieces)
+    }
+
     /// Set a validator for this grammar
     pub fn with_validator(mut self, validator: Box<dyn GrammarValidator>) -> Self {
         self.validator = validator;
@@ -520,7 +992,6 @@ impl Grammar {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = File::open(path).map_err(GrammarError::Io)?;
         let reader = io::BufReader::new(file);
-        let mut grammar = Grammar::new();
 
         let mut current_content = String::new();
         for line in reader.lines() {
@@ -529,21 +1000,58 @@ impl Grammar {
             current_content.push('\n');
         }
 
-        let mut parser = Parser::new(&current_content)?;
-
-        while parser.current_token != Token::EndOfFile {
-            let (non_terminal, production) = parser.parse_rule()?;
+        current_content.parse()
+    }
 
-            if grammar.rules.contains_key(&non_terminal) {
-                if let Some(productions) = grammar.rules.get_mut(&non_terminal) {
-                    productions.push(production);
+    /// Write the grammar in the text format read by [`Grammar::from_file`].
+    ///
+    /// Symbols are written in sorted order, each production on its own line
+    /// with its predicate, weight and context weights. Terminals are quoted
+    /// where they would otherwise read back as something else. Productions
+    /// without elements are written as `[]`, which the format does not accept.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut symbols: Vec<_> = self.rules.iter().collect();
+        symbols.sort_by(|a, b| a.0.cmp(b.0));
+        let mut contexts: Vec<_> = self.context_weights.iter().collect();
+        contexts.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (i, (symbol, productions)) in symbols.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            for (p, production) in productions.iter().enumerate() {
+                let elements = elements_text(&production.elements);
+                write!(out, "<{}> ::= {}", symbol, elements)?;
+                if let Some(guard) = &production.guard {
+                    write!(out, " if {}", guard)?;
                 }
-            } else {
-                grammar.rules.insert(non_terminal, vec![production]);
+                if production.weight != 1.0 {
+                    write!(out, " weight {}", production.weight)?;
+                }
+                for (context, weights) in &contexts {
+                    let Some(weight) = weights.get(symbol.as_str()).and_then(|w| w.get(p)) else {
+                        continue;
+                    };
+                    write!(out, " weight {} when <{}>", weight, context.parent)?;
+                    if let Some(production) = context.production {
+                        write!(out, " production {}", production)?;
+                        if let Some(position) = context.position {
+                            write!(out, " position {}", position)?;
+                        }
+                    }
+                }
+                writeln!(out)?;
             }
         }
+        Ok(())
+    }
 
-        Ok(grammar)
+    /// The grammar in the text format; see [`Grammar::write_text`]
+    pub fn to_text(&self) -> String {
+        let mut out = Vec::new();
+        self.write_text(&mut out)
+            .expect("writing to a Vec cannot fail");
+        String::from_utf8(out).expect("grammars are written from strings")
     }
 
     /// Parse a production rule from a string
@@ -555,32 +1063,252 @@ impl Grammar {
     /// Add a rule to the grammar
     pub fn add_rule(&mut self, non_terminal: &str, elements: Vec<&str>) -> Result<&mut Self> {
         let production = self.parse_elements(elements)?;
+        Ok(self.add_production(non_terminal, production))
+    }
 
-        if self.rules.contains_key(non_terminal) {
-            if let Some(productions) = self.rules.get_mut(non_terminal) {
-                productions.push(production);
+    /// Add a rule that may only be chosen when the named predicate accepts its expansion
+    pub fn add_guarded_rule(
+        &mut self,
+        non_terminal: &str,
+        elements: Vec<&str>,
+        predicate: &str,
+    ) -> Result<&mut Self> {
+        let mut production = self.parse_elements(elements)?;
+        production.guard = Some(predicate.to_string());
+        Ok(self.add_production(non_terminal, production))
+    }
+
+    /// Add an already built production to a non-terminal's rules.
+    ///
+    /// Unlike the text format, this accepts a production without elements,
+    /// which generates nothing. Contexts that weight the non-terminal give
+    /// the new production its unconditioned weight, as the text format does.
+    pub fn add_production(&mut self, non_terminal: &str, production: Production) -> &mut Self {
+        for symbols in self.context_weights.values_mut() {
+            if let Some(weights) = symbols.get_mut(non_terminal) {
+                weights.push(production.weight);
             }
-        } else {
-            self.rules
-                .insert(non_terminal.to_string(), vec![production]);
         }
+        self.rules
+            .entry(non_terminal.to_string())
+            .or_default()
+            .push(production);
+        self
+    }
+
+    /// A copy of this grammar with no rules and no context weights, keeping
+    /// its configuration, renderer, validator, predicates and attributes
+    pub(crate) fn without_rules(&self) -> Grammar {
+        Grammar {
+            rules: HashMap::new(),
+            context_weights: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Keep only the rules of symbols `keep` accepts, along with the context
+    /// weights whose symbol and parent are both kept
+    pub(crate) fn retain_rules(&mut self, keep: impl Fn(&str) -> bool) {
+        self.rules.retain(|symbol, _| keep(symbol));
+        self.context_weights.retain(|context, weights| {
+            weights.retain(|symbol, _| keep(symbol));
+            keep(&context.parent) && !weights.is_empty()
+        });
+    }
+
+    /// Register a predicate that guarded productions refer to by name
+    pub fn add_predicate<F>(&mut self, name: &str, predicate: F) -> &mut Self
+    where
+        F: Fn(&PredicateContext) -> bool + Send + Sync + 'static,
+    {
+        self.predicates
+            .insert(name.to_string(), Predicate::new(predicate));
+        self
+    }
+
+    /// Declare an inherited attribute on `symbol`, computed from its parent
+    /// before the symbol expands
+    pub fn add_inherited<F>(&mut self, symbol: &str, name: &str, rule: F) -> &mut Self
+    where
+        F: Fn(&AttributeContext) -> AttrValue + Send + Sync + 'static,
+    {
+        self.add_attribute(
+            symbol,
+            AttributeRule::new(name, AttributeKind::Inherited, rule),
+        )
+    }
+
+    /// Declare a synthesized attribute on `symbol`, computed from its
+    /// children once the symbol has expanded
+    pub fn add_synthesized<F>(&mut self, symbol: &str, name: &str, rule: F) -> &mut Self
+    where
+        F: Fn(&AttributeContext) -> AttrValue + Send + Sync + 'static,
+    {
+        self.add_attribute(
+            symbol,
+            AttributeRule::new(name, AttributeKind::Synthesized, rule),
+        )
+    }
 
+    /// Declare an attribute on `symbol`
+    pub fn add_attribute(&mut self, symbol: &str, rule: AttributeRule) -> &mut Self {
+        self.attributes
+            .entry(symbol.to_string())
+            .or_default()
+            .push(rule);
+        self
+    }
+
+    /// Attributes declared on `symbol`, in evaluation order
+    pub fn attributes(&self, symbol: &str) -> &[AttributeRule] {
+        self.attributes.get(symbol).map_or(&[], Vec::as_slice)
+    }
+
+    /// Set the weights of a symbol's productions, in production order
+    pub fn set_weights(&mut self, symbol: &str, weights: &[f64]) -> Result<&mut Self> {
+        self.check_weights(symbol, weights)?;
+        if let Some(productions) = self.rules.get_mut(symbol) {
+            for (production, &weight) in productions.iter_mut().zip(weights) {
+                production.weight = weight;
+            }
+        }
         Ok(self)
     }
 
+    /// Set the weights of a symbol's productions when it is expanded within `context`
+    pub fn set_context_weights(
+        &mut self,
+        context: WeightContext,
+        symbol: &str,
+        weights: &[f64],
+    ) -> Result<&mut Self> {
+        self.check_weights(symbol, weights)?;
+        self.context_weights
+            .entry(context)
+            .or_default()
+            .insert(symbol.to_string(), weights.to_vec());
+        Ok(self)
+    }
+
+    /// Weights declared for `symbol` in exactly this context, if any
+    pub fn context_weights(&self, context: &WeightContext, symbol: &str) -> Option<&[f64]> {
+        self.context_weights
+            .get(context)
+            .and_then(|symbols| symbols.get(symbol))
+            .map(Vec::as_slice)
+    }
+
+    /// Every context declaring weights for `symbol`, with those weights
+    pub(crate) fn contexts_of(&self, symbol: &str) -> Vec<(&WeightContext, &[f64])> {
+        self.context_weights
+            .iter()
+            .filter_map(|(context, symbols)| Some((context, symbols.get(symbol)?.as_slice())))
+            .collect()
+    }
+
+    /// The weights of `symbol`'s productions for its occurrence as element
+    /// `position` of production `production` of `parent`, from the most
+    /// specific context that sets them, or `None` to use the unconditioned
+    /// weights
+    pub(crate) fn weights_within(
+        &self,
+        symbol: &str,
+        parent: &str,
+        production: usize,
+        position: usize,
+    ) -> Option<&[f64]> {
+        if self.context_weights.is_empty() {
+            return None;
+        }
+        let within = WeightContext::production(parent, production);
+        let contexts = [
+            within.clone().at(position),
+            within,
+            WeightContext::parent(parent),
+        ];
+        contexts
+            .iter()
+            .find_map(|context| self.context_weights(context, symbol))
+    }
+
+    /// Natural log of the probability that generation expands `symbol` with
+    /// production `index`, as element `position` of production `production`
+    /// of `parent` when `within` is `(parent, production, position)`
+    pub(crate) fn choice_log_probability(
+        &self,
+        symbol: &str,
+        index: usize,
+        within: Option<(&str, usize, usize)>,
+    ) -> f64 {
+        let productions = &self.rules[symbol];
+        let context = within.and_then(|(parent, production, position)| {
+            self.weights_within(symbol, parent, production, position)
+        });
+        let (weight, total) = match context {
+            Some(weights) => (weights[index], weights.iter().sum::<f64>()),
+            None => (
+                productions[index].weight,
+                productions.iter().map(|p| p.weight).sum(),
+            ),
+        };
+        // Generation chooses uniformly when no production has weight
+        if total > 0.0 {
+            (weight / total).ln()
+        } else {
+            -(productions.len() as f64).ln()
+        }
+    }
+
+    /// Apply weights learned by a `Trainer` or loaded from a weight file
+    pub fn apply_weights(&mut self, weights: &ProductionWeights) -> Result<()> {
+        for (symbol, symbol_weights) in &weights.symbols {
+            self.set_weights(symbol, symbol_weights)?;
+        }
+        for context in &weights.contexts {
+            self.set_context_weights(context.context.clone(), &context.symbol, &context.weights)?;
+        }
+        Ok(())
+    }
+
+    /// Load and apply a JSON weight file
+    pub fn load_weights<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.apply_weights(&ProductionWeights::from_json_file(path)?)
+    }
+
+    fn check_weights(&self, symbol: &str, weights: &[f64]) -> Result<()> {
+        let productions = self
+            .rules
+            .get(symbol)
+            .ok_or_else(|| GrammarError::UnknownNonTerminal(symbol.to_string()))?;
+
+        if weights.len() != productions.len() {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "<{}> has {} productions but {} weights were given",
+                symbol,
+                productions.len(),
+                weights.len()
+            )));
+        }
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "Weights for <{}> must be finite and non-negative",
+                symbol
+            )));
+        }
+        Ok(())
+    }
+
+    /// Parse `text` as `start_symbol`, returning its derivation tree
+    pub fn parse(&self, start_symbol: &str, text: &str) -> Result<QueryAstNode> {
+        EarleyParser::new(self)?.parse(start_symbol, text)
+    }
+
     /// Parse a vector of strings into a Production
     fn parse_elements(&self, elements: Vec<&str>) -> Result<Production> {
         let mut parsed_elements = Vec::new();
 
         for element in elements {
-            if element.starts_with('<') && element.ends_with('>') {
-                // This is a non-terminal
-                let name = element[1..element.len() - 1].to_string();
-                parsed_elements.push(Element::NonTerminal(name));
-            } else {
-                // This is a terminal
-                parsed_elements.push(Element::Terminal(element.to_string()));
-            }
+            parsed_elements.push(Self::parse_element(element)?);
         }
 
         if parsed_elements.is_empty() {
@@ -591,23 +1319,133 @@ impl Grammar {
 
         Ok(Production {
             elements: parsed_elements,
+            guard: None,
+            weight: 1.0,
         })
     }
 
-    /// Generate random text based on the grammar rules
+    /// Parse a single element string as accepted by `add_rule`
+    fn parse_element(element: &str) -> Result<Element> {
+        if let Some((inner, binding)) = element.split_once(" as $") {
+            // This is a binding, `<symbol> as $name [in <scope>]`
+            let (variable, scope) = match binding.split_once(" in ") {
+                Some((variable, scope)) if scope.starts_with('<') && scope.ends_with('>') => {
+                    (variable, Some(scope[1..scope.len() - 1].to_string()))
+                }
+                Some(_) => {
+                    return Err(GrammarError::Parse(format!(
+                        "Expected <symbol> after 'in': {}",
+                        element
+                    )));
+                }
+                None => (binding, None),
+            };
+
+            return Ok(Element::Bind(Binding {
+                element: Box::new(Self::parse_element(inner.trim())?),
+                variable: variable.trim().to_string(),
+                scope,
+            }));
+        }
+
+        if let Some((inner, class)) = element.rsplit_once(" :")
+            && let Ok(class) = class.parse()
+        {
+            // This is a terminal tagged with its class, `text :class`
+            return classify(Self::parse_element(inner.trim())?, class);
+        }
+
+        if let Ok(layout) = element.parse() {
+            // This is a layout directive
+            return Ok(Element::Layout(layout));
+        }
+
+        if element.starts_with('<') && element.ends_with('>') {
+            // This is a non-terminal
+            let name = element[1..element.len() - 1].to_string();
+            Ok(Element::NonTerminal(name))
+        } else if element.len() > 2 && element.starts_with('/') && element.ends_with('/') {
+            // This is a regex terminal
+            let pattern = &element[1..element.len() - 1];
+            Ok(Element::Regex(RegexGenerator::new(pattern)?))
+        } else if let Some(name) = element
+            .strip_prefix("@pick($")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            Ok(Element::Pick(name.to_string()))
+        } else if let Some(name) = element
+            .strip_prefix('$')
+            .filter(|name| name.starts_with(|c: char| c.is_alphabetic() || c == '_'))
+        {
+            Ok(Element::Variable(name.to_string()))
+        } else {
+            // This is a terminal
+            Ok(Element::Terminal(element.to_string()))
+        }
+    }
+
+    /// Generate random text based on the grammar rules.
+    ///
+    /// When predicates reject every derivation the text is
+    /// `<no_valid_derivation>`; use [`Grammar::try_generate`] for the reason.
+    ///
+    /// # Panics
+    ///
+    /// When the grammar is invalid, such as a guard naming a predicate that
+    /// was never registered; [`Grammar::try_generate`] returns the error.
     pub fn generate(&self, start_symbol: &str) -> QueryAst {
-        // Create root node for the AST
-        let root_node = QueryAstNode {
-            element_type: "non_terminal".to_string(),
-            value: start_symbol.to_string(),
-            children: Vec::new(),
-        };
+        self.generate_with_rng(start_symbol, &mut rand::thread_rng())
+    }
+
+    /// Generate random text drawing every choice from `rng`, so a seeded
+    /// generator reproduces the same output
+    pub fn generate_with_rng<R: Rng + ?Sized>(&self, start_symbol: &str, rng: &mut R) -> QueryAst {
+        match self.try_generate_with_rng(start_symbol, rng) {
+            Ok(ast) => ast,
+            Err(GrammarError::NoValidDerivation(_)) => QueryAst {
+                text: "<no_valid_derivation>".to_string(),
+                type_name: start_symbol.to_string(),
+                root: QueryAstNode::new("error", "no_valid_derivation"),
+            },
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Generate random text, failing when predicates reject every derivation
+    /// within the retry budget
+    pub fn try_generate(&self, start_symbol: &str) -> Result<QueryAst> {
+        self.try_generate_with_rng(start_symbol, &mut rand::thread_rng())
+    }
+
+    /// Like [`Grammar::try_generate`], drawing every choice from `rng`
+    pub fn try_generate_with_rng<R: Rng + ?Sized>(
+        &self,
+        start_symbol: &str,
+        rng: &mut R,
+    ) -> Result<QueryAst> {
+        self.check_predicates()?;
+
+        let mut expander = Expander::new(self, rng);
 
         // Start generation from the start symbol
-        let (result, ast_root) = self.expand_non_terminal(start_symbol, root_node);
+        let mut ast_root = expander
+            .expand_symbol(start_symbol)
+            .map_err(|Rejected| expander.failure(start_symbol))?;
+
+        // Handle recursion limit if reached
+        if expander.expanded >= self.config.max_recursion_depth {
+            expander
+                .tokens
+                .push(Piece::Token("<recursion_limit_exceeded>".to_string()));
+            ast_root
+                .children
+                .push(QueryAstNode::new("error", "recursion_limit_exceeded"));
+        }
 
         // Apply validation/post-processing
-        let result = self.validator.validate(&result);
+        let result = self
+            .validator
+            .validate(&self.render_pieces(&expander.tokens));
 
         // Apply final trimming if configured
         let text = if self.config.trim_output {
@@ -616,133 +1454,131 @@ impl Grammar {
             result
         };
 
-        QueryAst {
+        Ok(QueryAst {
             text,
             type_name: start_symbol.to_string(),
             root: ast_root,
-        }
+        })
     }
 
-    /// Recursively expand a non-terminal symbol, now returning both the text and AST node
-    fn expand_non_terminal(&self, symbol: &str, ast_node: QueryAstNode) -> (String, QueryAstNode) {
-        let mut depth = 0;
-        let mut rng = rand::thread_rng();
-        let mut tokens = Vec::new();
-
-        // Create a stack for production elements and AST nodes
-        // Each stack frame contains (Element, AST_Node, Parent_Index)
-        // where Parent_Index is the index in ast_nodes of the parent node
-        let mut stack: Vec<(Element, usize)> = Vec::new();
-        let mut ast_nodes: Vec<QueryAstNode> = Vec::new();
-
-        // Start with the root node
-        ast_nodes.push(ast_node);
-        let root_idx = 0;
-
-        // Push the initial element with the root as parent
-        stack.push((Element::NonTerminal(symbol.to_string()), root_idx));
-
-        while let Some((element, parent_idx)) = stack.pop() {
-            match element {
-                Element::Terminal(text) => {
-                    tokens.push(text.clone());
-
-                    // Create terminal node
-                    let terminal_node = QueryAstNode {
-                        element_type: "terminal".to_string(),
-                        value: text,
-                        children: Vec::new(),
-                    };
+    /// Generate random text straight into `out` without building the AST.
+    ///
+    /// Tokens are written as soon as nothing can revise them, so memory stays
+    /// bounded by the depth of the derivation rather than the output size.
+    pub fn generate_to<W: Write>(&self, start_symbol: &str, out: &mut W) -> Result<()> {
+        self.generate_to_with_rng(start_symbol, &mut rand::thread_rng(), out, false)
+            .map(|_| ())
+    }
 
-                    // Add it as a child to its parent
-                    ast_nodes[parent_idx].children.push(terminal_node);
-                }
-                Element::NonTerminal(name) => {
-                    if let Some(productions) = self.rules.get(&name) {
-                        // Choose a random production
-                        let production_idx = rng.gen_range(0..productions.len());
-                        let production = &productions[production_idx];
-
-                        // Create a non-terminal node
-                        let non_terminal_node = QueryAstNode {
-                            element_type: "non_terminal".to_string(),
-                            value: name.clone(),
-                            children: Vec::new(),
-                        };
-
-                        // Add node to the tree and get its index
-                        ast_nodes.push(non_terminal_node);
-                        let node_idx = ast_nodes.len() - 1;
-
-                        // Split the vector to get non-overlapping mutable references
-                        let (left, right) = ast_nodes.split_at_mut(parent_idx + 1);
-                        left[parent_idx].children.push(right[0].clone());
-
-                        // Push elements and corresponding AST nodes in reverse order
-                        if depth < self.config.max_recursion_depth {
-                            for element in production.elements.iter().rev() {
-                                stack.push((element.clone(), node_idx));
-                                depth += 1;
-                            }
-                        }
-                    } else {
-                        // Handle unknown non-terminals
-                        tokens.push(format!("<{}>", name));
-
-                        // Create an "undefined" node
-                        let undefined_node = QueryAstNode {
-                            element_type: "undefined".to_string(),
-                            value: name,
-                            children: Vec::new(),
-                        };
-
-                        // Add it as a child to its parent
-                        ast_nodes[parent_idx].children.push(undefined_node);
-                    }
-                }
-            }
+    /// Like [`Grammar::generate_to`], drawing every choice from `rng` and
+    /// returning the AST when `build_ast` is set.
+    ///
+    /// The text is the same as [`Grammar::try_generate_with_rng`] produces
+    /// for the same `rng`. Grammars with predicates, or a validator other
+    /// than the no-op one, need the whole text before any of it is final,
+    /// so it is only written once generation completes.
+    pub fn generate_to_with_rng<W: Write, R: Rng + ?Sized>(
+        &self,
+        start_symbol: &str,
+        rng: &mut R,
+        out: &mut W,
+        build_ast: bool,
+    ) -> Result<Option<QueryAstNode>> {
+        if !self.validator.is_noop() {
+            let ast = self.try_generate_with_rng(start_symbol, rng)?;
+            out.write_all(ast.text.as_bytes())?;
+            out.flush()?;
+            return Ok(build_ast.then_some(ast.root));
+        }
+        self.check_predicates()?;
+
+        let guarded = self.rules.values().flatten().any(|p| p.guard.is_some());
+        let mut expander = Expander::new(self, rng);
+        expander.sink = Some(TokenSink::new(
+            out,
+            self.renderer(),
+            self.config.auto_spacing,
+        ));
+        // A rejection can undo anything generated so far
+        expander.pins = usize::from(guarded);
+        // Without predicates or attributes nothing looks below a child
+        // once it is complete
+        expander.prune = !build_ast && !guarded && self.attributes.is_empty();
+
+        let mut root = expander
+            .expand_symbol(start_symbol)
+            .map_err(|Rejected| expander.failure(start_symbol))?;
+
+        if expander.expanded >= self.config.max_recursion_depth {
+            expander
+                .tokens
+                .push(Piece::Token("<recursion_limit_exceeded>".to_string()));
+            root.children
+                .push(QueryAstNode::new("error", "recursion_limit_exceeded"));
         }
 
-        // Handle recursion limit if reached
-        if depth >= self.config.max_recursion_depth {
-            tokens.push(format!("<recursion_limit_exceeded>"));
-
-            // Add a recursion limit node to the root
-            let limit_node = QueryAstNode {
-                element_type: "error".to_string(),
-                value: "recursion_limit_exceeded".to_string(),
-                children: Vec::new(),
-            };
-            ast_nodes[root_idx].children.push(limit_node);
+        expander.pins = 0;
+        expander.flush();
+        if let Some(sink) = expander.sink.take() {
+            sink.finish()?;
         }
 
-        // Construct the final string
-        let mut result = String::new();
-        let mut in_quotes = false;
+        Ok(build_ast.then_some(root))
+    }
 
-        for (i, token) in tokens.iter().enumerate() {
-            if i > 0 {
-                let prev = &tokens[i - 1];
-                if !in_quotes
-                    && !prev.ends_with('(')
-                    && !token.starts_with(')')
-                    && !token.starts_with(',')
-                    && !prev.ends_with(',')
-                {
-                    result.push(' ');
+    /// Generate `count` queries on `jobs` threads (0 for one per CPU).
+    ///
+    /// Query `i` is generated from its own seed derived from `seed` and `i`,
+    /// so the output is the same whatever the number of threads.
+    pub fn generate_batch(
+        &self,
+        start_symbol: &str,
+        count: usize,
+        seed: u64,
+        jobs: usize,
+    ) -> Result<Vec<QueryAst>> {
+        batch::generate_batch(self, start_symbol, count, seed, jobs)
+    }
+
+    /// Generate the queries of [`Grammar::generate_batch`], passing each to
+    /// `each` with its index, in index order, instead of collecting them.
+    ///
+    /// Only a bounded chunk of queries per thread is held at once, so this
+    /// suits batches too large to keep in memory. An error from `each`
+    /// stops generation and is returned.
+    pub fn generate_batch_with<F>(
+        &self,
+        start_symbol: &str,
+        count: usize,
+        seed: u64,
+        jobs: usize,
+        each: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize, QueryAst) -> Result<()>,
+    {
+        batch::for_each_in_batch(self, start_symbol, count, seed, jobs, each)
+    }
+
+    /// Generate queries that have not been produced before, as an iterator
+    /// ending once the grammar's language looks exhausted
+    pub fn generate_unique(&self, start_symbol: &str, config: DedupConfig) -> UniqueGenerator<'_> {
+        UniqueGenerator::new(self, start_symbol, config)
+    }
+
+    /// Ensure every guard refers to a registered predicate
+    pub(crate) fn check_predicates(&self) -> Result<()> {
+        for (non_terminal, productions) in &self.rules {
+            for guard in productions.iter().filter_map(|p| p.guard.as_ref()) {
+                if !self.predicates.contains_key(guard) {
+                    return Err(GrammarError::InvalidGrammar(format!(
+                        "Unknown predicate '{}' guarding <{}>",
+                        guard, non_terminal
+                    )));
                 }
             }
-            if token == "\"" || token == "'" {
-                in_quotes = !in_quotes;
-            }
-            result.push_str(token);
-            if token == "," && !in_quotes {
-                result.push(' ');
-            }
         }
-
-        // Return the generated string and the root AST node
-        (result.trim().to_string(), ast_nodes[root_idx].clone())
+        Ok(())
     }
 
     /// Check if the grammar contains a specific non-terminal
@@ -770,46 +1606,649 @@ impl Grammar {
         self.config.max_recursion_depth = depth;
     }
 
-    /// Print the grammar as a DOT graph
+    /// Print the grammar as a DOT graph; see [`Grammar::write_dot`] to
+    /// write it elsewhere
     pub fn print_graph(&self) {
-        println!("digraph Grammar {{");
-        println!("  rankdir=LR;");
-        println!("  node [shape=box];");
+        print!("{}", self.to_dot());
+    }
+}
+
+/// Raised when predicates reject an expansion and its choice point has no
+/// retries left, so the enclosing choice point must try again
+struct Rejected;
+
+/// A pending step of an expansion. Frames live on the heap, so the depth of
+/// a derivation is bounded by the budget rather than the thread's stack
+enum Frame<'a> {
+    /// A choice point for a non-terminal, retried when its production is
+    /// rejected
+    Symbol {
+        name: &'a str,
+        productions: &'a [Production],
+        inherited: QueryAstNode,
+        attempts: usize,
+        /// Output position, expansion count and symbol table checkpoint to
+        /// roll back to before choosing again
+        checkpoint: (usize, usize, usize),
+    },
+    /// A production whose elements are expanded into `node` one by one; its
+    /// path entry and scope are removed when the frame is
+    Production {
+        name: &'a str,
+        index: usize,
+        production: &'a Production,
+        node: QueryAstNode,
+        next: usize,
+        start: usize,
+        pinned: bool,
+    },
+    /// A bound element, whose text is bound once it is expanded
+    Bind { binding: &'a Binding, start: usize },
+    /// A classified element, whose terminals from child `first` on get `class`
+    Classify { class: TokenClass, first: usize },
+}
+
+/// What the expansion does next
+enum Step {
+    /// Expand the next element of the innermost production
+    Next,
+    /// Attach a completed non-terminal to the innermost production
+    Node(QueryAstNode),
+    /// Undo frames up to the innermost choice point with retries left
+    Rejected,
+}
 
-        // Print all non-terminals as nodes
-        for non_terminal in self.rules.keys() {
-            println!("  \"{}\" [label=\"{}\"];", non_terminal, non_terminal);
+/// State for a single random expansion of a grammar
+struct Expander<'a, R: Rng + ?Sized> {
+    grammar: &'a Grammar,
+    rng: &'a mut R,
+    /// Generated terminals and layout not yet written to `sink`, in output order
+    tokens: Vec<Piece>,
+    /// Number of terminals already written to `sink`
+    flushed: usize,
+    /// Open expansions that may still read or undo their terminals; tokens
+    /// are only written to `sink` while there are none
+    pins: usize,
+    /// Where tokens are streamed, when generating into a writer
+    sink: Option<TokenSink<'a>>,
+    /// Drop the subtrees of completed children, keeping only the nodes
+    prune: bool,
+    /// Number of production elements expanded so far, bounded by
+    /// `GrammarConfig::max_recursion_depth`
+    expanded: usize,
+    /// Variables bound with `as $name`
+    symbols: SymbolTable,
+    /// Non-terminals currently being expanded, outermost first
+    path: Vec<&'a str>,
+    /// Rejected attempts so far, bounded by `GrammarConfig::max_backtracks`
+    backtracks: usize,
+    /// The most recent rejection, as (predicate, symbol)
+    last_rejection: Option<(&'a str, &'a str)>,
+}
+
+impl<'a, R: Rng + ?Sized> Expander<'a, R> {
+    fn new(grammar: &'a Grammar, rng: &'a mut R) -> Self {
+        Expander {
+            grammar,
+            rng,
+            tokens: Vec::new(),
+            flushed: 0,
+            pins: 0,
+            sink: None,
+            prune: false,
+            expanded: 0,
+            symbols: SymbolTable::new(),
+            path: Vec::new(),
+            backtracks: 0,
+            last_rejection: None,
         }
+    }
 
-        // Print all productions as edges
-        for (non_terminal, productions) in &self.rules {
-            for production in productions {
-                let mut label = String::new();
-                for element in &production.elements {
-                    match element {
-                        Element::Terminal(text) => label.push_str(&format!("{} ", text)),
-                        Element::NonTerminal(name) => {
-                            println!(
-                                "  \"{}\" -> \"{}\" [label=\"{}\"];",
-                                non_terminal,
-                                name,
-                                label.trim()
-                            );
-                            label.clear();
-                        }
+    /// Expand a non-terminal symbol into its AST node.
+    ///
+    /// Each expansion is a choice point: when a predicate rejects the chosen
+    /// production, or a descendant that ran out of retries, everything it
+    /// generated is undone and a production is sampled again. Pending work is
+    /// kept on an explicit stack of frames, so deep derivations don't
+    /// overflow the call stack.
+    fn expand_symbol(&mut self, name: &'a str) -> std::result::Result<QueryAstNode, Rejected> {
+        let mut frames = Vec::new();
+        let mut step = self.enter_symbol(name, &mut frames);
+        loop {
+            step = match step {
+                Step::Next => self.advance(&mut frames),
+                Step::Node(mut node) => {
+                    let Some(parent) = innermost_mut(&mut frames) else {
+                        return Ok(node);
+                    };
+                    if self.prune {
+                        node.children = Vec::new();
+                    }
+                    parent.children.push(node);
+                    self.finish_wrappers(&mut frames);
+                    Step::Next
+                }
+                Step::Rejected => self.reject(&mut frames).ok_or(Rejected)?,
+            };
+        }
+    }
+
+    /// Start expanding `name` as a child of the innermost production
+    fn enter_symbol(&mut self, name: &'a str, frames: &mut Vec<Frame<'a>>) -> Step {
+        let Some(productions) = self.grammar.rules.get(name) else {
+            // Handle unknown non-terminals
+            self.push_piece(Piece::Token(format!("<{}>", name)));
+            return Step::Node(QueryAstNode::new("undefined", name));
+        };
+
+        let inherited = self.inherit(name, innermost(frames));
+
+        // Once the budget is spent, non-terminals are left unexpanded
+        if self.expanded >= self.grammar.config.max_recursion_depth {
+            return Step::Node(inherited);
+        }
+
+        frames.push(Frame::Symbol {
+            name,
+            productions,
+            inherited,
+            attempts: 0,
+            checkpoint: (0, 0, 0),
+        });
+        self.start_production(frames)
+    }
+
+    /// Choose a production for the symbol on top of `frames` and start
+    /// expanding it
+    fn start_production(&mut self, frames: &mut Vec<Frame<'a>>) -> Step {
+        let Some(Frame::Symbol {
+            name, productions, ..
+        }) = frames.last()
+        else {
+            unreachable!("a production starts from its symbol's frame");
+        };
+        let (name, productions) = (*name, *productions);
+        let index = self.choose(name, productions, innermost(frames));
+
+        let checkpoint = (self.position(), self.expanded, self.symbols.checkpoint());
+        let Some(Frame::Symbol {
+            inherited,
+            checkpoint: saved,
+            ..
+        }) = frames.last_mut()
+        else {
+            unreachable!();
+        };
+        *saved = checkpoint;
+        let mut node = inherited.clone();
+
+        let production = &productions[index];
+        node.production = Some(index);
+        self.expanded += production.elements.len();
+
+        // Synthesized attributes read the production's text
+        let pinned = self
+            .grammar
+            .attributes(name)
+            .iter()
+            .any(|rule| rule.kind == AttributeKind::Synthesized);
+        if pinned {
+            self.pins += 1;
+        }
+
+        self.path.push(name);
+        self.symbols.push_scope(name);
+        frames.push(Frame::Production {
+            name,
+            index,
+            production,
+            node,
+            next: 0,
+            start: self.position(),
+            pinned,
+        });
+        Step::Next
+    }
+
+    /// Expand the next element of the production on top of `frames`, or
+    /// finish the production once all of them are
+    fn advance(&mut self, frames: &mut Vec<Frame<'a>>) -> Step {
+        let Some(Frame::Production {
+            production, next, ..
+        }) = frames.last_mut()
+        else {
+            unreachable!("elements are expanded into their production");
+        };
+        if let Some(element) = production.elements.get(*next) {
+            *next += 1;
+            return self.expand_element(element, frames);
+        }
+
+        let Some(Frame::Production {
+            name,
+            index,
+            production,
+            mut node,
+            start,
+            pinned,
+            ..
+        }) = frames.pop()
+        else {
+            unreachable!();
+        };
+        self.path.pop();
+
+        self.synthesize(&mut node, start);
+        let accepted = self.check_guard(name, index, production, &node, start);
+        self.symbols.pop_scope();
+        if pinned {
+            self.unpin();
+        }
+        if !accepted {
+            return Step::Rejected;
+        }
+
+        frames.pop();
+        Step::Node(node)
+    }
+
+    /// Unwind `frames` to the innermost choice point with retries left and
+    /// choose again there, or return `None` when there is none
+    fn reject(&mut self, frames: &mut Vec<Frame<'a>>) -> Option<Step> {
+        while let Some(frame) = frames.pop() {
+            match frame {
+                Frame::Bind { .. } => self.unpin(),
+                Frame::Classify { .. } => {}
+                Frame::Production { pinned, .. } => {
+                    self.path.pop();
+                    self.symbols.pop_scope();
+                    if pinned {
+                        self.unpin();
                     }
                 }
-                if !label.is_empty() {
-                    println!(
-                        "  \"{}\" -> \"{}\" [label=\"{}\"];",
-                        non_terminal,
-                        "END",
-                        label.trim()
-                    );
+                Frame::Symbol {
+                    name,
+                    productions,
+                    inherited,
+                    attempts,
+                    checkpoint: (tokens, expanded, symbols),
+                } => {
+                    self.tokens.truncate(tokens - self.flushed);
+                    self.expanded = expanded;
+                    self.symbols.rollback(symbols);
+
+                    self.backtracks += 1;
+                    let attempts = attempts + 1;
+                    if attempts >= self.grammar.config.max_retries
+                        || self.backtracks >= self.grammar.config.max_backtracks
+                    {
+                        continue;
+                    }
+
+                    frames.push(Frame::Symbol {
+                        name,
+                        productions,
+                        inherited,
+                        attempts,
+                        checkpoint: (tokens, expanded, symbols),
+                    });
+                    return Some(self.start_production(frames));
                 }
             }
         }
+        None
+    }
 
-        println!("}}");
+    /// Choose a production at random, following the weights for the
+    /// parent context when there are any
+    fn choose(
+        &mut self,
+        name: &str,
+        productions: &[Production],
+        parent: Option<&QueryAstNode>,
+    ) -> usize {
+        // Each element of a production adds exactly one child, so the
+        // number of children so far is this symbol's position
+        let context = parent.and_then(|parent| {
+            let production = parent.production?;
+            self.grammar.weights_within(
+                name,
+                &parent.value,
+                production,
+                parent.children.len(),
+            )
+        });
+        match context {
+            Some(weights) => sample(self.rng, weights.iter().copied()),
+            None => sample(self.rng, productions.iter().map(|p| p.weight)),
+        }
+    }
+
+    /// An unexpanded node for `name` carrying its inherited attributes
+    fn inherit(&self, name: &str, parent: Option<&QueryAstNode>) -> QueryAstNode {
+        let mut node = QueryAstNode::new("non_terminal", name);
+
+        for rule in self.grammar.attributes(name) {
+            if rule.kind == AttributeKind::Inherited {
+                let context = AttributeContext {
+                    grammar: self.grammar,
+                    node: &node,
+                    parent,
+                    tokens: &[],
+                };
+                let value = rule.evaluate(&context);
+                node.attributes.insert(rule.name.clone(), value);
+            }
+        }
+
+        node
+    }
+
+    /// Compute the synthesized attributes of an expanded node
+    fn synthesize(&self, node: &mut QueryAstNode, start: usize) {
+        for rule in self.grammar.attributes(&node.value) {
+            if rule.kind == AttributeKind::Synthesized {
+                let context = AttributeContext {
+                    grammar: self.grammar,
+                    node,
+                    parent: None,
+                    tokens: self.since(start),
+                };
+                let value = rule.evaluate(&context);
+                node.attributes.insert(rule.name.clone(), value);
+            }
+        }
+    }
+
+    /// Evaluate the production's predicate, if any, on the partial derivation
+    fn check_guard(
+        &mut self,
+        name: &'a str,
+        index: usize,
+        production: &'a Production,
+        node: &QueryAstNode,
+        start: usize,
+    ) -> bool {
+        let Some(guard) = &production.guard else {
+            return true;
+        };
+        let Some(predicate) = self.grammar.predicates.get(guard) else {
+            return false;
+        };
+
+        let context = PredicateContext {
+            grammar: self.grammar,
+            symbol: name,
+            production: index,
+            node,
+            tokens: &self.tokens,
+            start: start - self.flushed,
+            path: &self.path,
+            symbols: &self.symbols,
+        };
+
+        let accepted = predicate.check(&context);
+        if !accepted {
+            self.last_rejection = Some((guard, name));
+        }
+        accepted
+    }
+
+    /// Describe why no valid derivation of `start_symbol` was found
+    fn failure(&self, start_symbol: &str) -> GrammarError {
+        let reason = match self.last_rejection {
+            Some((predicate, symbol)) => {
+                format!("last rejected by predicate '{}' on <{}>", predicate, symbol)
+            }
+            None => "no predicate accepted".to_string(),
+        };
+        GrammarError::NoValidDerivation(format!(
+            "<{}> after {} backtracks, {}",
+            start_symbol, self.backtracks, reason
+        ))
+    }
+
+    /// Expand one production element, appending its node(s) to the
+    /// innermost production. Non-terminals only push their frame, and are
+    /// attached once their expansion completes
+    fn expand_element(&mut self, element: &'a Element, frames: &mut Vec<Frame<'a>>) -> Step {
+        let Some(parent) = innermost_mut(frames) else {
+            unreachable!("elements are expanded into their production");
+        };
+        match element {
+            Element::Terminal(text) => self.emit(text.clone(), parent),
+            Element::Regex(regex) => {
+                let text = regex.generate(self.rng, self.grammar.config.regex_max_repeat);
+                self.emit(text, parent);
+            }
+            Element::NonTerminal(name) => return self.enter_symbol(name, frames),
+            Element::Bind(binding) => {
+                let start = self.position();
+                self.pins += 1;
+                frames.push(Frame::Bind { binding, start });
+                return self.expand_element(&binding.element, frames);
+            }
+            Element::Variable(name) => {
+                let value = self.symbols.lookup(name).map(str::to_string);
+                self.emit_variable(name, value, parent);
+            }
+            Element::Classified(element, class) => {
+                let first = parent.children.len();
+                frames.push(Frame::Classify {
+                    class: *class,
+                    first,
+                });
+                return self.expand_element(element, frames);
+            }
+            Element::Layout(layout) => {
+                let value = layout.to_string();
+                parent.children.push(QueryAstNode::new("layout", &value));
+                self.push_piece(Piece::Layout(*layout));
+            }
+            Element::Pick(name) => {
+                let values = self.symbols.visible(name);
+                let value = (!values.is_empty())
+                    .then(|| values[self.rng.gen_range(0..values.len())].to_string());
+                self.emit_variable(name, value, parent);
+            }
+        }
+        self.finish_wrappers(frames);
+        Step::Next
+    }
+
+    /// Finish the bound and classified elements whose inner element just
+    /// completed
+    fn finish_wrappers(&mut self, frames: &mut Vec<Frame<'a>>) {
+        loop {
+            match frames.last() {
+                Some(Frame::Bind { binding, start }) => {
+                    let (binding, start) = (*binding, *start);
+                    frames.pop();
+                    let value = self.grammar.render_pieces(self.since(start));
+                    self.unpin();
+                    self.symbols
+                        .bind(&binding.variable, value, binding.scope.as_deref());
+                }
+                Some(Frame::Classify { class, first }) => {
+                    let (class, first) = (*class, *first);
+                    frames.pop();
+                    let Some(parent) = innermost_mut(frames) else {
+                        unreachable!();
+                    };
+                    for child in &mut parent.children[first..] {
+                        if child.element_type == "terminal" {
+                            child.class = Some(class);
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn emit(&mut self, text: String, parent: &mut QueryAstNode) {
+        parent.children.push(QueryAstNode::new("terminal", &text));
+        self.push_piece(Piece::Token(text));
+    }
+
+    /// Emit a variable's value, or an undefined `<$name>` marker when unbound
+    fn emit_variable(&mut self, name: &str, value: Option<String>, parent: &mut QueryAstNode) {
+        match value {
+            Some(value) => self.emit(value, parent),
+            None => {
+                let name = format!("${}", name);
+                self.push_piece(Piece::Token(format!("<{}>", name)));
+                parent.children.push(QueryAstNode::new("undefined", &name));
+            }
+        }
+    }
+
+    /// Index of the next piece in the whole output
+    fn position(&self) -> usize {
+        self.flushed + self.tokens.len()
+    }
+
+    /// Pieces generated from output index `start` on
+    fn since(&self, start: usize) -> &[Piece] {
+        &self.tokens[start - self.flushed..]
+    }
+
+    fn push_piece(&mut self, piece: Piece) {
+        self.tokens.push(piece);
+        if self.pins == 0 {
+            self.flush();
+        }
+    }
+
+    fn unpin(&mut self) {
+        self.pins -= 1;
+        if self.pins == 0 {
+            self.flush();
+        }
+    }
+
+    /// Write buffered terminals to the sink, if streaming
+    fn flush(&mut self) {
+        if let Some(sink) = &mut self.sink {
+            self.flushed += self.tokens.len();
+            for piece in self.tokens.drain(..) {
+                sink.push(&piece);
+            }
+        }
+    }
+}
+
+/// The elements of a production as written in the text format, `[a, <b>]`
+pub(crate) fn elements_text(elements: &[Element]) -> String {
+    let elements: Vec<String> = elements.iter().map(element_text).collect();
+    format!("[{}]", elements.join(", "))
+}
+
+/// An element as written in the text format
+fn element_text(element: &Element) -> String {
+    match element {
+        Element::Terminal(text) => terminal_text(text),
+        Element::NonTerminal(name) => format!("<{}>", name),
+        Element::Regex(regex) => format!("/{}/", regex.pattern()),
+        Element::Bind(binding) => {
+            let mut text = format!(
+                "{} as ${}",
+                element_text(&binding.element),
+                binding.variable
+            );
+            if let Some(scope) = &binding.scope {
+                text.push_str(&format!(" in <{}>", scope));
+            }
+            text
+        }
+        Element::Variable(name) => format!("${}", name),
+        Element::Pick(name) => format!("@pick(${})", name),
+        Element::Layout(layout) => layout.to_string(),
+        Element::Classified(element, class) => format!("{} :{}", element_text(element), class),
+    }
+}
+
+/// A terminal as written in the text format, quoted unless it reads back as
+/// the same plain terminal. The binding keywords `as` and `in` are always
+/// quoted, and quotes are escaped where the text contains both kinds.
+fn terminal_text(text: &str) -> String {
+    if text == "'" {
+        return "\\'".to_string();
+    }
+    let plain = !text.is_empty()
+        && !matches!(text, "as" | "in")
+        && !text.starts_with(['<', '[', '\\', '\'', '"', '#', ':', '/', '$', '@', '~'])
+        && !text.contains(|c: char| c.is_whitespace() || matches!(c, ',' | ']' | '>'));
+    if plain {
+        return text.to_string();
+    }
+
+    let quote = if text.contains('\'') && !text.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut quoted = String::from(quote);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        // A backslash only needs escaping where it would escape what follows
+        let escaped = match c {
+            '\\' => chars
+                .peek()
+                .is_none_or(|&next| next == '\\' || next == quote),
+            c => c == quote,
+        };
+        if escaped {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push(quote);
+    quoted
+}
+
+/// Tag a terminal element with its token class
+/// The node of the innermost production being expanded
+fn innermost<'f>(frames: &'f [Frame<'_>]) -> Option<&'f QueryAstNode> {
+    frames.iter().rev().find_map(|frame| match frame {
+        Frame::Production { node, .. } => Some(node),
+        _ => None,
+    })
+}
+
+fn innermost_mut<'f>(frames: &'f mut [Frame<'_>]) -> Option<&'f mut QueryAstNode> {
+    frames.iter_mut().rev().find_map(|frame| match frame {
+        Frame::Production { node, .. } => Some(node),
+        _ => None,
+    })
+}
+
+fn classify(element: Element, class: TokenClass) -> Result<Element> {
+    match element {
+        Element::NonTerminal(name) => Err(GrammarError::Parse(format!(
+            "Token class :{} can only follow a terminal, found <{}>",
+            class, name
+        ))),
+        element => Ok(Element::Classified(Box::new(element), class)),
+    }
+}
+
+/// Pick an index with probability proportional to its weight, uniformly
+/// when all weights are zero
+fn sample<R: Rng + ?Sized>(rng: &mut R, weights: impl Iterator<Item = f64> + Clone) -> usize {
+    let total: f64 = weights.clone().sum();
+    if total <= 0.0 {
+        return rng.gen_range(0..weights.count());
+    }
+
+    let mut target = rng.gen_range(0.0..total);
+    let mut last = 0;
+    for (index, weight) in weights.enumerate() {
+        if target < weight {
+            return index;
+        }
+        target -= weight;
+        last = index;
     }
+    last
 }
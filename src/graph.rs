@@ -0,0 +1,343 @@
+use std::io::{self, Write};
+
+use crate::grammar::{Element, Grammar, QueryAst, QueryAstNode};
+
+/// How a derivation tree node is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    NonTerminal,
+    Terminal,
+    Layout,
+    Error,
+}
+
+impl Style {
+    fn of(node: &QueryAstNode) -> Self {
+        match node.element_type.as_str() {
+            "terminal" => Style::Terminal,
+            "layout" => Style::Layout,
+            "undefined" | "error" => Style::Error,
+            _ => Style::NonTerminal,
+        }
+    }
+
+    /// Graphviz node attributes
+    fn dot(&self) -> &'static str {
+        match self {
+            Style::NonTerminal => "shape=box, style=rounded",
+            Style::Terminal => "shape=ellipse, style=filled, fillcolor=\"#e8f0fe\"",
+            Style::Layout => "shape=plaintext, fontcolor=gray",
+            Style::Error => "shape=octagon, style=filled, fillcolor=\"#f8d7da\", color=red",
+        }
+    }
+
+    /// Mermaid class name
+    fn class(&self) -> &'static str {
+        match self {
+            Style::NonTerminal => "nonTerminal",
+            Style::Terminal => "terminal",
+            Style::Layout => "layout",
+            Style::Error => "error",
+        }
+    }
+
+    /// Mermaid node shape around a quoted label
+    fn brackets(&self) -> (&'static str, &'static str) {
+        match self {
+            Style::NonTerminal => ("[", "]"),
+            Style::Terminal => ("([", "])"),
+            Style::Layout => ("[/", "/]"),
+            Style::Error => ("{{", "}}"),
+        }
+    }
+}
+
+/// Text shown for a node: the symbol of a non-terminal, the text of a
+/// terminal and the placeholder text of an error
+fn label(node: &QueryAstNode) -> String {
+    match Style::of(node) {
+        Style::NonTerminal => match node.production {
+            Some(production) => format!("<{}> #{}", node.value, production),
+            None => format!("<{}>", node.value),
+        },
+        Style::Error => format!("<{}>", node.value),
+        Style::Terminal | Style::Layout => node.value.clone(),
+    }
+}
+
+/// Escape `text` for a double-quoted DOT string
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape `text` for a double-quoted Mermaid label
+fn mermaid_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("#quot;"),
+            '<' => escaped.push_str("#lt;"),
+            '>' => escaped.push_str("#gt;"),
+            '#' => escaped.push_str("#35;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Nodes in preorder with the index of their parent
+fn preorder(root: &QueryAstNode) -> Vec<(Option<usize>, &QueryAstNode)> {
+    fn visit<'a>(
+        node: &'a QueryAstNode,
+        parent: Option<usize>,
+        out: &mut Vec<(Option<usize>, &'a QueryAstNode)>,
+    ) {
+        let index = out.len();
+        out.push((parent, node));
+        for child in &node.children {
+            visit(child, Some(index), out);
+        }
+    }
+
+    let mut out = Vec::new();
+    visit(root, None, &mut out);
+    out
+}
+
+/// Write the derivation tree below `root` as a Graphviz digraph
+pub fn write_dot<W: Write>(root: &QueryAstNode, out: &mut W) -> io::Result<()> {
+    writeln!(out, "digraph AST {{")?;
+    writeln!(out, "  node [fontname=\"monospace\"];")?;
+    for (i, (parent, node)) in preorder(root).into_iter().enumerate() {
+        let style = Style::of(node);
+        writeln!(
+            out,
+            "  n{} [label=\"{}\", {}];",
+            i,
+            dot_escape(&label(node)),
+            style.dot()
+        )?;
+        if let Some(parent) = parent {
+            writeln!(out, "  n{} -> n{};", parent, i)?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// Write the derivation tree below `root` as a Mermaid flowchart
+pub fn write_mermaid<W: Write>(root: &QueryAstNode, out: &mut W) -> io::Result<()> {
+    writeln!(out, "flowchart TD")?;
+    for (i, (parent, node)) in preorder(root).into_iter().enumerate() {
+        let style = Style::of(node);
+        let (open, close) = style.brackets();
+        writeln!(
+            out,
+            "  n{}{}\"{}\"{}:::{}",
+            i,
+            open,
+            mermaid_escape(&label(node)),
+            close,
+            style.class()
+        )?;
+        if let Some(parent) = parent {
+            writeln!(out, "  n{} --> n{}", parent, i)?;
+        }
+    }
+    writeln!(out, "  classDef nonTerminal fill:#fff,stroke:#333")?;
+    writeln!(out, "  classDef terminal fill:#e8f0fe,stroke:#4a6fa5")?;
+    writeln!(out, "  classDef layout fill:#fff,stroke:#bbb,color:#888")?;
+    writeln!(out, "  classDef error fill:#f8d7da,stroke:#c00")
+}
+
+/// Run `write` into a string
+fn to_string<F>(write: F) -> String
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let mut out = Vec::new();
+    write(&mut out).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("graphs are written from strings")
+}
+
+impl QueryAstNode {
+    /// The derivation tree below this node as a Graphviz digraph
+    pub fn to_dot(&self) -> String {
+        to_string(|out| write_dot(self, out))
+    }
+
+    /// The derivation tree below this node as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        to_string(|out| write_mermaid(self, out))
+    }
+}
+
+impl QueryAst {
+    /// The derivation tree as a Graphviz digraph.
+    ///
+    /// Non-terminals are boxes labelled with their symbol and production,
+    /// terminals filled ellipses, layout directives plain grey text, and
+    /// undefined symbols and errors red octagons.
+    pub fn to_dot(&self) -> String {
+        self.root.to_dot()
+    }
+
+    /// The derivation tree as a Mermaid flowchart, styled like [`QueryAst::to_dot`]
+    pub fn to_mermaid(&self) -> String {
+        self.root.to_mermaid()
+    }
+
+    /// Write the derivation tree as a Graphviz digraph
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_dot(&self.root, out)
+    }
+
+    /// Write the derivation tree as a Mermaid flowchart
+    pub fn write_mermaid<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_mermaid(&self.root, out)
+    }
+}
+
+/// Text of an element within an edge label
+fn element_label(element: &Element) -> String {
+    match element {
+        Element::Terminal(text) => text.clone(),
+        Element::NonTerminal(name) => format!("<{}>", name),
+        Element::Regex(regex) => format!("/{}/", regex.pattern()),
+        Element::Bind(binding) => format!(
+            "{} as ${}",
+            element_label(&binding.element),
+            binding.variable
+        ),
+        Element::Variable(name) => format!("${}", name),
+        Element::Pick(name) => format!("@pick(${})", name),
+        Element::Layout(layout) => layout.to_string(),
+        Element::Classified(element, class) => format!("{} :{}", element_label(element), class),
+    }
+}
+
+impl Grammar {
+    /// Write the grammar's rule graph as a Graphviz digraph.
+    ///
+    /// Each non-terminal is a node. An edge leads to every non-terminal a
+    /// production refers to, labelled with the elements before it, and to
+    /// `END` when a production ends with other elements. Symbols are in
+    /// sorted order so the output is stable.
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut symbols: Vec<_> = self.rules().iter().collect();
+        symbols.sort_by(|a, b| a.0.cmp(b.0));
+
+        writeln!(out, "digraph Grammar {{")?;
+        writeln!(out, "  rankdir=LR;")?;
+        writeln!(out, "  node [shape=box];")?;
+
+        for (symbol, _) in &symbols {
+            let symbol = dot_escape(symbol);
+            writeln!(out, "  \"{}\" [label=\"{}\"];", symbol, symbol)?;
+        }
+
+        for (symbol, productions) in &symbols {
+            let symbol = dot_escape(symbol);
+            for production in productions.iter() {
+                let mut label = Vec::new();
+                for element in &production.elements {
+                    let target = match element {
+                        Element::NonTerminal(name) => Some(name),
+                        Element::Bind(binding) => match binding.element.as_ref() {
+                            Element::NonTerminal(name) => Some(name),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(target) = target {
+                        writeln!(
+                            out,
+                            "  \"{}\" -> \"{}\" [label=\"{}\"];",
+                            symbol,
+                            dot_escape(target),
+                            dot_escape(&label.join(" "))
+                        )?;
+                        label.clear();
+                    }
+                    match (target, element) {
+                        (Some(_), Element::Bind(binding)) => {
+                            label.push(format!("as ${}", binding.variable))
+                        }
+                        (Some(_), _) => {}
+                        (None, _) => label.push(element_label(element)),
+                    }
+                }
+                if !label.is_empty() {
+                    writeln!(
+                        out,
+                        "  \"{}\" -> \"END\" [label=\"{}\"];",
+                        symbol,
+                        dot_escape(&label.join(" "))
+                    )?;
+                }
+            }
+        }
+
+        writeln!(out, "}}")
+    }
+
+    /// The grammar's rule graph as a Graphviz digraph; see [`Grammar::write_dot`]
+    pub fn to_dot(&self) -> String {
+        to_string(|out| self.write_dot(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> QueryAstNode {
+        let mut root = QueryAstNode::new("non_terminal", "query");
+        root.production = Some(2);
+        root.children = vec![
+            QueryAstNode::new("terminal", "SELECT"),
+            QueryAstNode::new("layout", "@newline"),
+            QueryAstNode::new("terminal", "\"a\""),
+            QueryAstNode::new("undefined", "table"),
+        ];
+        root
+    }
+
+    #[test]
+    fn test_dot_and_mermaid_output() {
+        let root = tree();
+        assert_eq!(
+            root.to_dot(),
+            "digraph AST {\n  node [fontname=\"monospace\"];\n  \
+             n0 [label=\"<query> #2\", shape=box, style=rounded];\n  \
+             n1 [label=\"SELECT\", shape=ellipse, style=filled, fillcolor=\"#e8f0fe\"];\n  n0 -> n1;\n  \
+             n2 [label=\"@newline\", shape=plaintext, fontcolor=gray];\n  n0 -> n2;\n  \
+             n3 [label=\"\\\"a\\\"\", shape=ellipse, style=filled, fillcolor=\"#e8f0fe\"];\n  n0 -> n3;\n  \
+             n4 [label=\"<table>\", shape=octagon, style=filled, fillcolor=\"#f8d7da\", color=red];\n  n0 -> n4;\n}\n"
+        );
+
+        let mermaid = root.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n  n0[\"#lt;query#gt; #35;2\"]:::nonTerminal\n"));
+        assert!(mermaid.contains("  n1([\"SELECT\"]):::terminal\n  n0 --> n1\n"));
+        assert!(mermaid.contains("  n3([\"#quot;a#quot;\"]):::terminal\n"));
+        assert!(mermaid.contains("  n4{{\"#lt;table#gt;\"}}:::error\n"));
+        assert!(mermaid.contains("classDef error"));
+    }
+
+    #[test]
+    fn test_grammar_dot() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<column>", "FROM", "t"])
+            .unwrap();
+        grammar.add_rule("column", vec!["\"id\""]).unwrap();
+
+        assert_eq!(
+            grammar.to_dot(),
+            "digraph Grammar {\n  rankdir=LR;\n  node [shape=box];\n  \
+             \"column\" [label=\"column\"];\n  \"query\" [label=\"query\"];\n  \
+             \"column\" -> \"END\" [label=\"\\\"id\\\"\"];\n  \
+             \"query\" -> \"column\" [label=\"SELECT\"];\n  \
+             \"query\" -> \"END\" [label=\"FROM t\"];\n}\n"
+        );
+    }
+}
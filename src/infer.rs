@@ -0,0 +1,619 @@
+//! Experimental inference of an approximate grammar from example inputs.
+//!
+//! Examples are split into tokens, repeated pairs of adjacent symbols become
+//! non-terminals until no pair repeats, and symbols seen between the same
+//! neighbours become alternatives of one non-terminal. The result derives
+//! every example and is meant as a starting point to refine by hand.
+
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::grammar::{Element, Grammar, Production};
+use crate::regex_gen::RegexGenerator;
+use crate::utils::{GrammarError, Result};
+
+/// How one kind of token is recognised
+#[derive(Debug, Clone)]
+pub struct TokenRule {
+    /// Regex a token matches
+    pub pattern: String,
+    /// Non-terminal standing for every token the rule matches, defined by
+    /// the pattern itself, or `None` to keep each token as a terminal
+    pub symbol: Option<String>,
+}
+
+impl TokenRule {
+    /// Tokens matching `pattern` stay terminals
+    pub fn terminal(pattern: &str) -> Self {
+        TokenRule {
+            pattern: pattern.to_string(),
+            symbol: None,
+        }
+    }
+
+    /// Tokens matching `pattern` become `<symbol>`, which generates any
+    /// string matching the pattern
+    pub fn symbol(symbol: &str, pattern: &str) -> Self {
+        TokenRule {
+            pattern: pattern.to_string(),
+            symbol: Some(symbol.to_string()),
+        }
+    }
+}
+
+/// Options for [`Grammar::infer`]
+#[derive(Debug, Clone)]
+pub struct InferConfig {
+    /// Token rules tried after skipping whitespace; the longest match wins,
+    /// the earlier rule on a tie. A character no rule matches is a token on
+    /// its own.
+    pub tokens: Vec<TokenRule>,
+    /// Fewest occurrences of a pair of symbols, or of the neighbours around
+    /// different symbols, that makes them a non-terminal; at least 2
+    pub min_occurrences: usize,
+    /// Whether symbols seen between the same neighbours become alternatives
+    /// of one non-terminal; without it the grammar derives only the examples
+    pub merge_alternatives: bool,
+    /// The symbol deriving the examples
+    pub start_symbol: String,
+}
+
+impl Default for InferConfig {
+    fn default() -> Self {
+        InferConfig {
+            tokens: vec![
+                TokenRule::symbol("string", "'[A-Za-z0-9_ ]*'"),
+                TokenRule::symbol("number", r"[0-9]+(\.[0-9]+)?"),
+                TokenRule::terminal("[A-Za-z_][A-Za-z0-9_]*"),
+                TokenRule::terminal("<=|>=|<>|!=|==|\\|\\|"),
+            ],
+            min_occurrences: 2,
+            merge_alternatives: true,
+            start_symbol: "start".to_string(),
+        }
+    }
+}
+
+/// A symbol of an inferred production
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Sym {
+    Terminal(String),
+    /// A token of the token rule with this index
+    Token(usize),
+    /// The inferred rule with this index
+    Rule(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Start,
+    /// A repeated pair of symbols, with a single production
+    Pair,
+    /// Symbols seen between the same neighbours
+    Alternatives,
+}
+
+/// An inferred rule, with each production's number of occurrences
+#[derive(Debug)]
+struct Rule {
+    kind: Kind,
+    productions: Vec<(Vec<Sym>, usize)>,
+    removed: bool,
+}
+
+/// Splits examples into tokens
+struct Tokenizer {
+    rules: Vec<Regex>,
+}
+
+impl Tokenizer {
+    fn new(config: &InferConfig) -> Result<Self> {
+        let rules = config
+            .tokens
+            .iter()
+            .map(|rule| {
+                Regex::new(&format!("^(?:{})", rule.pattern)).map_err(|e| {
+                    GrammarError::Parse(format!("Invalid regex /{}/: {}", rule.pattern, e))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Tokenizer { rules })
+    }
+
+    fn tokenize(&self, config: &InferConfig, text: &str) -> Vec<Sym> {
+        let mut tokens = Vec::new();
+        let mut rest = text.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let longest = self
+                .rules
+                .iter()
+                .enumerate()
+                .filter_map(|(i, regex)| regex.find(rest).map(|m| (i, m.end())))
+                .filter(|&(_, len)| len > 0)
+                .fold(None, |best: Option<(usize, usize)>, (i, len)| match best {
+                    Some((_, best_len)) if best_len >= len => best,
+                    _ => Some((i, len)),
+                });
+            let (token, len) = match longest {
+                Some((i, len)) if config.tokens[i].symbol.is_some() => (Sym::Token(i), len),
+                Some((_, len)) => (Sym::Terminal(rest[..len].to_string()), len),
+                None => (Sym::Terminal(c.to_string()), c.len_utf8()),
+            };
+            tokens.push(token);
+            rest = rest[len..].trim_start();
+        }
+        tokens
+    }
+}
+
+/// The rules inferred so far, the start rule first
+struct Inference {
+    rules: Vec<Rule>,
+    min_occurrences: usize,
+}
+
+impl Inference {
+    /// Indices of the rules whose productions are searched for repeats
+    fn searched(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.rules.len())
+            .filter(|&r| !self.rules[r].removed && self.rules[r].kind != Kind::Pair)
+    }
+
+    /// Turn the most frequent pair of adjacent symbols into a rule
+    fn pair(&mut self) -> bool {
+        let mut counts: HashMap<(&Sym, &Sym), usize> = HashMap::new();
+        let mut order = Vec::new();
+        for r in self.searched() {
+            for (body, _) in &self.rules[r].productions {
+                // Overlapping occurrences, as in `a a a`, count once
+                let mut last: HashMap<(&Sym, &Sym), usize> = HashMap::new();
+                for i in 0..body.len().saturating_sub(1) {
+                    let pair = (&body[i], &body[i + 1]);
+                    if i > 0 && last.get(&pair) == Some(&(i - 1)) {
+                        continue;
+                    }
+                    last.insert(pair, i);
+                    let count = counts.entry(pair).or_insert(0);
+                    if *count == 0 {
+                        order.push(pair);
+                    }
+                    *count += 1;
+                }
+            }
+        }
+
+        let mut best: Option<((&Sym, &Sym), usize)> = None;
+        for pair in order {
+            let count = counts[&pair];
+            if count >= self.min_occurrences && best.is_none_or(|(_, most)| count > most) {
+                best = Some((pair, count));
+            }
+        }
+        let Some(((a, b), _)) = best else {
+            return false;
+        };
+        let pair = [a.clone(), b.clone()];
+
+        self.replace(&pair, self.rules.len());
+        self.rules.push(Rule {
+            kind: Kind::Pair,
+            productions: vec![(pair.to_vec(), 1)],
+            removed: false,
+        });
+        self.merge_duplicates();
+        true
+    }
+
+    /// Make the symbols seen between the neighbours with the most different
+    /// symbols between them alternatives of one rule
+    fn alternatives(&mut self) -> bool {
+        type Seen = (usize, Vec<(Sym, usize)>);
+        let mut contexts: HashMap<(&Sym, &Sym), Seen> = HashMap::new();
+        let mut order = Vec::new();
+        for r in self.searched() {
+            for (body, _) in &self.rules[r].productions {
+                for i in 1..body.len().saturating_sub(1) {
+                    let (total, middles) = contexts
+                        .entry((&body[i - 1], &body[i + 1]))
+                        .or_insert_with(|| {
+                            order.push((&body[i - 1], &body[i + 1]));
+                            (0, Vec::new())
+                        });
+                    *total += 1;
+                    match middles.iter_mut().find(|(sym, _)| *sym == body[i]) {
+                        Some((_, count)) => *count += 1,
+                        None => middles.push((body[i].clone(), 1)),
+                    }
+                }
+            }
+        }
+
+        let mut best: Option<&Seen> = None;
+        for context in order {
+            let seen = &contexts[&context];
+            if seen.0 >= self.min_occurrences
+                && seen.1.len() >= 2
+                && best.is_none_or(|most| seen.1.len() > most.1.len())
+            {
+                best = Some(seen);
+            }
+        }
+        let Some((_, middles)) = best else {
+            return false;
+        };
+        let middles = middles.clone();
+
+        // Alternatives among the symbols are flattened into the new rule
+        let index = self.rules.len();
+        let mut productions = Vec::new();
+        for (sym, count) in &middles {
+            match sym {
+                Sym::Rule(r) if self.rules[*r].kind == Kind::Alternatives => {
+                    productions.append(&mut self.rules[*r].productions);
+                    self.rules[*r].removed = true;
+                }
+                _ => productions.push((vec![sym.clone()], *count)),
+            }
+        }
+        self.rules.push(Rule {
+            kind: Kind::Alternatives,
+            productions,
+            removed: false,
+        });
+        for (sym, _) in middles {
+            self.substitute(&sym, &Sym::Rule(index));
+        }
+        self.merge_duplicates();
+
+        // Substituted symbols may complete occurrences of earlier pairs
+        for r in 0..self.rules.len() {
+            if !self.rules[r].removed && self.rules[r].kind == Kind::Pair {
+                let body = self.rules[r].productions[0].0.clone();
+                self.replace(&body, r);
+            }
+        }
+        self.merge_duplicates();
+        true
+    }
+
+    /// Replace the occurrences of `pair` in the searched productions with
+    /// the pair rule `rule`, from left to right
+    fn replace(&mut self, pair: &[Sym], rule: usize) {
+        let searched: Vec<usize> = self.searched().collect();
+        for r in searched {
+            for (body, _) in &mut self.rules[r].productions {
+                let mut replaced = Vec::with_capacity(body.len());
+                let mut i = 0;
+                while i < body.len() {
+                    if body[i..].starts_with(pair) {
+                        replaced.push(Sym::Rule(rule));
+                        i += 2;
+                    } else {
+                        replaced.push(body[i].clone());
+                        i += 1;
+                    }
+                }
+                *body = replaced;
+            }
+        }
+    }
+
+    /// Replace `from` with `to` everywhere except in the productions of `to`
+    fn substitute(&mut self, from: &Sym, to: &Sym) {
+        for (r, rule) in self.rules.iter_mut().enumerate() {
+            if rule.removed || *to == Sym::Rule(r) {
+                continue;
+            }
+            for (body, _) in &mut rule.productions {
+                for sym in body.iter_mut().filter(|sym| *sym == from) {
+                    *sym = to.clone();
+                }
+            }
+        }
+    }
+
+    /// Merge pair rules with the same production, and identical productions
+    /// of a rule, adding up their occurrences
+    fn merge_duplicates(&mut self) {
+        let mut pairs: HashMap<Vec<Sym>, usize> = HashMap::new();
+        for r in 0..self.rules.len() {
+            if self.rules[r].removed || self.rules[r].kind != Kind::Pair {
+                continue;
+            }
+            let body = self.rules[r].productions[0].0.clone();
+            match pairs.get(&body) {
+                Some(&kept) => {
+                    self.rules[r].removed = true;
+                    self.substitute(&Sym::Rule(r), &Sym::Rule(kept));
+                }
+                None => {
+                    pairs.insert(body, r);
+                }
+            }
+        }
+
+        for rule in &mut self.rules {
+            let mut merged: Vec<(Vec<Sym>, usize)> = Vec::new();
+            for (body, count) in rule.productions.drain(..) {
+                match merged.iter_mut().find(|(kept, _)| *kept == body) {
+                    Some((_, total)) => *total += count,
+                    None => merged.push((body, count)),
+                }
+            }
+            rule.productions = merged;
+        }
+    }
+
+    /// Inline pair rules used once, and alternatives left with a single
+    /// production, into the productions using them
+    fn inline(&mut self) {
+        loop {
+            let mut uses = vec![0; self.rules.len()];
+            for rule in self.rules.iter().filter(|rule| !rule.removed) {
+                for (body, _) in &rule.productions {
+                    for sym in body {
+                        if let Sym::Rule(r) = sym {
+                            uses[*r] += 1;
+                        }
+                    }
+                }
+            }
+
+            let inlined = (1..self.rules.len()).find(|&r| {
+                let rule = &self.rules[r];
+                let single = match rule.kind {
+                    Kind::Pair => uses[r] == 1,
+                    _ => rule.productions.len() == 1,
+                };
+                !rule.removed && single && !rule.productions[0].0.contains(&Sym::Rule(r))
+            });
+            let Some(r) = inlined else {
+                return;
+            };
+
+            self.rules[r].removed = true;
+            let replacement = self.rules[r].productions[0].0.clone();
+            for rule in self.rules.iter_mut().filter(|rule| !rule.removed) {
+                for (body, _) in &mut rule.productions {
+                    *body = body
+                        .drain(..)
+                        .flat_map(|sym| match sym {
+                            Sym::Rule(used) if used == r => replacement.clone(),
+                            sym => vec![sym],
+                        })
+                        .collect();
+                }
+            }
+        }
+    }
+}
+
+impl Grammar {
+    /// Infer an approximate grammar deriving every example.
+    ///
+    /// Examples are tokenised with the configured rules, so the grammar
+    /// renders tokens separated by spaces whatever the examples' spacing.
+    /// Inferred non-terminals are named `seq_N` for repeated sequences and
+    /// `alt_N` for alternatives, numbered in order of first use, and
+    /// productions that occur unevenly often are weighted by their counts.
+    /// This is experimental: the grammar may be much larger or more general
+    /// than one written by hand.
+    pub fn infer<I, S>(examples: I, config: &InferConfig) -> Result<Grammar>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let tokenizer = Tokenizer::new(config)?;
+        let mut start = Rule {
+            kind: Kind::Start,
+            productions: Vec::new(),
+            removed: false,
+        };
+        for example in examples {
+            let tokens = tokenizer.tokenize(config, example.as_ref());
+            if !tokens.is_empty() {
+                start.productions.push((tokens, 1));
+            }
+        }
+        if start.productions.is_empty() {
+            return Err(GrammarError::InvalidGrammar(
+                "No non-empty examples to infer a grammar from".to_string(),
+            ));
+        }
+
+        let mut inference = Inference {
+            rules: vec![start],
+            min_occurrences: config.min_occurrences.max(2),
+        };
+        inference.merge_duplicates();
+        while inference.pair() || (config.merge_alternatives && inference.alternatives()) {}
+        inference.inline();
+
+        emit(&inference.rules, config)
+    }
+}
+
+/// Build the grammar of the rules reachable from the start rule
+fn emit(rules: &[Rule], config: &InferConfig) -> Result<Grammar> {
+    let taken = |name: &str| {
+        config
+            .tokens
+            .iter()
+            .any(|rule| rule.symbol.as_deref() == Some(name))
+    };
+    if taken(&config.start_symbol) {
+        return Err(GrammarError::InvalidGrammar(format!(
+            "The start symbol <{}> is also a token symbol",
+            config.start_symbol
+        )));
+    }
+
+    let taken = |name: &str| name == config.start_symbol || taken(name);
+    let mut names = HashMap::from([(0, config.start_symbol.clone())]);
+
+    // Name rules in order of first use, walking from the start rule
+    let mut order = vec![0];
+    let mut counters = (0, 0);
+    let mut next = 0;
+    while next < order.len() {
+        let r = order[next];
+        next += 1;
+        for sym in rules[r].productions.iter().flat_map(|(body, _)| body) {
+            let Sym::Rule(used) = sym else {
+                continue;
+            };
+            if names.contains_key(used) {
+                continue;
+            }
+            let name = loop {
+                let name = match rules[*used].kind {
+                    Kind::Alternatives => {
+                        counters.1 += 1;
+                        format!("alt_{}", counters.1)
+                    }
+                    _ => {
+                        counters.0 += 1;
+                        format!("seq_{}", counters.0)
+                    }
+                };
+                if !taken(&name) {
+                    break name;
+                }
+            };
+            names.insert(*used, name);
+            order.push(*used);
+        }
+    }
+
+    let mut grammar = Grammar::new();
+    let mut tokens = Vec::new();
+    for r in order {
+        let productions = &rules[r].productions;
+        let uneven = productions
+            .iter()
+            .any(|(_, count)| *count != productions[0].1);
+        for (body, count) in productions {
+            let elements = body
+                .iter()
+                .map(|sym| match sym {
+                    Sym::Terminal(text) => Element::Terminal(text.clone()),
+                    Sym::Token(i) => {
+                        if !tokens.contains(i) {
+                            tokens.push(*i);
+                        }
+                        Element::NonTerminal(config.tokens[*i].symbol.clone().unwrap_or_default())
+                    }
+                    Sym::Rule(used) => Element::NonTerminal(names[used].clone()),
+                })
+                .collect();
+            let weight = if uneven { *count as f64 } else { 1.0 };
+            grammar.add_production(
+                &names[&r],
+                Production {
+                    elements,
+                    guard: None,
+                    weight,
+                },
+            );
+        }
+    }
+
+    // Token rules sharing a symbol become its alternatives
+    tokens.sort();
+    for i in tokens {
+        let rule = &config.tokens[i];
+        let production = Production {
+            elements: vec![Element::Regex(RegexGenerator::new(&rule.pattern)?)],
+            guard: None,
+            weight: 1.0,
+        };
+        grammar.add_production(rule.symbol.as_deref().unwrap_or_default(), production);
+    }
+    Ok(grammar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_tokenize_longest_match() {
+        let config = InferConfig::default();
+        let tokenizer = Tokenizer::new(&config).unwrap();
+        let terminal = |text: &str| Sym::Terminal(text.to_string());
+
+        assert_eq!(
+            tokenizer.tokenize(&config, "  WHERE x>=1.5 AND y = 'a b'"),
+            [
+                terminal("WHERE"),
+                terminal("x"),
+                terminal(">="),
+                Sym::Token(1),
+                terminal("AND"),
+                terminal("y"),
+                terminal("="),
+                Sym::Token(0),
+            ]
+        );
+        assert_eq!(
+            tokenizer.tokenize(&config, "count(*)"),
+            [
+                terminal("count"),
+                terminal("("),
+                terminal("*"),
+                terminal(")")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repeated_sequences_and_alternatives() {
+        let examples = [
+            "SELECT a FROM t WHERE a = 1",
+            "SELECT b FROM t WHERE b > 2",
+            "SELECT a FROM t WHERE b = 3",
+            "SELECT a FROM t",
+        ];
+        let grammar = Grammar::infer(examples, &InferConfig::default()).unwrap();
+
+        assert_eq!(grammar.rules()["start"].len(), 3);
+        assert!(grammar.rules()["seq_1"].len() == 1);
+        assert_eq!(grammar.rules()["number"].len(), 1);
+        assert!(grammar.rules().keys().any(|name| name.starts_with("alt_")));
+        for example in examples {
+            grammar.parse("start", example).unwrap();
+        }
+
+        // The text format reads back as the same grammar
+        let reloaded: Grammar = grammar.to_text().parse().unwrap();
+        assert_eq!(reloaded.to_text(), grammar.to_text());
+    }
+
+    #[test]
+    fn test_without_alternatives_only_examples_derive() {
+        let config = InferConfig {
+            merge_alternatives: false,
+            ..InferConfig::default()
+        };
+        let grammar = Grammar::infer(["f(x, y)", "f(y, x)", "f(x, y)"], &config).unwrap();
+
+        let mut sentences = Vec::new();
+        for seed in 0..20 {
+            let ast = grammar
+                .try_generate_with_rng("start", &mut StdRng::seed_from_u64(seed))
+                .unwrap();
+            sentences.push(ast.text);
+        }
+        assert!(sentences.iter().all(|s| s == "f (x, y)" || s == "f (y, x)"));
+        assert_eq!(grammar.rules()["start"][0].weight, 2.0);
+
+        assert!(Grammar::infer(["  ", ""], &config).is_err());
+        let clash = InferConfig {
+            start_symbol: "number".to_string(),
+            ..InferConfig::default()
+        };
+        assert!(Grammar::infer(["1"], &clash).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::earley::EarleyParser;
+use crate::grammar::{Grammar, QueryAstNode};
+use crate::utils::Result;
+use crate::weights::{ContextWeights, ProductionWeights, WeightContext};
+
+/// Outcome of training on a corpus
+#[derive(Debug, Clone, Default)]
+pub struct TrainingReport {
+    /// Number of entries that parsed and were counted
+    pub parsed: usize,
+    /// Entries that did not parse, as (index in the corpus, error message)
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Learns production probabilities from a corpus of real queries.
+///
+/// Each entry is parsed against the grammar and every production used in
+/// its derivation is counted. Optionally counts are also kept per parent
+/// production, so a symbol can be weighted differently depending on where
+/// it appears.
+pub struct Trainer<'g> {
+    grammar: &'g Grammar,
+    parser: EarleyParser<'g>,
+    start_symbol: String,
+    condition_on_parent: bool,
+    smoothing: f64,
+    counts: HashMap<String, Vec<f64>>,
+    context_counts: HashMap<(WeightContext, String), Vec<f64>>,
+}
+
+impl<'g> Trainer<'g> {
+    /// Create a trainer parsing corpus entries as `start_symbol`
+    pub fn new(grammar: &'g Grammar, start_symbol: &str) -> Result<Self> {
+        Ok(Trainer {
+            grammar,
+            parser: EarleyParser::new(grammar)?,
+            start_symbol: start_symbol.to_string(),
+            condition_on_parent: false,
+            smoothing: 1.0,
+            counts: HashMap::new(),
+            context_counts: HashMap::new(),
+        })
+    }
+
+    /// Also learn weights conditioned on the parent production
+    pub fn condition_on_parent(mut self, enabled: bool) -> Self {
+        self.condition_on_parent = enabled;
+        self
+    }
+
+    /// Pseudo-count added to every production of an observed symbol, so
+    /// productions missing from the corpus keep a small probability
+    pub fn smoothing(mut self, alpha: f64) -> Self {
+        self.smoothing = alpha;
+        self
+    }
+
+    /// Parse one corpus entry and count its productions
+    pub fn train(&mut self, text: &str) -> Result<()> {
+        let tree = self.parser.parse(&self.start_symbol, text)?;
+        self.count(&tree, None);
+        Ok(())
+    }
+
+    /// Train on every entry of a corpus, skipping entries that do not parse
+    pub fn train_corpus<I, S>(&mut self, corpus: I) -> TrainingReport
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut report = TrainingReport::default();
+        for (index, text) in corpus.into_iter().enumerate() {
+            match self.train(text.as_ref()) {
+                Ok(()) => report.parsed += 1,
+                Err(e) => report.failed.push((index, e.to_string())),
+            }
+        }
+        report
+    }
+
+    fn count(&mut self, node: &QueryAstNode, parent: Option<&QueryAstNode>) {
+        let Some(production) = node.production else {
+            return;
+        };
+        let size = self.grammar.rules()[&node.value].len();
+
+        self.counts
+            .entry(node.value.clone())
+            .or_insert_with(|| vec![0.0; size])[production] += 1.0;
+
+        if self.condition_on_parent
+            && let Some(parent) = parent
+            && let Some(parent_production) = parent.production
+        {
+            let context = WeightContext::production(&parent.value, parent_production);
+            self.context_counts
+                .entry((context, node.value.clone()))
+                .or_insert_with(|| vec![0.0; size])[production] += 1.0;
+        }
+
+        for child in &node.children {
+            self.count(child, Some(node));
+        }
+    }
+
+    /// Learned probabilities for every symbol seen in the corpus
+    pub fn weights(&self) -> ProductionWeights {
+        let symbols = self
+            .counts
+            .iter()
+            .map(|(symbol, counts)| (symbol.clone(), self.normalize(counts)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut contexts = self
+            .context_counts
+            .iter()
+            .map(|((context, symbol), counts)| ContextWeights {
+                context: context.clone(),
+                symbol: symbol.clone(),
+                weights: self.normalize(counts),
+            })
+            .collect::<Vec<_>>();
+        contexts.sort_by(|a, b| (&a.context, &a.symbol).cmp(&(&b.context, &b.symbol)));
+
+        ProductionWeights { symbols, contexts }
+    }
+
+    fn normalize(&self, counts: &[f64]) -> Vec<f64> {
+        let total: f64 = counts.iter().sum::<f64>() + self.smoothing * counts.len() as f64;
+        counts
+            .iter()
+            .map(|count| (count + self.smoothing) / total)
+            .collect()
+    }
+}
@@ -7,28 +7,73 @@
 //! # Example
 //!
 //! ```rust
-//! use grammar_gen::{Grammar, GrammarBuilder};
+//! use grammar_gen::Grammar;
 //!
 //! // Create a simple grammar programmatically
-//! let mut grammar = GrammarBuilder::new("greeting")
-//!     .add_rule("greeting", &["Hello", "<subject>"])
-//!     .add_rule("subject", &["world"])
-//!     .add_rule("subject", &["Rust", "programmers"])
-//!     .build();
+//! let mut grammar = Grammar::new();
+//! grammar.add_rule("greeting", vec!["Hello", "<subject>"]).unwrap();
+//! grammar.add_rule("subject", vec!["world"]).unwrap();
+//! grammar.add_rule("subject", vec!["Rust", "programmers"]).unwrap();
 //!
 //! // Generate a random greeting
-//! let text = grammar.generate();
+//! let text = grammar.generate("greeting").text;
 //! assert!(text == "Hello world" || text == "Hello Rust programmers");
 //! ```
 
+pub mod ambiguity;
+pub mod attribute;
+pub mod batch;
+pub mod codec;
 pub mod common;
+pub mod diff;
+pub mod dedup;
+pub mod doc;
+pub mod earley;
 pub mod grammar;
+pub mod graph;
+pub mod infer;
+pub mod learn;
+pub mod normalize;
+pub mod predicate;
+pub mod probability;
+pub mod pretty;
+pub mod regex_gen;
+pub mod render;
 pub mod schema;
+pub mod select;
+pub mod session;
+pub mod shortest;
+pub mod slice;
+mod stream;
+pub mod symbol_table;
+pub mod token;
 pub mod utils;
+pub mod visit;
+pub mod weights;
 
+pub use ambiguity::{Ambiguity, AmbiguityConfig};
+pub use attribute::{AttrValue, AttributeContext};
+pub use codec::AST_SCHEMA_VERSION;
+pub use dedup::{DedupConfig, DedupKey, SeenFilter};
+pub use diff::{GrammarDiff, SymbolDiff, WeightChange};
+pub use doc::DocConfig;
 pub use grammar::{Grammar, GrammarConfig};
+pub use infer::{InferConfig, TokenRule};
+pub use learn::{Trainer, TrainingReport};
+pub use normalize::Transformed;
+pub use predicate::PredicateContext;
+pub use pretty::{FormatConfig, SqlFormatter};
+pub use regex_gen::RegexGenerator;
+pub use render::{Layout, Renderer, RuleRenderer};
 pub use schema::{Column, Schema, SqlGenerator, SqlGrammarExtension, SqlType, Table};
+pub use select::Selector;
+pub use session::{GenerationSession, SessionConfig};
+pub use shortest::{Measure, Shortest, ShortestDerivations};
+pub use slice::Slice;
+pub use token::{QueryToken, TokenClass};
 pub use utils::{GrammarError, Result, SqlNullValidator};
+pub use visit::{Fold, VisitContext, Visitor, VisitorMut, Walk};
+pub use weights::{ProductionWeights, WeightContext};
 
 // Re-export common enums and structs
 pub use grammar::{Element, Production};
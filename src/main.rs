@@ -1,7 +1,7 @@
-use clap::{Parser, Subcommand};
-use grammar_gen::Grammar;
+use clap::{Parser, Subcommand, ValueEnum};
+use grammar_gen::{DocConfig, Grammar, SqlFormatter};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
 
 /// Grammar-based text generator
@@ -20,11 +20,36 @@ struct Cli {
     #[arg(help = "Number of texts to generate", default_value = "1")]
     count: Option<usize>,
 
+    /// Number of worker threads (0 for one per CPU)
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Seed for reproducible output, identical for any number of jobs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Lay out generated SQL one clause per line
+    #[arg(long)]
+    pretty: bool,
+
+    /// What to print for each generated text
+    #[arg(long, value_enum, default_value_t = Emit::Text)]
+    emit: Emit,
+
     /// Subcommands
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// Output formats for generated texts
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Emit {
+    /// The numbered texts
+    Text,
+    /// One JSON document per line with the text and its derivation tree
+    AstJson,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Generate example grammar files
@@ -37,6 +62,52 @@ enum Commands {
         #[arg(help = "Output file path")]
         output: Option<PathBuf>,
     },
+    /// Generate an HTML page with a railroad diagram per rule
+    Doc {
+        /// Path to the grammar file
+        #[arg(help = "Path to the grammar file")]
+        grammar_file: PathBuf,
+
+        /// Output file path, standard output if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Sample expansions shown per rule
+        #[arg(long, default_value_t = 3)]
+        samples: usize,
+
+        /// Seed for the sample expansions
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Write the rules reachable from a start symbol in the grammar format
+    Slice {
+        /// Path to the grammar file
+        #[arg(help = "Path to the grammar file")]
+        grammar_file: PathBuf,
+
+        /// The symbol to slice from
+        #[arg(help = "Starting non-terminal symbol")]
+        start_symbol: String,
+
+        /// Symbols whose rules are left out
+        #[arg(short = 'x', long)]
+        exclude: Vec<String>,
+
+        /// Output file path, standard output if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show how a grammar's symbols, productions and weights changed
+    Diff {
+        /// Path to the old grammar file
+        #[arg(help = "Path to the old grammar file")]
+        old_file: PathBuf,
+
+        /// Path to the new grammar file
+        #[arg(help = "Path to the new grammar file")]
+        new_file: PathBuf,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -67,6 +138,51 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 );
                 return Ok(());
             }
+            Commands::Doc {
+                grammar_file,
+                output,
+                samples,
+                seed,
+            } => {
+                let grammar = Grammar::from_file(&grammar_file)?;
+                let config = DocConfig {
+                    title: grammar_file.display().to_string(),
+                    samples,
+                    seed,
+                    ..DocConfig::default()
+                };
+                let mut out = open_output(output.as_ref())?;
+                grammar.write_html(&config, &mut out)?;
+                out.flush()?;
+                return Ok(());
+            }
+            Commands::Slice {
+                grammar_file,
+                start_symbol,
+                exclude,
+                output,
+            } => {
+                let grammar = Grammar::from_file(&grammar_file)?;
+                let excluded: Vec<&str> = exclude.iter().map(String::as_str).collect();
+                let slice = grammar.slice_excluding(&start_symbol, &excluded)?;
+
+                for (symbol, users) in &slice.excluded {
+                    eprintln!("warning: excluded <{}> is used by {}", symbol, list(users));
+                }
+                for (symbol, users) in &slice.undefined {
+                    eprintln!("warning: undefined <{}> is used by {}", symbol, list(users));
+                }
+                let mut out = open_output(output.as_ref())?;
+                slice.grammar.write_text(&mut out)?;
+                out.flush()?;
+                return Ok(());
+            }
+            Commands::Diff { old_file, new_file } => {
+                let old = Grammar::from_file(&old_file)?;
+                let new = Grammar::from_file(&new_file)?;
+                print!("{}", old.diff(&new));
+                return Ok(());
+            }
         }
     }
 
@@ -75,20 +191,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start_symbol = cli.start_symbol.ok_or("Start symbol required")?;
     let count = cli.count.unwrap_or(1);
 
+    if cli.emit == Emit::AstJson {
+        let grammar = Grammar::from_file(&grammar_file)?;
+        let seed = cli.seed.unwrap_or_else(rand::random);
+        let mut out = BufWriter::new(io::stdout().lock());
+        grammar.generate_batch_with(&start_symbol, count, seed, cli.jobs, |_, ast| {
+            writeln!(out, "{}", ast.to_json_string()?)?;
+            Ok(())
+        })?;
+        out.flush()?;
+        return Ok(());
+    }
+
     println!("Loading grammar from {}...", grammar_file.display());
     let grammar = Grammar::from_file(&grammar_file)?;
 
     println!("Loaded {} rules.", grammar.rules().len());
     println!("Generating {} random samples:\n", count);
 
-    for i in 0..count {
-        let generated = grammar.generate(&start_symbol);
-        println!("{}. {}", i + 1, generated.text);
-    }
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    let formatter = SqlFormatter::default().with_renderer(grammar.renderer().clone_box());
+    let mut out = BufWriter::new(io::stdout().lock());
+    grammar.generate_batch_with(&start_symbol, count, seed, cli.jobs, |i, ast| {
+        if cli.pretty {
+            writeln!(out, "{}.\n{}\n", i + 1, formatter.format(&ast))?;
+        } else {
+            writeln!(out, "{}. {}", i + 1, ast.text)?;
+        }
+        Ok(())
+    })?;
+    out.flush()?;
 
     Ok(())
 }
 
+/// A buffered writer to the file at `path`, or to standard output
+fn open_output(path: Option<&PathBuf>) -> io::Result<BufWriter<Box<dyn Write>>> {
+    let out: Box<dyn Write> = match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    Ok(BufWriter::new(out))
+}
+
+/// Symbols as `<a>, <b>` for a warning
+fn list<'a>(symbols: impl IntoIterator<Item = &'a String>) -> String {
+    symbols
+        .into_iter()
+        .map(|symbol| format!("<{}>", symbol))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 fn read_sql_grammar(path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
@@ -0,0 +1,1491 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::grammar::{Element, Grammar, Production, QueryAst, QueryAstNode};
+use crate::utils::{GrammarError, Result};
+
+/// Most productions one production may expand into when epsilon
+/// productions are removed
+const MAX_VARIANTS: usize = 1 << 12;
+
+/// How a derivation node of a transformed grammar maps back to the original.
+///
+/// Every production of a transformed grammar has a template: items that,
+/// evaluated against a node's children, give the nodes the original grammar
+/// derives there. Helper symbols introduced by a transform give the nodes
+/// they stand for within their parent, and some receive nodes their parent
+/// already restored as arguments. Substituting one production into another
+/// substitutes the templates the same way, so chains of transforms still
+/// map straight back to the original grammar.
+#[derive(Debug, Clone, PartialEq)]
+enum Template {
+    /// The nodes restored from child `i`
+    Child(usize),
+    /// The nodes restored from a child given arguments evaluated here
+    Pass {
+        args: Vec<(u32, Vec<Template>)>,
+        child: usize,
+    },
+    /// The nodes passed as argument `id`
+    Arg(u32),
+    /// Items evaluated with further arguments, left where a passed child
+    /// was substituted
+    With {
+        args: Vec<(u32, Vec<Template>)>,
+        items: Vec<Template>,
+    },
+    /// A node of the original grammar
+    Node {
+        symbol: String,
+        production: usize,
+        children: Vec<Template>,
+    },
+}
+
+impl Template {
+    /// Apply `f` to this template and the templates nested in it, outermost first
+    fn map(&self, f: &mut impl FnMut(&Template) -> Option<Vec<Template>>) -> Vec<Template> {
+        if let Some(replaced) = f(self) {
+            return replaced;
+        }
+        let map_all = |items: &[Template], f: &mut _| -> Vec<Template> {
+            items.iter().flat_map(|item| item.map(f)).collect()
+        };
+        let map_args = |args: &[(u32, Vec<Template>)], f: &mut _| {
+            args.iter()
+                .map(|(id, items)| (*id, map_all(items, f)))
+                .collect()
+        };
+        vec![match self {
+            Template::Pass { args, child } => Template::Pass {
+                args: map_args(args, f),
+                child: *child,
+            },
+            Template::With { args, items } => Template::With {
+                args: map_args(args, f),
+                items: map_all(items, f),
+            },
+            Template::Node {
+                symbol,
+                production,
+                children,
+            } => Template::Node {
+                symbol: symbol.clone(),
+                production: *production,
+                children: map_all(children, f),
+            },
+            other => other.clone(),
+        }]
+    }
+}
+
+/// Renumber children by `f`
+fn renumber(items: &[Template], f: &dyn Fn(usize) -> usize) -> Vec<Template> {
+    let mut g = |item: &Template| match item {
+        Template::Child(i) => Some(vec![Template::Child(f(*i))]),
+        Template::Pass { args, child } => Some(vec![Template::Pass {
+            args: args
+                .iter()
+                .map(|(id, items)| (*id, renumber(items, f)))
+                .collect(),
+            child: f(*child),
+        }]),
+        _ => None,
+    };
+    items.iter().flat_map(|item| item.map(&mut g)).collect()
+}
+
+/// Replace child `k` with `replacement`, whose children are numbered from
+/// `k`, and renumber the children after it for a replacement of `len` children
+fn splice(items: &[Template], k: usize, replacement: &[Template], len: usize) -> Vec<Template> {
+    let shift = |i: usize| if i > k { i - 1 + len } else { i };
+    let mut f = |item: &Template| match item {
+        Template::Child(i) if *i == k => Some(replacement.to_vec()),
+        Template::Child(i) => Some(vec![Template::Child(shift(*i))]),
+        Template::Pass { args, child } => {
+            let args = args
+                .iter()
+                .map(|(id, items)| (*id, splice(items, k, replacement, len)))
+                .collect();
+            Some(vec![if *child == k {
+                Template::With {
+                    args,
+                    items: replacement.to_vec(),
+                }
+            } else {
+                Template::Pass {
+                    args,
+                    child: shift(*child),
+                }
+            }])
+        }
+        _ => None,
+    };
+    items.iter().flat_map(|item| item.map(&mut f)).collect()
+}
+
+/// Argument ids used but not bound within `items`
+fn free_args(items: &[Template]) -> BTreeSet<u32> {
+    fn visit(items: &[Template], bound: &mut Vec<u32>, free: &mut BTreeSet<u32>) {
+        for item in items {
+            match item {
+                Template::Arg(id) if !bound.contains(id) => {
+                    free.insert(*id);
+                }
+                Template::Arg(_) | Template::Child(_) => {}
+                Template::Pass { args, .. } => {
+                    for (_, items) in args {
+                        visit(items, bound, free);
+                    }
+                }
+                Template::With { args, items } => {
+                    for (_, items) in args {
+                        visit(items, bound, free);
+                    }
+                    let depth = bound.len();
+                    bound.extend(args.iter().map(|(id, _)| *id));
+                    visit(items, bound, free);
+                    bound.truncate(depth);
+                }
+                Template::Node { children, .. } => visit(children, bound, free),
+            }
+        }
+    }
+
+    let mut free = BTreeSet::new();
+    visit(items, &mut Vec::new(), &mut free);
+    free
+}
+
+/// Move the first `ids.len()` children of a production into arguments, for
+/// a helper that takes over the rest of the production.
+///
+/// Returns the arguments the parent passes to the helper, which forward any
+/// arguments the template already used, and the helper's template.
+fn to_args(items: &[Template], ids: &[u32]) -> (Vec<(u32, Vec<Template>)>, Vec<Template>) {
+    let j = ids.len();
+    let mut slots: BTreeMap<u32, Vec<Template>> = BTreeMap::new();
+    for id in free_args(items) {
+        slots.insert(id, vec![Template::Arg(id)]);
+    }
+
+    let mut f = |item: &Template| match item {
+        Template::Child(i) if *i < j => {
+            slots.insert(ids[*i], vec![item.clone()]);
+            Some(vec![Template::Arg(ids[*i])])
+        }
+        Template::Child(i) => Some(vec![Template::Child(i - j)]),
+        Template::Pass { child, .. } if *child < j => {
+            slots.insert(ids[*child], vec![item.clone()]);
+            Some(vec![Template::Arg(ids[*child])])
+        }
+        _ => None,
+    };
+    // Children in the arguments of a passed child still to be moved are
+    // prefix children, which become arguments
+    let mut moved: Vec<Template> = items.iter().flat_map(|item| item.map(&mut f)).collect();
+    moved = renumber_pass(&moved, j);
+    (slots.into_iter().collect(), moved)
+}
+
+/// Shift passed children at or after `j` down by `j`, after [`to_args`]
+/// has replaced plain children
+fn renumber_pass(items: &[Template], j: usize) -> Vec<Template> {
+    let mut f = |item: &Template| match item {
+        Template::Pass { args, child } if *child >= j => Some(vec![Template::Pass {
+            args: args
+                .iter()
+                .map(|(id, items)| (*id, renumber_pass(items, j)))
+                .collect(),
+            child: child - j,
+        }]),
+        _ => None,
+    };
+    items.iter().flat_map(|item| item.map(&mut f)).collect()
+}
+
+/// A production of a transformed grammar with its template
+#[derive(Debug, Clone)]
+struct Rule {
+    production: Production,
+    template: Vec<Template>,
+}
+
+impl Rule {
+    /// Substitute `with`, a rule of the non-terminal at element `k`, for it.
+    /// Neither rule may be guarded.
+    fn substitute(&self, k: usize, with: &Rule, weight: f64) -> Rule {
+        let len = with.production.elements.len();
+        let mut elements = self.production.elements[..k].to_vec();
+        elements.extend(with.production.elements.iter().cloned());
+        elements.extend(self.production.elements[k + 1..].iter().cloned());
+        Rule {
+            production: Production {
+                elements,
+                guard: None,
+                weight,
+            },
+            template: splice(
+                &self.template,
+                k,
+                &renumber(&with.template, &|i| i + k),
+                len,
+            ),
+        }
+    }
+
+    /// The symbol the element at `k` refers to, if it is a plain non-terminal
+    fn symbol_at(&self, k: usize) -> Option<&str> {
+        match self.production.elements.get(k) {
+            Some(Element::NonTerminal(name)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Weight of each of `rules` relative to their total, uniform when they
+/// have no positive weight
+fn shares(rules: &[Rule]) -> Vec<f64> {
+    let total: f64 = rules.iter().map(|rule| rule.production.weight).sum();
+    rules
+        .iter()
+        .map(|rule| {
+            if total > 0.0 {
+                rule.production.weight / total
+            } else {
+                1.0 / rules.len() as f64
+            }
+        })
+        .collect()
+}
+
+/// Drop rules of `symbol` whose elements repeat an earlier rule's, keeping
+/// the first. Rules guarded differently cannot be merged.
+fn dedup(symbol: &str, rules: Vec<Rule>) -> Result<Vec<Rule>> {
+    let mut kept: Vec<Rule> = Vec::new();
+    for rule in rules {
+        match kept
+            .iter()
+            .find(|other| other.production.elements == rule.production.elements)
+        {
+            None => kept.push(rule),
+            Some(other) if other.production.guard != rule.production.guard => {
+                let guarded = if rule.production.guard.is_some() {
+                    &rule
+                } else {
+                    other
+                };
+                unguarded(symbol, guarded)?;
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(kept)
+}
+
+/// Fail when `rule` of `symbol` is guarded, for transforms that would merge
+/// or move it: its predicate would then check a different expansion
+fn unguarded(symbol: &str, rule: &Rule) -> Result<()> {
+    match &rule.production.guard {
+        Some(guard) => Err(GrammarError::InvalidGrammar(format!(
+            "<{}> has a production guarded by '{}' that the transform would merge or move",
+            symbol, guard
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// A grammar produced by normalisation transforms, able to map its
+/// derivation trees back to the grammar it was made from.
+///
+/// Transforms preserve the language of every original non-terminal, except
+/// that removing epsilon productions drops the empty sentence. Bindings,
+/// variables, picks, regexes and references to undefined symbols are treated
+/// as terminals. New productions keep the weight of the production they came
+/// from, so probabilities are not preserved, and context weights are
+/// dropped. Transforms fail with [`GrammarError::InvalidGrammar`] rather
+/// than merge or move a guarded production.
+#[derive(Debug, Clone)]
+pub struct Transformed {
+    grammar: Grammar,
+    rules: BTreeMap<String, Vec<Rule>>,
+    /// Original symbol each non-terminal stands for; terminal helpers have none
+    origins: HashMap<String, String>,
+    next_arg: u32,
+}
+
+impl Transformed {
+    /// The identity transform of `grammar`, to chain transforms from
+    pub fn new(grammar: &Grammar) -> Self {
+        let mut rules = BTreeMap::new();
+        for (symbol, productions) in grammar.rules() {
+            let symbol_rules = productions
+                .iter()
+                .enumerate()
+                .map(|(p, production)| Rule {
+                    production: production.clone(),
+                    template: vec![Template::Node {
+                        symbol: symbol.clone(),
+                        production: p,
+                        children: (0..production.elements.len())
+                            .map(Template::Child)
+                            .collect(),
+                    }],
+                })
+                .collect();
+            rules.insert(symbol.clone(), symbol_rules);
+        }
+        let origins = rules.keys().map(|s| (s.clone(), s.clone())).collect();
+        Transformed {
+            grammar: grammar.clone(),
+            rules,
+            origins,
+            next_arg: 0,
+        }
+    }
+
+    /// The transformed grammar
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Take the transformed grammar
+    pub fn into_grammar(self) -> Grammar {
+        self.grammar
+    }
+
+    /// The original non-terminal `symbol` stands for, or `None` for helpers
+    /// that wrap a terminal and for unknown symbols
+    pub fn original_symbol(&self, symbol: &str) -> Option<&str> {
+        self.origins.get(symbol).map(String::as_str)
+    }
+
+    /// Map a derivation tree of the transformed grammar back to the original
+    /// grammar, with the original symbols and production indices.
+    ///
+    /// Attributes are not carried over. Unexpanded nodes stay unexpanded.
+    pub fn restore(&self, node: &QueryAstNode) -> Result<QueryAstNode> {
+        let mut nodes = self.restore_node(node, &HashMap::new());
+        let mut root = match (nodes.len(), self.origins.get(&node.value)) {
+            (1, _) => nodes.remove(0),
+            (_, Some(origin)) if node.value == *origin || node.production.is_none() => {
+                let mut root = QueryAstNode::new("non_terminal", origin);
+                root.children = nodes;
+                root
+            }
+            _ => {
+                return Err(GrammarError::InvalidGrammar(format!(
+                    "<{}> was introduced by a transform and has no node of its own in the original grammar",
+                    node.value
+                )));
+            }
+        };
+
+        // Generation appends an error node to the root when it runs out of budget
+        let covered = node
+            .production
+            .and_then(|p| self.rules.get(&node.value)?.get(p))
+            .map_or(node.children.len(), |rule| rule.production.elements.len());
+        root.children
+            .extend(node.children.iter().skip(covered).cloned());
+        Ok(root)
+    }
+
+    /// Map an AST of the transformed grammar back to the original grammar
+    pub fn restore_ast(&self, ast: &QueryAst) -> Result<QueryAst> {
+        Ok(QueryAst {
+            text: ast.text.clone(),
+            type_name: self
+                .original_symbol(&ast.type_name)
+                .unwrap_or(&ast.type_name)
+                .to_string(),
+            root: self.restore(&ast.root)?,
+        })
+    }
+
+    fn restore_node(
+        &self,
+        node: &QueryAstNode,
+        args: &HashMap<u32, Vec<QueryAstNode>>,
+    ) -> Vec<QueryAstNode> {
+        if node.element_type != "non_terminal" {
+            return vec![node.clone()];
+        }
+
+        let template = node
+            .production
+            .and_then(|p| self.rules.get(&node.value)?.get(p));
+        let mut out = Vec::new();
+        match (template, self.origins.get(&node.value)) {
+            (Some(rule), _) => self.eval(&rule.template, &node.children, args, &mut out),
+            // Left unexpanded when the generation budget ran out
+            (None, Some(origin)) if *origin == node.value => {
+                out.push(QueryAstNode::new("non_terminal", origin));
+            }
+            (None, _) => {
+                let mut ids: Vec<_> = args.keys().collect();
+                ids.sort();
+                for id in ids {
+                    out.extend(args[id].iter().cloned());
+                }
+            }
+        }
+        out
+    }
+
+    fn eval(
+        &self,
+        items: &[Template],
+        children: &[QueryAstNode],
+        args: &HashMap<u32, Vec<QueryAstNode>>,
+        out: &mut Vec<QueryAstNode>,
+    ) {
+        let bind = |bindings: &[(u32, Vec<Template>)], mut scope: HashMap<_, _>| {
+            for (id, items) in bindings {
+                let mut nodes = Vec::new();
+                self.eval(items, children, args, &mut nodes);
+                scope.insert(*id, nodes);
+            }
+            scope
+        };
+
+        for item in items {
+            match item {
+                Template::Child(i) => {
+                    if let Some(child) = children.get(*i) {
+                        out.extend(self.restore_node(child, &HashMap::new()));
+                    }
+                }
+                Template::Pass {
+                    args: bindings,
+                    child,
+                } => {
+                    let scope = bind(bindings, HashMap::new());
+                    if let Some(child) = children.get(*child) {
+                        out.extend(self.restore_node(child, &scope));
+                    }
+                }
+                Template::Arg(id) => out.extend(args.get(id).into_iter().flatten().cloned()),
+                Template::With {
+                    args: bindings,
+                    items,
+                } => {
+                    let scope = bind(bindings, args.clone());
+                    self.eval(items, children, &scope, out);
+                }
+                Template::Node {
+                    symbol,
+                    production,
+                    children: items,
+                } => {
+                    let mut node = QueryAstNode::new("non_terminal", symbol);
+                    node.production = Some(*production);
+                    self.eval(items, children, args, &mut node.children);
+                    out.push(node);
+                }
+            }
+        }
+    }
+
+    /// Remove productions without elements.
+    ///
+    /// Each production referring to nullable symbols is replaced by a copy
+    /// for every combination of them left out. Restored trees put back an
+    /// empty derivation of each symbol left out.
+    pub fn remove_epsilon(mut self) -> Result<Self> {
+        // Nullable symbols with the template of one of their empty derivations
+        let mut empty: HashMap<String, Vec<Template>> = HashMap::new();
+        loop {
+            let mut found = false;
+            for (symbol, rules) in &self.rules {
+                if empty.contains_key(symbol) {
+                    continue;
+                }
+                let nullable = rules.iter().find(|rule| {
+                    (0..rule.production.elements.len())
+                        .all(|k| rule.symbol_at(k).is_some_and(|s| empty.contains_key(s)))
+                });
+                if let Some(rule) = nullable {
+                    let mut template = rule.template.clone();
+                    for k in (0..rule.production.elements.len()).rev() {
+                        let symbol = rule.symbol_at(k).unwrap_or_default();
+                        template = splice(&template, k, &empty[symbol], 0);
+                    }
+                    empty.insert(symbol.clone(), template);
+                    found = true;
+                }
+            }
+            if !found {
+                break;
+            }
+        }
+        if empty.is_empty() {
+            return Ok(self);
+        }
+
+        // A nullable production's empty derivation is merged into its users
+        for (symbol, rules) in &self.rules {
+            for rule in rules {
+                let nullable = (0..rule.production.elements.len())
+                    .all(|k| rule.symbol_at(k).is_some_and(|s| empty.contains_key(s)));
+                if nullable {
+                    unguarded(symbol, rule)?;
+                }
+            }
+        }
+
+        // Symbols with a non-empty sentence, which may be kept where they are used
+        let mut solid: HashSet<String> = HashSet::new();
+        loop {
+            let before = solid.len();
+            for (symbol, rules) in &self.rules {
+                let has_text = rules.iter().any(|rule| {
+                    (0..rule.production.elements.len()).any(|k| match rule.symbol_at(k) {
+                        Some(s) if self.rules.contains_key(s) => solid.contains(s),
+                        _ => true,
+                    })
+                });
+                if has_text {
+                    solid.insert(symbol.clone());
+                }
+            }
+            if solid.len() == before {
+                break;
+            }
+        }
+
+        let mut rules = BTreeMap::new();
+        for (symbol, symbol_rules) in &self.rules {
+            let mut variants = Vec::new();
+            for rule in symbol_rules {
+                let len = rule.production.elements.len();
+                let optional: Vec<usize> = (0..len)
+                    .filter(|&k| rule.symbol_at(k).is_some_and(|s| empty.contains_key(s)))
+                    .collect();
+                if optional.len() >= MAX_VARIANTS.trailing_zeros() as usize {
+                    return Err(GrammarError::InvalidGrammar(format!(
+                        "<{}> has a production with {} nullable symbols, too many to expand",
+                        symbol,
+                        optional.len()
+                    )));
+                }
+
+                for mask in 0..1usize << optional.len() {
+                    let dropped: Vec<usize> = optional
+                        .iter()
+                        .enumerate()
+                        .filter(|&(bit, &k)| {
+                            mask & (1 << bit) != 0
+                                || !solid.contains(rule.symbol_at(k).unwrap_or_default())
+                        })
+                        .map(|(_, &k)| k)
+                        .collect();
+                    if dropped.len() == len {
+                        continue;
+                    }
+                    let mut variant = rule.clone();
+                    for &k in dropped.iter().rev() {
+                        let symbol = rule.symbol_at(k).unwrap_or_default();
+                        variant.production.elements.remove(k);
+                        variant.template = splice(&variant.template, k, &empty[symbol], 0);
+                    }
+                    variants.push(variant);
+                }
+            }
+            let variants = dedup(symbol, variants)?;
+            if !variants.is_empty() {
+                rules.insert(symbol.clone(), variants);
+            }
+        }
+
+        self.origins.retain(|symbol, _| rules.contains_key(symbol));
+        self.rules = rules;
+        Ok(self.rebuild())
+    }
+
+    /// Replace productions that are a single non-terminal with that
+    /// non-terminal's productions
+    pub fn remove_unit_productions(mut self) -> Result<Self> {
+        let is_unit = |rule: &Rule, rules: &BTreeMap<String, Vec<Rule>>| {
+            rule.production.elements.len() == 1
+                && rule.symbol_at(0).is_some_and(|s| rules.contains_key(s))
+        };
+
+        let mut rules = BTreeMap::new();
+        for symbol in self.rules.keys() {
+            let mut result = Vec::new();
+            let mut visited = BTreeSet::from([symbol.as_str()]);
+            // The symbol reached, the template with it as child 0 and the
+            // factor its weights are scaled by
+            let mut queue = vec![(symbol.as_str(), vec![Template::Child(0)], 1.0)];
+            while let Some((current, template, factor)) = queue.pop() {
+                let current_rules = &self.rules[current];
+                let weights = shares(current_rules);
+                for (rule, share) in current_rules.iter().zip(weights) {
+                    let weight = if current == symbol {
+                        rule.production.weight
+                    } else {
+                        factor * share
+                    };
+                    // Unit productions are replaced, and the productions
+                    // they reach are copied into `symbol`
+                    if current != symbol || is_unit(rule, &self.rules) {
+                        unguarded(current, rule)?;
+                    }
+                    let through = splice(&template, 0, &rule.template, 1);
+                    if is_unit(rule, &self.rules) {
+                        let target = rule.symbol_at(0).unwrap_or_default();
+                        if visited.insert(target) {
+                            queue.push((target, through, weight));
+                        }
+                        continue;
+                    }
+                    result.push(Rule {
+                        template: splice(
+                            &template,
+                            0,
+                            &rule.template,
+                            rule.production.elements.len(),
+                        ),
+                        production: Production {
+                            weight,
+                            ..rule.production.clone()
+                        },
+                    });
+                }
+            }
+            rules.insert(symbol.clone(), dedup(symbol, result)?);
+        }
+
+        self.rules = rules;
+        Ok(self.rebuild())
+    }
+
+    /// Eliminate direct and indirect left recursion.
+    ///
+    /// `<a> ::= [<a>, x]` next to `<a> ::= [y]` becomes `<a> ::= [y]`,
+    /// `<a> ::= [y, <a_tail>]`, `<a_tail> ::= [x]` and
+    /// `<a_tail> ::= [x, <a_tail>]`, and restored trees nest to the left as
+    /// before. The grammar must not have epsilon productions.
+    pub fn eliminate_left_recursion(mut self) -> Result<Self> {
+        if let Some((symbol, _)) = self
+            .rules
+            .iter()
+            .find(|(_, rules)| rules.iter().any(|r| r.production.elements.is_empty()))
+        {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "<{}> has an epsilon production; remove epsilon productions first",
+                symbol
+            )));
+        }
+
+        // Symbols each symbol can start with
+        let corners: BTreeMap<&str, BTreeSet<&str>> = self
+            .rules
+            .iter()
+            .map(|(symbol, rules)| {
+                let first = rules
+                    .iter()
+                    .filter_map(|rule| rule.symbol_at(0))
+                    .filter(|s| self.rules.contains_key(*s))
+                    .collect();
+                (symbol.as_str(), first)
+            })
+            .collect();
+        let reach = |from: &str| {
+            let mut seen = BTreeSet::new();
+            let mut stack = vec![from];
+            while let Some(symbol) = stack.pop() {
+                for &next in &corners[symbol] {
+                    if seen.insert(next) {
+                        stack.push(next);
+                    }
+                }
+            }
+            seen
+        };
+        let reaches: BTreeMap<&str, BTreeSet<&str>> = corners
+            .keys()
+            .map(|&symbol| (symbol, reach(symbol)))
+            .collect();
+
+        // Groups of mutually left-recursive symbols, each in sorted order
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        let mut grouped = BTreeSet::new();
+        for (&symbol, reached) in &reaches {
+            if !reached.contains(symbol) || grouped.contains(symbol) {
+                continue;
+            }
+            let group: Vec<String> = reaches
+                .iter()
+                .filter(|(other, theirs)| reached.contains(*other) && theirs.contains(symbol))
+                .map(|(other, _)| other.to_string())
+                .collect();
+            grouped.extend(group.iter().cloned());
+            groups.push(group);
+        }
+
+        for group in groups {
+            for i in 0..group.len() {
+                let symbol = &group[i];
+                for earlier in &group[..i] {
+                    let replacements = self.rules[earlier].clone();
+                    let weights = shares(&replacements);
+                    let mut rules = Vec::new();
+                    for rule in &self.rules[symbol] {
+                        if rule.symbol_at(0) != Some(earlier.as_str()) {
+                            rules.push(rule.clone());
+                            continue;
+                        }
+                        unguarded(symbol, rule)?;
+                        for (with, share) in replacements.iter().zip(&weights) {
+                            unguarded(earlier, with)?;
+                            rules.push(rule.substitute(0, with, rule.production.weight * share));
+                        }
+                    }
+                    self.rules.insert(symbol.clone(), dedup(symbol, rules)?);
+                }
+                self.eliminate_direct(symbol)?;
+            }
+        }
+
+        Ok(self.rebuild())
+    }
+
+    fn eliminate_direct(&mut self, symbol: &str) -> Result<()> {
+        let (recursive, others): (Vec<Rule>, Vec<Rule>) = self.rules[symbol]
+            .iter()
+            .filter(|rule| rule.production.elements.len() > 1 || rule.symbol_at(0) != Some(symbol))
+            .cloned()
+            .partition(|rule| rule.symbol_at(0) == Some(symbol));
+        if recursive.is_empty() {
+            return Ok(());
+        }
+        if others.is_empty() {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "<{}> is left-recursive in every production",
+                symbol
+            )));
+        }
+        // Recursive productions move to the tail, and the others are
+        // continued with it
+        for rule in recursive.iter().chain(&others) {
+            unguarded(symbol, rule)?;
+        }
+
+        let tail = self.fresh(&format!("{}_tail", symbol));
+        let id = self.arg();
+        let tail_rules: Vec<Rule> = recursive
+            .iter()
+            .map(|rule| {
+                // The recursive child becomes the argument; the tail passes
+                // on any other argument itself
+                let (_, template) = to_args(&rule.template, &[id]);
+                Rule {
+                    production: Production {
+                        elements: rule.production.elements[1..].to_vec(),
+                        ..rule.production.clone()
+                    },
+                    template,
+                }
+            })
+            .collect();
+        let forwarded: BTreeSet<u32> = tail_rules
+            .iter()
+            .flat_map(|rule| free_args(&rule.template))
+            .filter(|&other| other != id)
+            .collect();
+        let pass = |value: Vec<Template>, child: usize| {
+            let mut args = vec![(id, value)];
+            args.extend(forwarded.iter().map(|&x| (x, vec![Template::Arg(x)])));
+            vec![Template::Pass { args, child }]
+        };
+
+        let mut rules = Vec::new();
+        for rule in others {
+            let len = rule.production.elements.len();
+            let mut continued = rule.clone();
+            continued
+                .production
+                .elements
+                .push(Element::NonTerminal(tail.clone()));
+            continued.template = pass(rule.template.clone(), len);
+            rules.push(rule);
+            rules.push(continued);
+        }
+
+        let mut tails = Vec::new();
+        for rule in tail_rules {
+            let len = rule.production.elements.len();
+            let mut continued = rule.clone();
+            continued
+                .production
+                .elements
+                .push(Element::NonTerminal(tail.clone()));
+            continued.template = pass(rule.template.clone(), len);
+            tails.push(rule);
+            tails.push(continued);
+        }
+
+        self.rules.insert(symbol.to_string(), rules);
+        self.rules.insert(tail.clone(), tails);
+        if let Some(origin) = self.origins.get(symbol).cloned() {
+            self.origins.insert(tail, origin);
+        }
+        Ok(())
+    }
+
+    /// Inline every non-terminal used exactly once, by a plain reference in
+    /// another symbol's production, and remove it.
+    ///
+    /// Symbols with attributes are kept, since their attributes would be
+    /// lost, as are symbols used as generation start symbols only, which are
+    /// not referenced at all.
+    pub fn inline_single_use(mut self) -> Result<Self> {
+        loop {
+            let mut uses: BTreeMap<&str, Vec<(&str, usize, usize)>> = BTreeMap::new();
+            let mut pinned: BTreeSet<&str> = BTreeSet::new();
+            for (symbol, rules) in &self.rules {
+                for (r, rule) in rules.iter().enumerate() {
+                    for (k, element) in rule.production.elements.iter().enumerate() {
+                        match element {
+                            Element::NonTerminal(name) => {
+                                uses.entry(name.as_str()).or_default().push((symbol, r, k))
+                            }
+                            Element::Bind(binding) => {
+                                if let Element::NonTerminal(name) = binding.element.as_ref() {
+                                    pinned.insert(name.as_str());
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            let candidate = uses.iter().find_map(|(&name, places)| {
+                let &[(user, r, k)] = places.as_slice() else {
+                    return None;
+                };
+                let rules = self.rules.get(name)?;
+                let inlinable = user != name
+                    && !pinned.contains(name)
+                    && self.grammar.attributes(name).is_empty()
+                    && rules
+                        .iter()
+                        .all(|rule| free_args(&rule.template).is_empty());
+                inlinable.then(|| (name.to_string(), user.to_string(), r, k))
+            });
+            let Some((name, user, r, k)) = candidate else {
+                break;
+            };
+
+            let inlined = self.rules.remove(&name).unwrap_or_default();
+            let weights = shares(&inlined);
+            let user_rules = self.rules.get_mut(&user).expect("user of a symbol exists");
+            let rule = user_rules.remove(r);
+            unguarded(&user, &rule)?;
+            for with in &inlined {
+                unguarded(&name, with)?;
+            }
+            let replacements: Vec<Rule> = inlined
+                .iter()
+                .zip(weights)
+                .map(|(with, share)| rule.substitute(k, with, rule.production.weight * share))
+                .collect();
+            user_rules.splice(r..r, replacements);
+            *user_rules = dedup(&user, std::mem::take(user_rules))?;
+            self.origins.remove(&name);
+        }
+
+        Ok(self.rebuild())
+    }
+
+    /// Factor out prefixes shared by productions of the same symbol.
+    ///
+    /// `<a> ::= [x, y, z]` next to `<a> ::= [x, y, w]` becomes
+    /// `<a> ::= [x, y, <a_rest>]` with `<a_rest> ::= [z]` and
+    /// `<a_rest> ::= [w]`. A production that is the shared prefix itself is
+    /// left as it is, since the suffix helper would need an empty production.
+    pub fn factor_prefixes(mut self) -> Result<Self> {
+        let mut pending: Vec<String> = self.rules.keys().cloned().collect();
+        let mut stuck: HashSet<(String, usize)> = HashSet::new();
+        while let Some(symbol) = pending.pop() {
+            let Some(group) = self.prefix_group(&symbol, &stuck) else {
+                continue;
+            };
+            let (members, len) = group;
+            // Factored productions move to the helper
+            for &m in &members {
+                unguarded(&symbol, &self.rules[&symbol][m])?;
+            }
+            match self.factor(&symbol, &members, len) {
+                Some(helper) => {
+                    pending.push(helper);
+                    pending.push(symbol);
+                }
+                None => {
+                    stuck.insert((symbol.clone(), members[0]));
+                    pending.push(symbol);
+                }
+            }
+        }
+        Ok(self.rebuild())
+    }
+
+    /// Indices of two or more productions of `symbol` sharing a prefix, and
+    /// the prefix length
+    fn prefix_group(
+        &self,
+        symbol: &str,
+        stuck: &HashSet<(String, usize)>,
+    ) -> Option<(Vec<usize>, usize)> {
+        let rules = &self.rules[symbol];
+        let common = |members: &[usize]| {
+            let first = &rules[members[0]].production.elements;
+            (0..first.len())
+                .take_while(|&i| {
+                    members
+                        .iter()
+                        .all(|&m| rules[m].production.elements.get(i) == Some(&first[i]))
+                })
+                .count()
+        };
+
+        for (i, rule) in rules.iter().enumerate() {
+            if stuck.contains(&(symbol.to_string(), i)) {
+                continue;
+            }
+            let Some(head) = rule.production.elements.first() else {
+                continue;
+            };
+            let mut members: Vec<usize> = (i..rules.len())
+                .filter(|&j| rules[j].production.elements.first() == Some(head))
+                .collect();
+            if members.len() < 2 || members[0] != i {
+                continue;
+            }
+            // Leave out productions that are the whole prefix until the
+            // rest still share it
+            loop {
+                let len = common(&members);
+                let longer: Vec<usize> = members
+                    .iter()
+                    .copied()
+                    .filter(|&m| rules[m].production.elements.len() > len)
+                    .collect();
+                if longer.len() < 2 {
+                    break;
+                }
+                if longer.len() == members.len() {
+                    return Some((members, len));
+                }
+                members = longer;
+            }
+        }
+        None
+    }
+
+    /// Factor the prefix of length `len` out of `members`, returning the new
+    /// helper, or `None` when their templates pass different values for the
+    /// prefix
+    fn factor(&mut self, symbol: &str, members: &[usize], len: usize) -> Option<String> {
+        let ids: Vec<u32> = (0..len).map(|_| self.arg()).collect();
+        let rules = &self.rules[symbol];
+
+        let mut slots: BTreeMap<u32, Vec<Template>> = BTreeMap::new();
+        let mut helper_rules = Vec::new();
+        for &m in members {
+            let rule = &rules[m];
+            let (rule_slots, template) = to_args(&rule.template, &ids);
+            for (id, value) in rule_slots {
+                if slots.get(&id).is_some_and(|other| *other != value) {
+                    return None;
+                }
+                slots.insert(id, value);
+            }
+            helper_rules.push(Rule {
+                production: Production {
+                    elements: rule.production.elements[len..].to_vec(),
+                    ..rule.production.clone()
+                },
+                template,
+            });
+        }
+
+        let helper = self.fresh(&format!("{}_rest", symbol));
+        let rules = &self.rules[symbol];
+        let mut elements = rules[members[0]].production.elements[..len].to_vec();
+        elements.push(Element::NonTerminal(helper.clone()));
+        let factored = Rule {
+            production: Production {
+                elements,
+                guard: None,
+                weight: members.iter().map(|&m| rules[m].production.weight).sum(),
+            },
+            template: vec![Template::Pass {
+                args: slots.into_iter().collect(),
+                child: len,
+            }],
+        };
+
+        let mut kept = Vec::new();
+        for (i, rule) in rules.iter().enumerate() {
+            if i == members[0] {
+                kept.push(factored.clone());
+            } else if !members.contains(&i) {
+                kept.push(rule.clone());
+            }
+        }
+        self.rules.insert(symbol.to_string(), kept);
+        self.rules.insert(helper.clone(), helper_rules);
+        if let Some(origin) = self.origins.get(symbol).cloned() {
+            self.origins.insert(helper.clone(), origin);
+        }
+        Some(helper)
+    }
+
+    /// Convert to Chomsky normal form: every production is two non-terminals
+    /// or a single terminal.
+    ///
+    /// Epsilon and unit productions are removed first. Terminals in longer
+    /// productions are replaced by `<term>` helpers, and productions longer
+    /// than two are split with `<symbol_rest>` helpers.
+    pub fn to_cnf(self) -> Result<Self> {
+        let mut cnf = self.remove_epsilon()?.remove_unit_productions()?;
+
+        let is_symbol = |element: &Element, rules: &BTreeMap<String, Vec<Rule>>| matches!(element, Element::NonTerminal(name) if rules.contains_key(name));
+
+        let mut terminals: Vec<(Element, String)> = Vec::new();
+        let symbols: Vec<String> = cnf.rules.keys().cloned().collect();
+        for symbol in &symbols {
+            let mut rules = cnf.rules[symbol].clone();
+            for rule in &mut rules {
+                if rule.production.elements.len() < 2 {
+                    continue;
+                }
+                for element in &mut rule.production.elements {
+                    if is_symbol(element, &cnf.rules) {
+                        continue;
+                    }
+                    let name = match terminals.iter().find(|(e, _)| e == element) {
+                        Some((_, name)) => name.clone(),
+                        None => {
+                            let name = cnf.fresh("term");
+                            cnf.rules.insert(
+                                name.clone(),
+                                vec![Rule {
+                                    production: Production {
+                                        elements: vec![element.clone()],
+                                        guard: None,
+                                        weight: 1.0,
+                                    },
+                                    template: vec![Template::Child(0)],
+                                }],
+                            );
+                            terminals.push((element.clone(), name.clone()));
+                            name
+                        }
+                    };
+                    *element = Element::NonTerminal(name);
+                }
+            }
+            cnf.rules.insert(symbol.clone(), rules);
+        }
+
+        for symbol in &symbols {
+            let mut rules = cnf.rules[symbol].clone();
+            for rule in &mut rules {
+                let mut owner = symbol.clone();
+                let mut current = rule.clone();
+                let mut head: Option<Rule> = None;
+                while current.production.elements.len() > 2 {
+                    let helper = cnf.fresh(&format!("{}_rest", owner));
+                    let id = cnf.arg();
+                    let (slots, template) = to_args(&current.template, &[id]);
+                    let split = Rule {
+                        production: Production {
+                            elements: vec![
+                                current.production.elements[0].clone(),
+                                Element::NonTerminal(helper.clone()),
+                            ],
+                            ..current.production.clone()
+                        },
+                        template: vec![Template::Pass {
+                            args: slots,
+                            child: 1,
+                        }],
+                    };
+                    let rest = Rule {
+                        production: Production {
+                            elements: current.production.elements[1..].to_vec(),
+                            guard: None,
+                            weight: 1.0,
+                        },
+                        template,
+                    };
+                    match head {
+                        None => head = Some(split),
+                        Some(_) => {
+                            cnf.rules.insert(owner.clone(), vec![split]);
+                        }
+                    }
+                    if let Some(origin) = cnf.origins.get(symbol).cloned() {
+                        cnf.origins.insert(helper.clone(), origin);
+                    }
+                    // Placeholder until the helper's own production is final
+                    cnf.rules.insert(helper.clone(), Vec::new());
+                    owner = helper;
+                    current = rest;
+                }
+                match head {
+                    Some(head) => {
+                        cnf.rules.insert(owner, vec![current]);
+                        *rule = head;
+                    }
+                    None => *rule = current,
+                }
+            }
+            cnf.rules.insert(symbol.clone(), rules);
+        }
+
+        Ok(cnf.rebuild())
+    }
+
+    /// A symbol name starting with `base` not used by the grammar
+    fn fresh(&self, base: &str) -> String {
+        let used = |name: &str| {
+            self.rules.contains_key(name)
+                || self.rules.values().flatten().any(|rule| {
+                    rule.production
+                        .elements
+                        .iter()
+                        .any(|e| matches!(e, Element::NonTerminal(n) if n == name))
+                })
+        };
+        let mut name = base.to_string();
+        let mut n = 1;
+        while used(&name) {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        name
+    }
+
+    /// A new argument id
+    fn arg(&mut self) -> u32 {
+        self.next_arg += 1;
+        self.next_arg
+    }
+
+    /// Rebuild the grammar from the rules
+    fn rebuild(mut self) -> Self {
+        let mut grammar = self.grammar.without_rules();
+        for (symbol, rules) in &self.rules {
+            for rule in rules {
+                grammar.add_production(symbol, rule.production.clone());
+            }
+        }
+        self.grammar = grammar;
+        self
+    }
+}
+
+impl Grammar {
+    /// Remove epsilon productions; see [`Transformed::remove_epsilon`]
+    pub fn remove_epsilon(&self) -> Result<Transformed> {
+        Transformed::new(self).remove_epsilon()
+    }
+
+    /// Remove unit productions; see [`Transformed::remove_unit_productions`]
+    pub fn remove_unit_productions(&self) -> Result<Transformed> {
+        Transformed::new(self).remove_unit_productions()
+    }
+
+    /// Eliminate left recursion; see [`Transformed::eliminate_left_recursion`]
+    pub fn eliminate_left_recursion(&self) -> Result<Transformed> {
+        Transformed::new(self).eliminate_left_recursion()
+    }
+
+    /// Inline single-use symbols; see [`Transformed::inline_single_use`]
+    pub fn inline_single_use(&self) -> Result<Transformed> {
+        Transformed::new(self).inline_single_use()
+    }
+
+    /// Factor out shared prefixes; see [`Transformed::factor_prefixes`]
+    pub fn factor_prefixes(&self) -> Result<Transformed> {
+        Transformed::new(self).factor_prefixes()
+    }
+
+    /// Convert to Chomsky normal form; see [`Transformed::to_cnf`]
+    pub fn to_cnf(&self) -> Result<Transformed> {
+        Transformed::new(self).to_cnf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn join_grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule(
+                "query",
+                vec!["SELECT", "<column>", "FROM", "<table_reference>"],
+            )
+            .unwrap();
+        grammar
+            .add_rule("table_reference", vec!["<table>"])
+            .unwrap();
+        grammar
+            .add_rule(
+                "table_reference",
+                vec!["<table_reference>", "<join_clause>"],
+            )
+            .unwrap();
+        grammar
+            .add_rule("join_clause", vec!["JOIN", "<table>", "<condition>"])
+            .unwrap();
+        grammar
+            .add_rule("condition", vec!["ON", "id", "=", "id"])
+            .unwrap();
+        grammar.add_rule("condition", vec!["USING", "id"]).unwrap();
+        grammar.add_rule("column", vec!["id"]).unwrap();
+        grammar.add_rule("column", vec!["name"]).unwrap();
+        grammar.add_rule("table", vec!["t1"]).unwrap();
+        grammar.add_rule("table", vec!["t2"]).unwrap();
+        grammar
+    }
+
+    /// Generate from the transformed grammar and check that every restored
+    /// tree is the original grammar's parse of the same text
+    fn assert_round_trips(original: &Grammar, transformed: &Transformed, start: &str) {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..30 {
+            let ast = transformed.grammar().generate_with_rng(start, &mut rng);
+            let restored = transformed.restore_ast(&ast).unwrap();
+            let parsed = original.parse(start, &ast.text).unwrap();
+            assert_eq!(restored.type_name, start);
+            assert_eq!(
+                restored.root.to_debug_string(),
+                parsed.to_debug_string(),
+                "{}",
+                ast.text
+            );
+        }
+    }
+
+    fn elements(grammar: &Grammar, symbol: &str) -> Vec<Vec<Element>> {
+        grammar.rules()[symbol]
+            .iter()
+            .map(|production| production.elements.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_eliminate_left_recursion() {
+        let grammar = join_grammar();
+        let transformed = grammar.eliminate_left_recursion().unwrap();
+
+        let result = transformed.grammar();
+        assert!(result.has_non_terminal("table_reference_tail"));
+        assert_eq!(
+            transformed.original_symbol("table_reference_tail"),
+            Some("table_reference")
+        );
+        for productions in result.rules().values() {
+            for production in productions {
+                assert_ne!(
+                    production.elements.first(),
+                    Some(&Element::NonTerminal("table_reference".to_string()))
+                );
+            }
+        }
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_indirect_left_recursion() {
+        let mut grammar = Grammar::new();
+        grammar.add_rule("a", vec!["<b>", "x"]).unwrap();
+        grammar.add_rule("a", vec!["y"]).unwrap();
+        grammar.add_rule("b", vec!["<a>", "z"]).unwrap();
+        grammar.add_rule("b", vec!["w"]).unwrap();
+
+        let transformed = grammar.eliminate_left_recursion().unwrap();
+        assert_round_trips(&grammar, &transformed, "a");
+        assert_round_trips(&grammar, &transformed, "b");
+
+        let mut looping = Grammar::new();
+        looping.add_rule("a", vec!["<a>", "x"]).unwrap();
+        assert!(looping.eliminate_left_recursion().is_err());
+    }
+
+    #[test]
+    fn test_remove_epsilon() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "id", "<where>", "<limit>"])
+            .unwrap();
+        let empty = Production {
+            elements: Vec::new(),
+            guard: None,
+            weight: 1.0,
+        };
+        grammar.add_production("where", empty.clone());
+        grammar.add_rule("where", vec!["WHERE", "id"]).unwrap();
+        grammar.add_production("limit", empty);
+        grammar.add_rule("limit", vec!["LIMIT", "1"]).unwrap();
+
+        let transformed = grammar.remove_epsilon().unwrap();
+        let result = transformed.grammar();
+        assert_eq!(result.rules()["query"].len(), 4);
+        assert!(
+            result
+                .rules()
+                .values()
+                .flatten()
+                .all(|production| !production.elements.is_empty())
+        );
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_remove_unit_productions() {
+        let grammar = join_grammar();
+        let transformed = grammar.remove_unit_productions().unwrap();
+
+        let table_reference = elements(transformed.grammar(), "table_reference");
+        assert!(table_reference.contains(&vec![Element::Terminal("t1".to_string())]));
+        assert!(!table_reference.contains(&vec![Element::NonTerminal("table".to_string())]));
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_inline_single_use() {
+        let grammar = join_grammar();
+        let transformed = grammar.inline_single_use().unwrap();
+
+        let result = transformed.grammar();
+        assert!(!result.has_non_terminal("condition"));
+        assert!(!result.has_non_terminal("column"));
+        assert!(!result.has_non_terminal("join_clause"));
+        assert!(result.has_non_terminal("table"));
+        assert_eq!(transformed.original_symbol("condition"), None);
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_factor_prefixes() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "id", "FROM", "t1"])
+            .unwrap();
+        grammar
+            .add_rule("query", vec!["SELECT", "id", "FROM", "t2"])
+            .unwrap();
+        grammar.add_rule("query", vec!["SELECT", "name"]).unwrap();
+        grammar.add_rule("query", vec!["VALUES", "1"]).unwrap();
+
+        let transformed = grammar.factor_prefixes().unwrap();
+        let result = transformed.grammar();
+        assert_eq!(
+            elements(result, "query"),
+            vec![
+                vec![
+                    Element::Terminal("SELECT".to_string()),
+                    Element::NonTerminal("query_rest".to_string())
+                ],
+                vec![
+                    Element::Terminal("VALUES".to_string()),
+                    Element::Terminal("1".to_string())
+                ],
+            ]
+        );
+        assert!(result.has_non_terminal("query_rest_rest"));
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_to_cnf() {
+        let grammar = join_grammar();
+        let transformed = grammar.to_cnf().unwrap();
+
+        let result = transformed.grammar();
+        for production in result.rules().values().flatten() {
+            match production.elements.as_slice() {
+                [Element::Terminal(_)] => {}
+                [Element::NonTerminal(a), Element::NonTerminal(b)] => {
+                    assert!(result.has_non_terminal(a) && result.has_non_terminal(b));
+                }
+                other => panic!("not in Chomsky normal form: {:?}", other),
+            }
+        }
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+
+    #[test]
+    fn test_guarded_productions_are_not_moved() {
+        let invalid = |result: Result<Transformed>| match result {
+            Err(GrammarError::InvalidGrammar(message)) => message.contains("'p'"),
+            _ => false,
+        };
+        let parse = |text: &str| text.parse::<Grammar>().unwrap();
+
+        let mut grammar = parse("<q> ::= [a, <e>]");
+        grammar.add_production(
+            "e",
+            Production {
+                elements: Vec::new(),
+                guard: Some("p".to_string()),
+                weight: 1.0,
+            },
+        );
+        assert!(invalid(grammar.remove_epsilon()));
+        assert!(invalid(grammar.to_cnf()));
+
+        let grammar = parse("<q> ::= [<t>] if p\n<q> ::= [y]\n<t> ::= [x]");
+        assert!(invalid(grammar.remove_unit_productions()));
+        let grammar = parse("<q> ::= [<t>]\n<q> ::= [y]\n<t> ::= [x] if p");
+        assert!(invalid(grammar.remove_unit_productions()));
+
+        let grammar = parse("<a> ::= [<a>, x] if p\n<a> ::= [y]");
+        assert!(invalid(grammar.eliminate_left_recursion()));
+        let grammar = parse("<a> ::= [<b>, x]\n<a> ::= [y]\n<b> ::= [<a>, z] if p\n<b> ::= [w]");
+        assert!(invalid(grammar.eliminate_left_recursion()));
+
+        let grammar = parse("<q> ::= [a, <t>]\n<t> ::= [x] if p\n<t> ::= [y]");
+        assert!(invalid(grammar.inline_single_use()));
+
+        let grammar = parse("<q> ::= [a, b] if p\n<q> ::= [a, c]");
+        assert!(invalid(grammar.factor_prefixes()));
+
+        // Guarded productions the transforms leave in place keep their guard
+        let grammar = parse("<q> ::= [a, b] if p\n<q> ::= [c, <t>]\n<t> ::= [x]");
+        for transformed in [grammar.factor_prefixes(), grammar.to_cnf()] {
+            let transformed = transformed.unwrap();
+            let guards: Vec<_> = transformed.grammar().rules()["q"]
+                .iter()
+                .filter_map(|production| production.guard.as_deref())
+                .collect();
+            assert_eq!(guards, ["p"]);
+        }
+    }
+
+    #[test]
+    fn test_chained_transforms() {
+        let grammar = join_grammar();
+        let transformed = Transformed::new(&grammar)
+            .eliminate_left_recursion()
+            .unwrap()
+            .inline_single_use()
+            .unwrap()
+            .factor_prefixes()
+            .unwrap()
+            .to_cnf()
+            .unwrap();
+        assert_round_trips(&grammar, &transformed, "query");
+    }
+}
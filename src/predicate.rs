@@ -0,0 +1,83 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::grammar::{Grammar, QueryAstNode};
+use crate::render::Piece;
+use crate::symbol_table::SymbolTable;
+
+/// A named check guarding a production, registered with `Grammar::add_predicate`
+#[derive(Clone)]
+pub struct Predicate(Arc<dyn Fn(&PredicateContext) -> bool + Send + Sync>);
+
+impl Predicate {
+    /// Wrap a closure as a predicate
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&PredicateContext) -> bool + Send + Sync + 'static,
+    {
+        Predicate(Arc::new(check))
+    }
+
+    /// Evaluate the predicate
+    pub fn check(&self, context: &PredicateContext) -> bool {
+        (self.0)(context)
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Predicate(..)")
+    }
+}
+
+/// The partial derivation a predicate is evaluated on.
+///
+/// A guarded production is checked right after it has been expanded, so the
+/// context holds its complete subtree plus everything generated before it.
+pub struct PredicateContext<'a> {
+    pub(crate) grammar: &'a Grammar,
+    pub(crate) symbol: &'a str,
+    pub(crate) production: usize,
+    pub(crate) node: &'a QueryAstNode,
+    pub(crate) tokens: &'a [Piece],
+    pub(crate) start: usize,
+    pub(crate) path: &'a [&'a str],
+    pub(crate) symbols: &'a SymbolTable,
+}
+
+impl PredicateContext<'_> {
+    /// The non-terminal whose production is being checked
+    pub fn symbol(&self) -> &str {
+        self.symbol
+    }
+
+    /// Index of the chosen production among the symbol's productions
+    pub fn production(&self) -> usize {
+        self.production
+    }
+
+    /// The subtree generated by the production
+    pub fn node(&self) -> &QueryAstNode {
+        self.node
+    }
+
+    /// Text generated by the production
+    pub fn text(&self) -> String {
+        self.grammar.render_pieces(&self.tokens[self.start..])
+    }
+
+    /// Text generated so far, including this production
+    pub fn generated_text(&self) -> String {
+        self.grammar.render_pieces(self.tokens)
+    }
+
+    /// Non-terminals currently being expanded, outermost first, excluding `symbol`
+    pub fn ancestors(&self) -> &[&str] {
+        self.path
+    }
+
+    /// Variables bound so far and visible from this production
+    pub fn symbols(&self) -> &SymbolTable {
+        self.symbols
+    }
+}
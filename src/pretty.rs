@@ -0,0 +1,491 @@
+use crate::grammar::QueryAst;
+use crate::render::{self, Piece, Renderer, RuleRenderer};
+use crate::token::{QueryToken, TokenClass};
+
+/// Keywords that start a clause on a new line
+const CLAUSES: &[&str] = &[
+    "WITH",
+    "SELECT",
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "VALUES",
+    "SET",
+    "FROM",
+    "JOIN",
+    "INNER",
+    "LEFT",
+    "RIGHT",
+    "FULL",
+    "CROSS",
+    "NATURAL",
+    "WHERE",
+    "GROUP",
+    "HAVING",
+    "ORDER",
+    "LIMIT",
+    "OFFSET",
+    "UNION",
+    "INTERSECT",
+    "EXCEPT",
+    "RETURNING",
+];
+
+/// Keywords that continue the clause keyword before them, as in `GROUP BY`
+/// or `LEFT OUTER JOIN`
+const CONTINUATIONS: &[&str] = &[
+    "BY", "JOIN", "OUTER", "INNER", "ALL", "DISTINCT", "INTO", "FROM",
+];
+
+/// Layout options for [`SqlFormatter`]
+#[derive(Debug, Clone)]
+pub struct FormatConfig {
+    /// Spaces per level of indentation
+    pub indent_width: usize,
+    /// Upper-case terminals classed as keywords
+    pub uppercase_keywords: bool,
+    /// Clauses longer than this put each comma-separated item on its own line
+    pub line_width: usize,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig {
+            indent_width: 2,
+            uppercase_keywords: true,
+            line_width: 80,
+        }
+    }
+}
+
+/// Lays out generated SQL one clause per line, with subqueries indented.
+///
+/// Formatting works on the token stream, so clause keywords must be classed
+/// as keywords, either tagged in the grammar or written in upper case.
+/// Tokens within a line are joined by the renderer, the default one unless
+/// set with [`SqlFormatter::with_renderer`].
+#[derive(Debug, Clone)]
+pub struct SqlFormatter {
+    config: FormatConfig,
+    renderer: Box<dyn Renderer>,
+}
+
+impl Default for SqlFormatter {
+    fn default() -> Self {
+        SqlFormatter::new(FormatConfig::default())
+    }
+}
+
+/// Part of a clause item
+enum Part {
+    Token(String),
+    /// A parenthesised query after its opening token, formatted on its own lines
+    Subquery(String, Vec<Clause>),
+}
+
+/// A clause keyword and the comma-separated items following it
+#[derive(Default)]
+struct Clause {
+    head: Vec<String>,
+    items: Vec<Vec<Part>>,
+}
+
+impl SqlFormatter {
+    /// Create a formatter with the given options
+    pub fn new(config: FormatConfig) -> Self {
+        SqlFormatter {
+            config,
+            renderer: Box::new(RuleRenderer::default()),
+        }
+    }
+
+    /// Set the renderer that joins tokens within a line, usually the
+    /// grammar's own
+    pub fn with_renderer(mut self, renderer: Box<dyn Renderer>) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Format a generated query.
+    ///
+    /// The tokens come from the derivation tree, keeping their classes,
+    /// unless a validator rewrote the text. Then the final text is split into
+    /// words, quoted strings and punctuation instead, so the rewrite is kept.
+    pub fn format(&self, ast: &QueryAst) -> String {
+        let tokens = ast.tokens();
+        let squeezed = |text: &str| text.split_whitespace().collect::<String>();
+        let from_tree: String = tokens.iter().map(|token| squeezed(&token.text)).collect();
+        if from_tree == squeezed(&ast.text) {
+            self.format_tokens(&tokens)
+        } else {
+            self.format_tokens(&lex(&ast.text))
+        }
+    }
+
+    /// Format a token stream
+    pub fn format_tokens(&self, tokens: &[QueryToken]) -> String {
+        let mut parser = Structure {
+            tokens,
+            next: 0,
+            in_quotes: false,
+            uppercase: self.config.uppercase_keywords,
+        };
+
+        let mut clauses = Vec::new();
+        while parser.next < tokens.len() {
+            clauses.extend(parser.statement());
+            // A stray `)` ends a statement early; keep it and carry on
+            if let Some(token) = parser.advance() {
+                clauses.push(Clause {
+                    head: Vec::new(),
+                    items: vec![vec![Part::Token(token)]],
+                });
+            }
+        }
+
+        self.statement(&clauses, 0)
+    }
+
+    fn indent(&self, depth: usize) -> String {
+        " ".repeat(depth * self.config.indent_width)
+    }
+
+    fn statement(&self, clauses: &[Clause], depth: usize) -> String {
+        clauses
+            .iter()
+            .map(|clause| self.clause(clause, depth))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn clause(&self, clause: &Clause, depth: usize) -> String {
+        let indent = self.indent(depth);
+        let head = self.inline(clause.head.iter().cloned().map(Piece::Token));
+        if clause.items.is_empty() {
+            return format!("{}{}", indent, head);
+        }
+
+        let prefix = if head.is_empty() {
+            indent.clone()
+        } else {
+            format!("{}{} ", indent, head)
+        };
+
+        let items: Vec<String> = clause
+            .items
+            .iter()
+            .map(|item| self.item(item, depth))
+            .collect();
+        let body = items.join(", ");
+        let fits = prefix.len() + body.len() <= self.config.line_width && !body.contains('\n');
+        if items.len() == 1 || fits {
+            return format!("{}{}", prefix, body);
+        }
+
+        // One item per line, one level deeper
+        let items: Vec<String> = clause
+            .items
+            .iter()
+            .map(|item| format!("{}{}", self.indent(depth + 1), self.item(item, depth + 1)))
+            .collect();
+        let items = items.join(",\n");
+        if head.is_empty() {
+            items
+        } else {
+            format!("{}{}\n{}", indent, head, items)
+        }
+    }
+
+    /// An item on a line indented `depth` levels
+    fn item(&self, item: &[Part], depth: usize) -> String {
+        self.inline(item.iter().map(|part| match part {
+            Part::Token(token) => Piece::Token(token.clone()),
+            Part::Subquery(open, clauses) => Piece::Token(format!(
+                "{}\n{}\n{})",
+                open,
+                self.statement(clauses, depth + 1),
+                self.indent(depth)
+            )),
+        }))
+    }
+
+    /// Join tokens on one line with the renderer's spacing rules
+    fn inline(&self, pieces: impl Iterator<Item = Piece>) -> String {
+        render::render(self.renderer.as_ref(), true, &pieces.collect::<Vec<_>>())
+    }
+}
+
+/// Split query text into tokens: quoted strings, the punctuation `(`, `)`,
+/// `,` and `;`, and runs of anything else. A `(` right after a word stays
+/// on it, as in `COUNT(`.
+fn lex(text: &str) -> Vec<QueryToken> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let mut token = String::new();
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '\'' | '"' => {
+                token.push(c);
+                chars.next();
+                while let Some(next) = chars.next() {
+                    token.push(next);
+                    // A doubled quote is an escaped one
+                    if next == c && chars.next_if_eq(&c).map(|q| token.push(q)).is_none() {
+                        break;
+                    }
+                }
+            }
+            '(' | ')' | ',' | ';' => {
+                token.push(c);
+                chars.next();
+            }
+            _ => {
+                while let Some(next) =
+                    chars.next_if(|&next| !next.is_whitespace() && !"(),;'\"".contains(next))
+                {
+                    token.push(next);
+                }
+                token.extend(chars.next_if_eq(&'('));
+            }
+        }
+        let class = TokenClass::infer(&token);
+        tokens.push(QueryToken { text: token, class });
+    }
+    tokens
+}
+
+/// Groups a token stream into clauses and subqueries
+struct Structure<'t> {
+    tokens: &'t [QueryToken],
+    next: usize,
+    in_quotes: bool,
+    uppercase: bool,
+}
+
+impl Structure<'_> {
+    /// The next token's text, with keywords cased as configured
+    fn advance(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.next)?;
+        self.next += 1;
+        if token.text == "'" || token.text == "\"" {
+            self.in_quotes = !self.in_quotes;
+        }
+        Some(if self.uppercase && token.class == TokenClass::Keyword {
+            token.text.to_uppercase()
+        } else {
+            token.text.clone()
+        })
+    }
+
+    /// The first word of the next token if it is a keyword outside quotes
+    fn keyword(&self) -> Option<String> {
+        let token = self.tokens.get(self.next)?;
+        if self.in_quotes || token.class != TokenClass::Keyword {
+            return None;
+        }
+        token.text.split_whitespace().next().map(str::to_uppercase)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(|token| token.text.as_str())
+    }
+
+    /// Parse clauses up to the end of input or an unmatched `)`
+    fn statement(&mut self) -> Vec<Clause> {
+        let mut clauses: Vec<Clause> = Vec::new();
+        let mut clause = Clause::default();
+        let mut item = Vec::new();
+
+        loop {
+            let quoted = self.in_quotes;
+            match self.peek() {
+                None => break,
+                Some(")") if !quoted => break,
+                Some(",") if !quoted => {
+                    self.advance();
+                    clause.items.push(std::mem::take(&mut item));
+                    continue;
+                }
+                Some(token) if !quoted && token.ends_with('(') => {
+                    item.extend(self.group());
+                    continue;
+                }
+                _ => {}
+            }
+
+            match self.keyword() {
+                Some(keyword) if CLAUSES.contains(&keyword.as_str()) => {
+                    let continues = clause.items.is_empty()
+                        && item.is_empty()
+                        && !clause.head.is_empty()
+                        && CONTINUATIONS.contains(&keyword.as_str());
+                    if !continues {
+                        if !item.is_empty() {
+                            clause.items.push(std::mem::take(&mut item));
+                        }
+                        if !clause.head.is_empty() || !clause.items.is_empty() {
+                            clauses.push(std::mem::take(&mut clause));
+                        }
+                    }
+                    clause.head.extend(self.advance());
+                }
+                Some(keyword)
+                    if clause.items.is_empty()
+                        && item.is_empty()
+                        && !clause.head.is_empty()
+                        && CONTINUATIONS.contains(&keyword.as_str()) =>
+                {
+                    clause.head.extend(self.advance());
+                }
+                _ => item.extend(self.advance().map(Part::Token)),
+            }
+        }
+
+        if !item.is_empty() {
+            clause.items.push(item);
+        }
+        if !clause.head.is_empty() || !clause.items.is_empty() {
+            clauses.push(clause);
+        }
+        clauses
+    }
+
+    /// A group opened by a token ending in `(`: a subquery, or the parts of
+    /// an expression up to and including the matching `)`
+    fn group(&mut self) -> Vec<Part> {
+        let Some(open) = self.advance() else {
+            return Vec::new();
+        };
+
+        if self
+            .keyword()
+            .is_some_and(|keyword| keyword == "SELECT" || keyword == "WITH")
+        {
+            let clauses = self.statement();
+            self.advance(); // Consume ')'
+            vec![Part::Subquery(open, clauses)]
+        } else {
+            let mut parts = vec![Part::Token(open)];
+            parts.extend(self.parenthesised());
+            parts
+        }
+    }
+
+    /// Parts of a parenthesised expression after its opening token, up to
+    /// and including the matching `)`
+    fn parenthesised(&mut self) -> Vec<Part> {
+        let mut parts = Vec::new();
+        loop {
+            let quoted = self.in_quotes;
+            match self.peek() {
+                None => break,
+                Some(")") if !quoted => {
+                    parts.extend(self.advance().map(Part::Token));
+                    break;
+                }
+                Some(token) if !quoted && token.ends_with('(') => parts.extend(self.group()),
+                _ => parts.extend(self.advance().map(Part::Token)),
+            }
+        }
+        parts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<QueryToken> {
+        text.split(' ')
+            .map(|text| QueryToken {
+                text: text.to_string(),
+                class: TokenClass::infer(text),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_format_clauses_and_subqueries() {
+        let formatter = SqlFormatter::default();
+        let text = "SELECT a , COUNT( * ) FROM t LEFT OUTER JOIN u ON a = b \
+                    WHERE a IN ( SELECT id FROM v WHERE x = ' FROM ( ' ) \
+                    GROUP BY a ORDER BY 2 LIMIT 5";
+
+        assert_eq!(
+            formatter.format_tokens(&tokens(text)),
+            "SELECT a, COUNT(*)\n\
+             FROM t\n\
+             LEFT OUTER JOIN u ON a = b\n\
+             WHERE a IN (\n  \
+               SELECT id\n  \
+               FROM v\n  \
+               WHERE x = 'FROM('\n\
+             )\n\
+             GROUP BY a\n\
+             ORDER BY 2\n\
+             LIMIT 5"
+        );
+    }
+
+    #[test]
+    fn test_wrap_long_lists() {
+        let formatter = SqlFormatter::new(FormatConfig {
+            indent_width: 4,
+            line_width: 30,
+            ..FormatConfig::default()
+        });
+        let text = "WITH c AS ( SELECT 1 ) SELECT first_name , last_name , email FROM c";
+
+        assert_eq!(
+            formatter.format_tokens(&tokens(text)),
+            "WITH c AS (\n    SELECT 1\n)\n\
+             SELECT\n    first_name,\n    last_name,\n    email\n\
+             FROM c"
+        );
+    }
+
+    #[test]
+    fn test_format_keeps_validator_rewrites() {
+        let mut grammar = crate::Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "COUNT(", "*", ")", "FROM", "t"])
+            .unwrap()
+            .add_rule(
+                "query",
+                vec!["WHERE", "x", "=", "NULL", "AND", "y", "=", "'a b'"],
+            )
+            .unwrap();
+        let formatter = SqlFormatter::default();
+
+        let mut ast = grammar.generate("query");
+        assert_eq!(
+            formatter.format(&ast),
+            formatter.format_tokens(&ast.tokens())
+        );
+
+        ast.text = "SELECT COUNT(*) FROM t WHERE x IS NULL AND y = 'it''s'".to_string();
+        assert_eq!(
+            formatter.format(&ast),
+            "SELECT COUNT(*)\nFROM t\nWHERE x IS NULL AND y = 'it''s'"
+        );
+    }
+
+    #[test]
+    fn test_renderer_joins_tokens() {
+        let renderer = RuleRenderer {
+            no_space_after: vec!["(".to_string(), ".".to_string()],
+            no_space_before: vec![")".to_string(), ",".to_string(), ".".to_string()],
+            ..RuleRenderer::default()
+        };
+        let formatter = SqlFormatter::default().with_renderer(Box::new(renderer));
+
+        assert_eq!(
+            formatter.format_tokens(&tokens("SELECT t . a FROM t")),
+            "SELECT t.a\nFROM t"
+        );
+    }
+}
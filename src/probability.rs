@@ -0,0 +1,208 @@
+use crate::earley::EarleyParser;
+use crate::grammar::{Grammar, QueryAstNode};
+use crate::utils::{GrammarError, Result};
+
+/// Natural log of the sum of the exponentials of `values`, without
+/// underflowing when they are all very negative
+pub(crate) fn log_sum_exp(values: impl Iterator<Item = f64> + Clone) -> f64 {
+    let max = values.clone().fold(f64::NEG_INFINITY, f64::max);
+    if max.is_infinite() {
+        return max;
+    }
+    max + values.map(|value| (value - max).exp()).sum::<f64>().ln()
+}
+
+impl Grammar {
+    /// Natural log of the probability that generation makes the production
+    /// choices of the derivation tree rooted at `node`.
+    ///
+    /// Each choice is weighted like generation weights it, by the most
+    /// specific context weights for its position in its parent's production
+    /// or else by the production weights. Predicates are ignored, and nodes
+    /// without a recorded production, such as captured variables, add
+    /// nothing.
+    pub fn log_probability(&self, node: &QueryAstNode) -> Result<f64> {
+        self.tree_log_probability(node, None)
+    }
+
+    /// The probability of the derivation tree rooted at `node`; see
+    /// [`Grammar::log_probability`]
+    pub fn probability(&self, node: &QueryAstNode) -> Result<f64> {
+        Ok(self.log_probability(node)?.exp())
+    }
+
+    /// Natural log of the total probability of every derivation of `text`
+    /// as `start_symbol`; see [`EarleyParser::log_probability`]
+    pub fn text_log_probability(&self, start_symbol: &str, text: &str) -> Result<f64> {
+        EarleyParser::new(self)?.log_probability(start_symbol, text)
+    }
+
+    fn tree_log_probability(
+        &self,
+        node: &QueryAstNode,
+        within: Option<(&str, usize, usize)>,
+    ) -> Result<f64> {
+        let production = match node.production {
+            Some(production) if node.element_type == "non_terminal" => production,
+            _ => return self.children_log_probability(node, None),
+        };
+        let Some(productions) = self.rules().get(&node.value) else {
+            return Err(GrammarError::UnknownNonTerminal(node.value.clone()));
+        };
+        if production >= productions.len() {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "<{}> has no production #{}",
+                node.value, production
+            )));
+        }
+
+        let choice = self.choice_log_probability(&node.value, production, within);
+        Ok(choice + self.children_log_probability(node, Some(production))?)
+    }
+
+    /// Each element of a production adds exactly one child, so a child's
+    /// index is its position in the production
+    fn children_log_probability(
+        &self,
+        node: &QueryAstNode,
+        production: Option<usize>,
+    ) -> Result<f64> {
+        let mut total = 0.0;
+        for (position, child) in node.children.iter().enumerate() {
+            let within = production.map(|production| (node.value.as_str(), production, position));
+            total += self.tree_log_probability(child, within)?;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weights::WeightContext;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_log_sum_exp() {
+        assert!(close(log_sum_exp([0.0, 0.0].into_iter()), 2f64.ln()));
+        assert!(close(
+            log_sum_exp([-1000.0, -1000.0].into_iter()),
+            -1000.0 + 2f64.ln()
+        ));
+        assert_eq!(log_sum_exp(std::iter::empty()), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_tree_and_text_probability_agree() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<columns>", "FROM", "t"])
+            .unwrap()
+            .add_rule("columns", vec!["<column>"])
+            .unwrap()
+            .add_rule("columns", vec!["<column>", ",", "<columns>"])
+            .unwrap()
+            .add_rule("column", vec!["id"])
+            .unwrap()
+            .add_rule("column", vec!["name"])
+            .unwrap()
+            .add_rule("column", vec!["age"])
+            .unwrap();
+        grammar.set_weights("columns", &[3.0, 1.0]).unwrap();
+
+        let tree = grammar.parse("query", "SELECT id, age FROM t").unwrap();
+        let expected = (0.25f64 * 0.75 / 9.0).ln();
+        assert!(close(grammar.log_probability(&tree).unwrap(), expected));
+        assert!(close(
+            grammar
+                .text_log_probability("query", "SELECT id, age FROM t")
+                .unwrap(),
+            expected
+        ));
+        assert!(close(grammar.probability(&tree).unwrap(), expected.exp()));
+        assert!(
+            grammar
+                .text_log_probability("query", "SELECT FROM t")
+                .is_err()
+        );
+
+        let mut unknown = tree.clone();
+        unknown.children[1].production = Some(5);
+        assert!(grammar.log_probability(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_text_probability_sums_derivations() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("expr", vec!["<expr>", "+", "<expr>"])
+            .unwrap()
+            .add_rule("expr", vec!["x"])
+            .unwrap()
+            .add_rule("wrapped", vec!["<inner>"])
+            .unwrap()
+            .add_rule("inner", vec!["<wrapped>"])
+            .unwrap()
+            .add_rule("inner", vec!["x"])
+            .unwrap();
+
+        // Two bracketings, each with two splits and three leaves
+        let derivation = 0.5f64.powi(5);
+        let total = grammar.text_log_probability("expr", "x + x + x").unwrap();
+        assert!(close(total, (2.0 * derivation).ln()));
+
+        // A unit cycle gives infinitely many derivations, 1/2 + 1/4 + ...
+        let total = grammar.text_log_probability("wrapped", "x").unwrap();
+        assert!(close(total, 0.0));
+
+        // ... however slowly the series converges
+        grammar.set_weights("inner", &[999.0, 1.0]).unwrap();
+        let total = grammar.text_log_probability("wrapped", "x").unwrap();
+        assert!(total.abs() < 1e-6, "{}", total);
+
+        // A series too slow to sum is an error, not a truncated sum
+        grammar.set_weights("inner", &[1e9, 1.0]).unwrap();
+        assert!(grammar.text_log_probability("wrapped", "x").is_err());
+    }
+
+    #[test]
+    fn test_context_weights_are_respected() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["<value>", "=", "<value>"])
+            .unwrap()
+            .add_rule("value", vec!["1"])
+            .unwrap()
+            .add_rule("value", vec!["x"])
+            .unwrap()
+            .set_context_weights(
+                WeightContext::production("query", 0).at(2),
+                "value",
+                &[1.0, 3.0],
+            )
+            .unwrap();
+
+        let text = "x = x";
+        let expected = (0.5f64 * 0.75).ln();
+        let tree = grammar.parse("query", text).unwrap();
+        assert!(close(grammar.log_probability(&tree).unwrap(), expected));
+        assert!(close(
+            grammar.text_log_probability("query", text).unwrap(),
+            expected
+        ));
+
+        // A production added after the context weights were set is weighted
+        // in them with its unconditioned weight
+        grammar.add_rule("value", vec!["c"]).unwrap();
+        let expected = (1.0f64 / 3.0 * 1.0 / 5.0).ln();
+        assert!(close(
+            grammar.text_log_probability("query", "c = c").unwrap(),
+            expected
+        ));
+        let tree = grammar.parse("query", "c = c").unwrap();
+        assert!(close(grammar.log_probability(&tree).unwrap(), expected));
+    }
+}
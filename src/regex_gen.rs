@@ -0,0 +1,282 @@
+use rand::Rng;
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+use std::fmt;
+
+use crate::utils::{GrammarError, Result};
+
+/// Generates random strings that match a regular expression.
+///
+/// The pattern is parsed once with `regex-syntax` and the resulting HIR is
+/// walked on every call to [`RegexGenerator::generate`]. Unbounded
+/// repetitions (`*`, `+`, `{n,}`) are capped so output stays finite.
+#[derive(Clone)]
+pub struct RegexGenerator {
+    pattern: String,
+    hir: Hir,
+}
+
+impl RegexGenerator {
+    /// Compile a pattern into a generator
+    pub fn new(pattern: &str) -> Result<Self> {
+        let hir = regex_syntax::ParserBuilder::new()
+            .build()
+            .parse(pattern)
+            .map_err(|e| GrammarError::Parse(format!("Invalid regex /{}/: {}", pattern, e)))?;
+
+        check_supported(&hir, true, true).map_err(|reason| {
+            GrammarError::Parse(format!("Unsupported regex /{}/: {}", pattern, reason))
+        })?;
+
+        Ok(RegexGenerator {
+            pattern: pattern.to_string(),
+            hir,
+        })
+    }
+
+    /// The source pattern, without the surrounding slashes
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Generate a random string matching the pattern.
+    ///
+    /// `max_repeat` bounds how many repetitions beyond the minimum an
+    /// unbounded quantifier may produce.
+    pub fn generate<R: Rng + ?Sized>(&self, rng: &mut R, max_repeat: u32) -> String {
+        let mut out = String::new();
+        generate_hir(&self.hir, rng, max_repeat, &mut out);
+        out
+    }
+
+    /// The shortest string matching the pattern: every repetition repeats
+    /// its minimum count, alternations take their shortest branch and
+    /// classes their lowest code point. Unlike [`RegexGenerator::generate`]
+    /// this is deterministic.
+    pub fn shortest(&self) -> String {
+        let mut out = String::new();
+        shortest_hir(&self.hir, &mut out);
+        out
+    }
+}
+
+impl PartialEq for RegexGenerator {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl fmt::Debug for RegexGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RegexGenerator(/{}/)", self.pattern)
+    }
+}
+
+/// Reject constructs we cannot guarantee to satisfy by construction.
+///
+/// `at_start` and `at_end` tell whether `hir` can only match at the start or
+/// end of the generated text, where anchors hold trivially since a terminal
+/// is generated as a whole. Anywhere else an anchor could never match.
+fn check_supported(hir: &Hir, at_start: bool, at_end: bool) -> std::result::Result<(), String> {
+    match hir.kind() {
+        HirKind::Look(look) => match look {
+            Look::Start | Look::StartLF | Look::StartCRLF if at_start => Ok(()),
+            Look::End | Look::EndLF | Look::EndCRLF if at_end => Ok(()),
+            Look::Start
+            | Look::End
+            | Look::StartLF
+            | Look::EndLF
+            | Look::StartCRLF
+            | Look::EndCRLF => Err(format!(
+                "anchor {:?} is only supported at the start or end of the pattern",
+                look
+            )),
+            _ => Err(format!("look-around assertion {:?} is not supported", look)),
+        },
+        HirKind::Class(Class::Bytes(class)) => {
+            if class.ranges().iter().any(|r| r.start() < 0x80) {
+                Ok(())
+            } else {
+                Err("byte class without ASCII members".to_string())
+            }
+        }
+        HirKind::Class(Class::Unicode(class)) if class.ranges().is_empty() => {
+            Err("empty character class".to_string())
+        }
+        HirKind::Repetition(rep) => check_supported(&rep.sub, false, false),
+        HirKind::Capture(cap) => check_supported(&cap.sub, at_start, at_end),
+        HirKind::Concat(subs) => subs.iter().enumerate().try_for_each(|(i, sub)| {
+            check_supported(sub, at_start && i == 0, at_end && i == subs.len() - 1)
+        }),
+        HirKind::Alternation(subs) => subs
+            .iter()
+            .try_for_each(|sub| check_supported(sub, at_start, at_end)),
+        _ => Ok(()),
+    }
+}
+
+fn generate_hir<R: Rng + ?Sized>(hir: &Hir, rng: &mut R, max_repeat: u32, out: &mut String) {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Literal(literal) => out.push_str(&String::from_utf8_lossy(&literal.0)),
+        HirKind::Class(Class::Unicode(class)) => {
+            let ranges = class.ranges();
+            let total: u64 = ranges
+                .iter()
+                .map(|r| u64::from(r.end() as u32 - r.start() as u32) + 1)
+                .sum();
+
+            // Pick a code point uniformly across the whole class, retrying
+            // when we land on a surrogate that char cannot represent.
+            loop {
+                let mut offset = rng.gen_range(0..total);
+                for range in ranges {
+                    let size = u64::from(range.end() as u32 - range.start() as u32) + 1;
+                    if offset < size {
+                        if let Some(c) = char::from_u32(range.start() as u32 + offset as u32) {
+                            out.push(c);
+                            return;
+                        }
+                        break;
+                    }
+                    offset -= size;
+                }
+            }
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            // Only ASCII bytes are emitted so the output stays valid UTF-8
+            let ascii: Vec<(u8, u8)> = class
+                .ranges()
+                .iter()
+                .filter(|r| r.start() < 0x80)
+                .map(|r| (r.start(), r.end().min(0x7f)))
+                .collect();
+            let (start, end) = ascii[rng.gen_range(0..ascii.len())];
+            out.push(rng.gen_range(start..=end) as char);
+        }
+        HirKind::Repetition(rep) => {
+            let max = rep.max.unwrap_or(rep.min.saturating_add(max_repeat));
+            let count = rng.gen_range(rep.min..=max);
+            for _ in 0..count {
+                generate_hir(&rep.sub, rng, max_repeat, out);
+            }
+        }
+        HirKind::Capture(cap) => generate_hir(&cap.sub, rng, max_repeat, out),
+        HirKind::Concat(subs) => {
+            for sub in subs {
+                generate_hir(sub, rng, max_repeat, out);
+            }
+        }
+        HirKind::Alternation(subs) => {
+            let sub = &subs[rng.gen_range(0..subs.len())];
+            generate_hir(sub, rng, max_repeat, out);
+        }
+    }
+}
+
+fn shortest_hir(hir: &Hir, out: &mut String) {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Literal(literal) => out.push_str(&String::from_utf8_lossy(&literal.0)),
+        HirKind::Class(Class::Unicode(class)) => out.push(class.ranges()[0].start()),
+        // Ranges are sorted and at least one starts in ASCII
+        HirKind::Class(Class::Bytes(class)) => out.push(class.ranges()[0].start() as char),
+        HirKind::Repetition(rep) => {
+            for _ in 0..rep.min {
+                shortest_hir(&rep.sub, out);
+            }
+        }
+        HirKind::Capture(cap) => shortest_hir(&cap.sub, out),
+        HirKind::Concat(subs) => {
+            for sub in subs {
+                shortest_hir(sub, out);
+            }
+        }
+        HirKind::Alternation(subs) => {
+            let branches = subs.iter().map(|sub| {
+                let mut branch = String::new();
+                shortest_hir(sub, &mut branch);
+                branch
+            });
+            if let Some(branch) = branches.min_by_key(|branch| branch.chars().count()) {
+                out.push_str(&branch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn assert_generates_matches(pattern: &str) {
+        let generator = RegexGenerator::new(pattern).unwrap();
+        let re = Regex::new(&format!("^(?:{})$", pattern)).unwrap();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..200 {
+            let text = generator.generate(&mut rng, 8);
+            assert!(re.is_match(&text), "/{}/ generated {:?}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn test_generated_text_matches_pattern() {
+        for pattern in [
+            r"[a-z_][a-z0-9_]*",
+            r"0x[0-9A-Fa-f]{1,8}",
+            r"'([^'\\]|\\.)*'",
+            r"(SELECT|INSERT|UPDATE)\s+\d+",
+            r"(?i)select",
+            r"\p{Greek}+",
+            r"[^a-z]?x{2,}",
+            r"^\w+@\w+\.(com|org)$",
+        ] {
+            assert_generates_matches(pattern);
+        }
+    }
+
+    #[test]
+    fn test_unbounded_repetition_is_capped() {
+        let generator = RegexGenerator::new("a*").unwrap();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..100 {
+            assert!(generator.generate(&mut rng, 3).len() <= 3);
+        }
+    }
+
+    #[test]
+    fn test_shortest_match() {
+        for (pattern, expected) in [
+            (r"0x[0-9A-F]{1,8}", "0x0"),
+            (r"[a-z_][a-z0-9_]*", "_"),
+            (r"(SELECT|AS|INSERT)\s+\d+", "AS\t0"),
+            (r"(?i)select", "SELECT"),
+            (r"^(ab|c)?d{2,}$", "dd"),
+        ] {
+            let generator = RegexGenerator::new(pattern).unwrap();
+            assert_eq!(generator.shortest(), expected, "/{}/", pattern);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_patterns_are_rejected() {
+        assert!(RegexGenerator::new(r"\bword\b").is_err());
+        assert!(RegexGenerator::new(r"[a-z").is_err());
+    }
+
+    #[test]
+    fn test_anchors_only_at_the_edges() {
+        for pattern in [r"a^b", r"a$b", r"(a^)b", r"(^a)*", r"(?m)a^b"] {
+            assert!(
+                matches!(RegexGenerator::new(pattern), Err(GrammarError::Parse(_))),
+                "/{}/",
+                pattern
+            );
+        }
+        for pattern in [r"^ab$", r"(^a|b$)", r"^(a|b)c$", r"(?m)^a$"] {
+            assert_generates_matches(pattern);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::utils::GrammarError;
+
+/// A layout directive placed between elements of a production
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Layout {
+    /// `~`: no space between the neighbouring terminals
+    Glue,
+    /// `@space`: exactly one space, whatever the spacing rules say
+    Space,
+    /// `@newline`: a line break followed by the current indentation
+    Newline,
+    /// `@indent`: indent following lines one level more
+    Indent,
+    /// `@dedent`: indent following lines one level less
+    Dedent,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Layout::Glue => "~",
+            Layout::Space => "@space",
+            Layout::Newline => "@newline",
+            Layout::Indent => "@indent",
+            Layout::Dedent => "@dedent",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl FromStr for Layout {
+    type Err = GrammarError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "~" => Ok(Layout::Glue),
+            "@space" => Ok(Layout::Space),
+            "@newline" => Ok(Layout::Newline),
+            "@indent" => Ok(Layout::Indent),
+            "@dedent" => Ok(Layout::Dedent),
+            _ => Err(GrammarError::Parse(format!(
+                "Unknown layout directive '{}'",
+                text
+            ))),
+        }
+    }
+}
+
+/// An item of output: a terminal or a layout directive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Token(String),
+    Layout(Layout),
+}
+
+/// Decides how adjacent terminals are joined into text.
+///
+/// Layout directives from the grammar take precedence over the renderer,
+/// and terminals inside a quoted string are never spaced.
+pub trait Renderer: Send + Sync + fmt::Debug {
+    /// Whether a space goes between two adjacent terminals
+    fn space_between(&self, prev: &str, next: &str) -> bool;
+
+    /// Whether a terminal opens or closes a quoted string
+    fn is_quote(&self, token: &str) -> bool {
+        token == "'" || token == "\""
+    }
+
+    /// Text for one level of indentation
+    fn indent(&self) -> &str {
+        "  "
+    }
+
+    /// Clone this renderer as a box
+    fn clone_box(&self) -> Box<dyn Renderer>;
+}
+
+impl Clone for Box<dyn Renderer> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// The default renderer: terminals are separated by a space except next to
+/// the configured punctuation
+#[derive(Debug, Clone)]
+pub struct RuleRenderer {
+    /// No space after a terminal ending with one of these
+    pub no_space_after: Vec<String>,
+    /// No space before a terminal starting with one of these
+    pub no_space_before: Vec<String>,
+    /// Text for one level of indentation
+    pub indent: String,
+}
+
+impl Default for RuleRenderer {
+    fn default() -> Self {
+        RuleRenderer {
+            no_space_after: vec!["(".to_string()],
+            no_space_before: vec![")".to_string(), ",".to_string()],
+            indent: "  ".to_string(),
+        }
+    }
+}
+
+impl Renderer for RuleRenderer {
+    fn space_between(&self, prev: &str, next: &str) -> bool {
+        !self
+            .no_space_after
+            .iter()
+            .any(|s| prev.ends_with(s.as_str()))
+            && !self
+                .no_space_before
+                .iter()
+                .any(|s| next.starts_with(s.as_str()))
+    }
+
+    fn indent(&self) -> &str {
+        &self.indent
+    }
+
+    fn clone_box(&self) -> Box<dyn Renderer> {
+        Box::new(self.clone())
+    }
+}
+
+/// Renders pieces one at a time, trimming the output as a whole by holding
+/// back whitespace until more text follows
+#[derive(Debug)]
+pub(crate) struct Render<'r> {
+    renderer: &'r dyn Renderer,
+    /// Consult the renderer; otherwise terminals are always separated by a space
+    auto_spacing: bool,
+    prev: Option<String>,
+    in_quotes: bool,
+    /// The strongest directive since the previous terminal
+    gap: Option<Layout>,
+    depth: usize,
+    started: bool,
+    held: String,
+}
+
+impl<'r> Render<'r> {
+    pub(crate) fn new(renderer: &'r dyn Renderer, auto_spacing: bool) -> Self {
+        Render {
+            renderer,
+            auto_spacing,
+            prev: None,
+            in_quotes: false,
+            gap: None,
+            depth: 0,
+            started: false,
+            held: String::new(),
+        }
+    }
+
+    /// Append `piece` to `out`
+    pub(crate) fn push(&mut self, piece: &Piece, out: &mut String) {
+        match piece {
+            Piece::Token(token) => self.push_token(token, out),
+            Piece::Layout(Layout::Indent) => self.depth += 1,
+            Piece::Layout(Layout::Dedent) => self.depth = self.depth.saturating_sub(1),
+            Piece::Layout(layout) => self.gap = self.gap.max(Some(*layout)),
+        }
+    }
+
+    fn push_token(&mut self, token: &str, out: &mut String) {
+        if let Some(prev) = &self.prev {
+            match self.gap.take() {
+                Some(Layout::Newline) => {
+                    let indent = self.renderer.indent().repeat(self.depth);
+                    self.write("\n", out);
+                    self.write(&indent, out);
+                }
+                Some(Layout::Space) => self.write(" ", out),
+                Some(_) => {}
+                None if self.in_quotes => {}
+                None => {
+                    if !self.auto_spacing || self.renderer.space_between(prev, token) {
+                        self.write(" ", out);
+                    }
+                }
+            }
+        }
+        self.gap = None;
+
+        if self.renderer.is_quote(token) {
+            self.in_quotes = !self.in_quotes;
+        }
+        self.write(token, out);
+        self.prev = Some(token.to_string());
+    }
+
+    fn write(&mut self, text: &str, out: &mut String) {
+        for c in text.chars() {
+            if c.is_whitespace() {
+                if self.started {
+                    self.held.push(c);
+                }
+            } else {
+                out.push_str(&self.held);
+                self.held.clear();
+                out.push(c);
+                self.started = true;
+            }
+        }
+    }
+}
+
+/// Render `pieces` into trimmed text
+pub fn render(renderer: &dyn Renderer, auto_spacing: bool, pieces: &[Piece]) -> String {
+    let mut render = Render::new(renderer, auto_spacing);
+    let mut out = String::new();
+    for piece in pieces {
+        render.push(piece, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pieces(items: &[&str]) -> Vec<Piece> {
+        items
+            .iter()
+            .map(|item| match item.parse() {
+                Ok(layout) => Piece::Layout(layout),
+                Err(_) => Piece::Token(item.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rule_renderer_spacing() {
+        let renderer = RuleRenderer::default();
+        let cases: &[(&[&str], &str)] = &[
+            (
+                &["SELECT", "COUNT(", "*", ")", ",", "id", "FROM", "t"],
+                "SELECT COUNT(*), id FROM t",
+            ),
+            (&[" x", "'", "a b", ",", "c", "'", ","], "x 'a b,c',"),
+            (&["", "  ", "a", " ", "b  "], "a   b"),
+            (&["a", "~", "b", "@space", "(", "@space", ")"], "ab ( )"),
+        ];
+
+        for (items, expected) in cases {
+            assert_eq!(render(&renderer, true, &pieces(items)), *expected);
+        }
+        assert_eq!(
+            render(&renderer, false, &pieces(&["f", "(", "x", ")", ",", "y"])),
+            "f ( x ) , y"
+        );
+    }
+
+    #[test]
+    fn test_newlines_and_indentation() {
+        let renderer = RuleRenderer::default();
+        let items = [
+            "SELECT", "@indent", "@newline", "a", ",", "@newline", "b", "@dedent", "@newline",
+            "FROM", "t", "@newline",
+        ];
+        assert_eq!(
+            render(&renderer, true, &pieces(&items)),
+            "SELECT\n  a,\n  b\nFROM t"
+        );
+    }
+}
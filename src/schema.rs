@@ -360,6 +360,12 @@ pub struct Schema {
     pub tables: Vec<Table>,
 }
 
+impl Default for Schema {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Schema {
     pub fn new() -> Self {
         Schema { tables: Vec::new() }
@@ -585,7 +591,7 @@ impl<D: SqlDialect> SqlGenerator<D> {
 pub fn load_common_column_types<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<String>>> {
     let content = fs::read_to_string(path).map_err(GrammarError::Io)?;
     let column_types: HashMap<String, Vec<String>> =
-        serde_json::from_str(&content).map_err(|e| GrammarError::Json(e))?;
+        serde_json::from_str(&content).map_err(GrammarError::Json)?;
     Ok(column_types)
 }
 
@@ -772,10 +778,10 @@ fn has_cycle(refs: &[(String, String)]) -> bool {
     let mut recursion_stack = HashSet::new();
 
     for node in graph.keys() {
-        if !visited.contains(node) {
-            if is_cyclic_util(node, &graph, &mut visited, &mut recursion_stack) {
-                return true;
-            }
+        if !visited.contains(node)
+            && is_cyclic_util(node, &graph, &mut visited, &mut recursion_stack)
+        {
+            return true;
         }
     }
 
@@ -1378,9 +1384,9 @@ mod tests {
 
             // Verify column types are valid
             for column in &table.columns {
-                match &column.sql_type {
-                    SqlType::Varchar(size) => assert!(*size > 0 && *size <= 255),
-                    _ => (), // Other types don't have size constraints
+                // Other types don't have size constraints
+                if let SqlType::Varchar(size) = &column.sql_type {
+                    assert!(*size > 0 && *size <= 255);
                 }
             }
         }
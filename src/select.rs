@@ -0,0 +1,598 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use crate::attribute::AttrValue;
+use crate::grammar::{QueryAst, QueryAstNode};
+use crate::utils::{GrammarError, Result};
+
+/// A compiled selector over AST nodes.
+///
+/// Steps are separated by whitespace (any descendant) or `>` (direct child).
+/// A step is `<symbol>` for a non-terminal, `"text"` for a terminal or `*`
+/// for any node, followed by any number of filters:
+///
+/// - `[name]`: the node has the attribute `name`
+/// - `[name=value]`: the attribute equals `value`, which may be quoted
+/// - `:nth(n)`, `:first`, `:last`: position among the step's matches under
+///   each node matched by the previous step, counting from 1
+///
+/// For example `<join_clause> <column_reference>:nth(2)` selects the second
+/// column reference in each join clause. Filters apply in the order written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    source: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Axis {
+    Descendant,
+    Child,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Any,
+    Symbol(String),
+    Terminal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Has(String),
+    Equals(String, String),
+    Nth(usize),
+    Last,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    axis: Axis,
+    test: Test,
+    filters: Vec<Filter>,
+}
+
+/// A node together with its child indices from the root
+type Located<'a> = (Vec<usize>, &'a QueryAstNode);
+
+impl Selector {
+    /// Compile a selector
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = SelectorParser {
+            source,
+            chars: source.chars().peekable(),
+        };
+
+        let mut steps = Vec::new();
+        let mut axis = Axis::Descendant;
+        loop {
+            let spaced = parser.skip_whitespace();
+            match parser.chars.peek() {
+                None => break,
+                Some('>') => {
+                    if steps.is_empty() || axis == Axis::Child {
+                        return Err(parser.error("'>' must follow a step"));
+                    }
+                    parser.chars.next();
+                    axis = Axis::Child;
+                    continue;
+                }
+                Some(_) if !steps.is_empty() && !spaced && axis == Axis::Descendant => {
+                    return Err(parser.error("expected whitespace or '>' between steps"));
+                }
+                Some(_) => {}
+            }
+            steps.push(parser.step(axis)?);
+            axis = Axis::Descendant;
+        }
+
+        if steps.is_empty() {
+            return Err(parser.error("empty selector"));
+        }
+        if axis == Axis::Child {
+            return Err(parser.error("'>' must be followed by a step"));
+        }
+
+        Ok(Selector {
+            source: source.to_string(),
+            steps,
+        })
+    }
+
+    /// Nodes below and including `root` that match, in document order
+    pub fn select<'a>(&self, root: &'a QueryAstNode) -> Vec<&'a QueryAstNode> {
+        self.locate(root)
+            .into_iter()
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    /// Matching nodes for modification, in document order.
+    ///
+    /// A match nested inside another match is left out, since the outer
+    /// match already gives access to it.
+    pub fn select_mut<'a>(&self, root: &'a mut QueryAstNode) -> Vec<&'a mut QueryAstNode> {
+        let paths = outermost(self.paths(root));
+        let mut result = Vec::new();
+        collect_mut(root, &mut Vec::new(), &paths, &mut result);
+        result
+    }
+
+    /// Replace every matching node with what `replace` returns and return
+    /// how many were replaced.
+    ///
+    /// Nested matches are replaced innermost first, so `replace` sees an
+    /// outer match with its inner matches already replaced.
+    pub fn replace<F>(&self, root: &mut QueryAstNode, mut replace: F) -> usize
+    where
+        F: FnMut(&QueryAstNode) -> QueryAstNode,
+    {
+        let paths = self.paths(root);
+        // Reverse document order visits descendants before their ancestors,
+        // and replacing a node leaves the paths of the others intact
+        for path in paths.iter().rev() {
+            let node = resolve_mut(root, path);
+            *node = replace(node);
+        }
+        paths.len()
+    }
+
+    /// Child-index paths of the matching nodes, in document order
+    fn paths(&self, root: &QueryAstNode) -> Vec<Vec<usize>> {
+        self.locate(root)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    fn locate<'a>(&self, root: &'a QueryAstNode) -> Vec<Located<'a>> {
+        let mut current: Vec<Located<'a>> = Vec::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            let mut next = Vec::new();
+            if i == 0 {
+                // The first step starts above the root, so the root can match
+                let candidates = match step.axis {
+                    Axis::Child => vec![(Vec::new(), root)],
+                    Axis::Descendant => {
+                        let mut nodes = vec![(Vec::new(), root)];
+                        descendants(root, &mut Vec::new(), &mut nodes);
+                        nodes
+                    }
+                };
+                next.extend(step.pick(candidates));
+            } else {
+                for (path, node) in &current {
+                    let candidates = match step.axis {
+                        Axis::Child => node
+                            .children
+                            .iter()
+                            .enumerate()
+                            .map(|(i, child)| (extend(path, i), child))
+                            .collect(),
+                        Axis::Descendant => {
+                            let mut nodes = Vec::new();
+                            descendants(node, &mut path.clone(), &mut nodes);
+                            nodes
+                        }
+                    };
+                    next.extend(step.pick(candidates));
+                }
+            }
+
+            next.sort_by(|a, b| a.0.cmp(&b.0));
+            next.dedup_by(|a, b| a.0 == b.0);
+            current = next;
+        }
+        current
+    }
+}
+
+impl FromStr for Selector {
+    type Err = GrammarError;
+
+    fn from_str(source: &str) -> Result<Self> {
+        Selector::parse(source)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Step {
+    /// Candidates that pass the test and every filter, in order
+    fn pick<'a>(&self, candidates: Vec<Located<'a>>) -> Vec<Located<'a>> {
+        let mut nodes: Vec<Located<'a>> = candidates
+            .into_iter()
+            .filter(|(_, node)| self.test.matches(node))
+            .collect();
+
+        for filter in &self.filters {
+            nodes = match filter {
+                Filter::Has(name) => nodes
+                    .into_iter()
+                    .filter(|(_, node)| node.attributes.contains_key(name))
+                    .collect(),
+                Filter::Equals(name, value) => nodes
+                    .into_iter()
+                    .filter(|(_, node)| {
+                        node.attribute(name)
+                            .is_some_and(|attr| attr_equals(attr, value))
+                    })
+                    .collect(),
+                Filter::Nth(n) => nodes.into_iter().nth(n - 1).into_iter().collect(),
+                Filter::Last => nodes.pop().into_iter().collect(),
+            };
+        }
+        nodes
+    }
+}
+
+impl Test {
+    fn matches(&self, node: &QueryAstNode) -> bool {
+        match self {
+            Test::Any => true,
+            Test::Symbol(name) => node.element_type == "non_terminal" && node.value == *name,
+            Test::Terminal(text) => node.element_type == "terminal" && node.value == *text,
+        }
+    }
+}
+
+/// Strings compare by content, other values by their display form
+fn attr_equals(attr: &AttrValue, value: &str) -> bool {
+    match attr {
+        AttrValue::Str(text) => text == value,
+        _ => attr.to_string() == value,
+    }
+}
+
+fn extend(path: &[usize], index: usize) -> Vec<usize> {
+    let mut path = path.to_vec();
+    path.push(index);
+    path
+}
+
+/// Every node strictly below `node`, in document order
+fn descendants<'a>(node: &'a QueryAstNode, path: &mut Vec<usize>, out: &mut Vec<Located<'a>>) {
+    for (i, child) in node.children.iter().enumerate() {
+        path.push(i);
+        out.push((path.clone(), child));
+        descendants(child, path, out);
+        path.pop();
+    }
+}
+
+/// Drop paths below another path in the list, which is in document order
+fn outermost(paths: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut result: Vec<Vec<usize>> = Vec::new();
+    for path in paths {
+        if !result.last().is_some_and(|outer| path.starts_with(outer)) {
+            result.push(path);
+        }
+    }
+    result
+}
+
+fn collect_mut<'a>(
+    node: &'a mut QueryAstNode,
+    path: &mut Vec<usize>,
+    paths: &[Vec<usize>],
+    out: &mut Vec<&'a mut QueryAstNode>,
+) {
+    if paths.contains(path) {
+        out.push(node);
+        return;
+    }
+    if !paths.iter().any(|p| p.starts_with(path)) {
+        return;
+    }
+    for (i, child) in node.children.iter_mut().enumerate() {
+        path.push(i);
+        collect_mut(child, path, paths, out);
+        path.pop();
+    }
+}
+
+fn resolve_mut<'a>(root: &'a mut QueryAstNode, path: &[usize]) -> &'a mut QueryAstNode {
+    path.iter().fold(root, |node, &i| &mut node.children[i])
+}
+
+struct SelectorParser<'a> {
+    source: &'a str,
+    chars: Peekable<Chars<'a>>,
+}
+
+impl SelectorParser<'_> {
+    fn error(&self, message: &str) -> GrammarError {
+        GrammarError::Parse(format!("Invalid selector '{}': {}", self.source, message))
+    }
+
+    /// Skip whitespace, returning whether there was any
+    fn skip_whitespace(&mut self) -> bool {
+        let mut skipped = false;
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn step(&mut self, axis: Axis) -> Result<Step> {
+        let test = match self.chars.next() {
+            Some('*') => Test::Any,
+            Some('<') => {
+                let name = self.name();
+                if name.is_empty() || self.chars.next() != Some('>') {
+                    return Err(self.error("expected '<symbol>'"));
+                }
+                Test::Symbol(name)
+            }
+            Some(quote @ ('"' | '\'')) => Test::Terminal(self.quoted(quote)?),
+            _ => return Err(self.error("expected '<symbol>', a quoted terminal or '*'")),
+        };
+
+        let mut filters = Vec::new();
+        loop {
+            match self.chars.peek() {
+                Some('[') => {
+                    self.chars.next();
+                    filters.push(self.attribute()?);
+                }
+                Some(':') => {
+                    self.chars.next();
+                    filters.push(self.position()?);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(Step {
+            axis,
+            test,
+            filters,
+        })
+    }
+
+    /// `name]` or `name=value]` after the `[`
+    fn attribute(&mut self) -> Result<Filter> {
+        self.skip_whitespace();
+        let name = self.name();
+        if name.is_empty() {
+            return Err(self.error("expected an attribute name after '['"));
+        }
+        self.skip_whitespace();
+
+        let filter = if self.chars.next_if_eq(&'=').is_some() {
+            self.skip_whitespace();
+            let value = match self.chars.next_if(|c| *c == '"' || *c == '\'') {
+                Some(quote) => self.quoted(quote)?,
+                None => {
+                    let mut value = String::new();
+                    while let Some(c) = self.chars.next_if(|c| *c != ']' && !c.is_whitespace()) {
+                        value.push(c);
+                    }
+                    value
+                }
+            };
+            self.skip_whitespace();
+            Filter::Equals(name, value)
+        } else {
+            Filter::Has(name)
+        };
+
+        if self.chars.next() != Some(']') {
+            return Err(self.error("expected ']'"));
+        }
+        Ok(filter)
+    }
+
+    /// `nth(n)`, `first` or `last` after the `:`
+    fn position(&mut self) -> Result<Filter> {
+        match self.name().as_str() {
+            "first" => Ok(Filter::Nth(1)),
+            "last" => Ok(Filter::Last),
+            "nth" => {
+                if self.chars.next() != Some('(') {
+                    return Err(self.error("expected '(' after ':nth'"));
+                }
+                let mut digits = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                }
+                if self.chars.next() != Some(')') {
+                    return Err(self.error("expected ')' after the ':nth' index"));
+                }
+                match digits.parse() {
+                    Ok(n) if n > 0 => Ok(Filter::Nth(n)),
+                    _ => Err(self.error(":nth takes an index starting at 1")),
+                }
+            }
+            other => Err(self.error(&format!(
+                "unknown filter ':{}', expected :nth(n), :first or :last",
+                other
+            ))),
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '-')
+        {
+            name.push(c);
+        }
+        name
+    }
+
+    /// Text up to the closing `quote`, with `\` escaping the next character
+    fn quoted(&mut self, quote: char) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                Some('\\') => match self.chars.next() {
+                    Some(c) => text.push(c),
+                    None => break,
+                },
+                Some(c) if c == quote => return Ok(text),
+                Some(c) => text.push(c),
+                None => break,
+            }
+        }
+        Err(self.error("unterminated quoted string"))
+    }
+}
+
+impl QueryAstNode {
+    /// Nodes below and including this one matching `selector`
+    pub fn select(&self, selector: &str) -> Result<Vec<&QueryAstNode>> {
+        Ok(Selector::parse(selector)?.select(self))
+    }
+
+    /// Nodes matching `selector` for modification; see [`Selector::select_mut`]
+    pub fn select_mut(&mut self, selector: &str) -> Result<Vec<&mut QueryAstNode>> {
+        Ok(Selector::parse(selector)?.select_mut(self))
+    }
+}
+
+impl QueryAst {
+    /// Nodes of the AST matching `selector`, in document order
+    pub fn select(&self, selector: &str) -> Result<Vec<&QueryAstNode>> {
+        self.root.select(selector)
+    }
+
+    /// Nodes of the AST matching `selector` for modification.
+    ///
+    /// The text is not updated; use [`QueryAst::replace`] or re-render with
+    /// [`QueryAst::with_root`] afterwards.
+    pub fn select_mut(&mut self, selector: &str) -> Result<Vec<&mut QueryAstNode>> {
+        self.root.select_mut(selector)
+    }
+
+    /// Replace the nodes matching `selector` and re-render the text,
+    /// returning how many were replaced
+    pub fn replace<F>(&mut self, selector: &str, replace: F) -> Result<usize>
+    where
+        F: FnMut(&QueryAstNode) -> QueryAstNode,
+    {
+        let count = Selector::parse(selector)?.replace(&mut self.root, replace);
+        self.text = self.root.to_string();
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(symbol: &str, children: Vec<QueryAstNode>) -> QueryAstNode {
+        let mut node = QueryAstNode::new("non_terminal", symbol);
+        node.children = children;
+        node
+    }
+
+    fn terminal(text: &str) -> QueryAstNode {
+        QueryAstNode::new("terminal", text)
+    }
+
+    /// SELECT a FROM t JOIN u ON (ref a) = (ref b) JOIN v ON (ref c) = (ref d)
+    fn tree() -> QueryAstNode {
+        let join = |table: &str, left: &str, right: &str| {
+            let mut table = node("table", vec![terminal(table)]);
+            table.attributes.insert(
+                "name".to_string(),
+                AttrValue::Str(table.children[0].value.clone()),
+            );
+            node(
+                "join",
+                vec![
+                    terminal("JOIN"),
+                    table,
+                    terminal("ON"),
+                    node("ref", vec![terminal(left)]),
+                    terminal("="),
+                    node("ref", vec![terminal(right)]),
+                ],
+            )
+        };
+        node(
+            "query",
+            vec![
+                terminal("SELECT"),
+                node("ref", vec![terminal("a")]),
+                terminal("FROM"),
+                node("table", vec![terminal("t")]),
+                join("u", "a", "b"),
+                join("v", "c", "d"),
+            ],
+        )
+    }
+
+    fn texts(nodes: &[&QueryAstNode]) -> Vec<String> {
+        nodes.iter().map(|node| node.to_string()).collect()
+    }
+
+    #[test]
+    fn test_select_steps_and_filters() {
+        let root = tree();
+        let cases: &[(&str, &[&str])] = &[
+            ("<ref>", &["a", "a", "b", "c", "d"]),
+            ("<query> > <ref>", &["a"]),
+            ("<join> <ref>:nth(2)", &["b", "d"]),
+            ("<join>:last <ref>:first", &["c"]),
+            ("<table>[name]", &["u", "v"]),
+            ("<table>[name='v'] ", &["v"]),
+            ("<query> > * > \"c\"", &[]),
+            ("<join> > 'ON'", &["ON", "ON"]),
+            (
+                "<query>",
+                &["SELECT a FROM t JOIN u ON a = b JOIN v ON c = d"],
+            ),
+        ];
+        for (selector, expected) in cases {
+            let selector: Selector = selector.parse().unwrap();
+            assert_eq!(texts(&selector.select(&root)), *expected, "{}", selector);
+        }
+
+        for bad in [
+            "",
+            "> <a>",
+            "<a> >",
+            "<a><b>",
+            "<a>:nth(0)",
+            "<a>[x",
+            "<a>:odd",
+            "a",
+        ] {
+            assert!(Selector::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_select_mut_and_replace() {
+        let mut root = tree();
+
+        // Nested matches are covered by the children of each join
+        assert_eq!(root.select_mut("<join> *").unwrap().len(), 12);
+
+        for node in root.select_mut("<join> > <ref>").unwrap() {
+            node.children[0].value = node.children[0].value.to_uppercase();
+        }
+        assert_eq!(
+            root.to_string(),
+            "SELECT a FROM t JOIN u ON A = B JOIN v ON C = D"
+        );
+
+        let selector = Selector::parse("<ref>").unwrap();
+        let replaced = selector.replace(&mut root, |old| {
+            node("ref", vec![terminal(&format!("x.{}", old))])
+        });
+        assert_eq!(replaced, 5);
+        assert_eq!(
+            root.to_string(),
+            "SELECT x.a FROM t JOIN u ON x.A = x.B JOIN v ON x.C = x.D"
+        );
+    }
+}
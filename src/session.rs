@@ -0,0 +1,251 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::grammar::{Grammar, QueryAst, QueryAstNode};
+use crate::utils::Result;
+use crate::weights::{ContextWeights, ProductionWeights, WeightContext};
+
+/// How quickly a session moves away from what it has already generated
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Factor applied to a production's weight each time it is used
+    pub decay: f64,
+    /// Lower bound on the accumulated decay, so no production is ruled out
+    pub min_factor: f64,
+    /// Also decay parent/child production combinations, not just productions
+    pub track_combinations: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            decay: 0.5,
+            min_factor: 0.01,
+            track_combinations: true,
+        }
+    }
+}
+
+/// A long-running generator that steers away from shapes it has produced.
+///
+/// Every production used in a generated query has its weight multiplied by
+/// `decay`, as does every (parent production, child production) pair, so
+/// later samples favour productions and combinations not seen yet. Context
+/// weights the grammar sets for a symbol, down to single positions, decay
+/// along with its productions. The wrapped grammar is left untouched; the
+/// session works on its own copy.
+#[derive(Debug, Clone)]
+pub struct GenerationSession {
+    base: Grammar,
+    grammar: Grammar,
+    config: SessionConfig,
+    /// Accumulated decay per symbol, in production order
+    factors: HashMap<String, Vec<f64>>,
+    /// Accumulated decay per child symbol within a parent production
+    combinations: HashMap<(WeightContext, String), Vec<f64>>,
+    generated: usize,
+}
+
+impl GenerationSession {
+    /// Start a session with the default configuration
+    pub fn new(grammar: Grammar) -> Self {
+        Self::with_config(grammar, SessionConfig::default())
+    }
+
+    /// Start a session with a custom configuration
+    pub fn with_config(grammar: Grammar, config: SessionConfig) -> Self {
+        GenerationSession {
+            base: grammar.clone(),
+            grammar,
+            config,
+            factors: HashMap::new(),
+            combinations: HashMap::new(),
+            generated: 0,
+        }
+    }
+
+    /// Generate a query and decay the productions it used
+    pub fn generate(&mut self, start_symbol: &str) -> QueryAst {
+        let ast = self.grammar.generate(start_symbol);
+        self.record(&ast);
+        ast
+    }
+
+    /// Generate a query, failing when predicates reject every derivation
+    pub fn try_generate(&mut self, start_symbol: &str) -> Result<QueryAst> {
+        let ast = self.grammar.try_generate(start_symbol)?;
+        self.record(&ast);
+        Ok(ast)
+    }
+
+    /// The grammar with the session's current weights
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Number of queries generated since the session started or was reset
+    pub fn generated(&self) -> usize {
+        self.generated
+    }
+
+    /// Fraction of the grammar's productions used at least once
+    pub fn coverage(&self) -> f64 {
+        let total: usize = self.base.rules().values().map(Vec::len).sum();
+        let used = self
+            .factors
+            .values()
+            .flatten()
+            .filter(|&&factor| factor < 1.0)
+            .count();
+        if total == 0 {
+            0.0
+        } else {
+            used as f64 / total as f64
+        }
+    }
+
+    /// Forget everything generated so far and restore the original weights
+    pub fn reset(&mut self) {
+        self.grammar = self.base.clone();
+        self.factors.clear();
+        self.combinations.clear();
+        self.generated = 0;
+    }
+
+    /// The session's current weights, loadable with `Grammar::load_weights`
+    /// to resume a campaign later
+    pub fn snapshot(&self) -> ProductionWeights {
+        let symbols = self
+            .factors
+            .keys()
+            .map(|symbol| {
+                (
+                    symbol.clone(),
+                    self.grammar.rules()[symbol]
+                        .iter()
+                        .map(|p| p.weight)
+                        .collect(),
+                )
+            })
+            .collect();
+
+        let mut contexts = self
+            .factors
+            .keys()
+            .flat_map(|symbol| {
+                self.grammar
+                    .contexts_of(symbol)
+                    .into_iter()
+                    .map(move |(context, weights)| ContextWeights {
+                        context: context.clone(),
+                        symbol: symbol.clone(),
+                        weights: weights.to_vec(),
+                    })
+            })
+            .collect::<Vec<_>>();
+        contexts.sort_by(|a, b| (&a.context, &a.symbol).cmp(&(&b.context, &b.symbol)));
+
+        ProductionWeights { symbols, contexts }
+    }
+
+    /// Write the session's current weights to a JSON weight file
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.snapshot().to_json_file(path)
+    }
+
+    fn record(&mut self, ast: &QueryAst) {
+        let mut touched = BTreeSet::new();
+        self.decay(&ast.root, None, &mut touched);
+        for symbol in touched {
+            self.refresh(&symbol);
+        }
+        self.generated += 1;
+    }
+
+    fn decay(
+        &mut self,
+        node: &QueryAstNode,
+        parent: Option<&QueryAstNode>,
+        touched: &mut BTreeSet<String>,
+    ) {
+        let Some(production) = node.production else {
+            return;
+        };
+        let size = self.base.rules()[&node.value].len();
+        let (decay, min_factor) = (self.config.decay, self.config.min_factor);
+
+        let factor = &mut self
+            .factors
+            .entry(node.value.clone())
+            .or_insert_with(|| vec![1.0; size])[production];
+        *factor = (*factor * decay).max(min_factor);
+
+        if self.config.track_combinations
+            && let Some(parent) = parent
+            && let Some(parent_production) = parent.production
+        {
+            let context = WeightContext::production(&parent.value, parent_production);
+            let factor = &mut self
+                .combinations
+                .entry((context, node.value.clone()))
+                .or_insert_with(|| vec![1.0; size])[production];
+            *factor = (*factor * decay).max(min_factor);
+        }
+        touched.insert(node.value.clone());
+
+        for child in &node.children {
+            self.decay(child, Some(node), touched);
+        }
+    }
+
+    /// Recompute the working weights of `symbol` from the original grammar
+    /// and the accumulated decay, at every context level the symbol is
+    /// weighted in
+    fn refresh(&mut self, symbol: &str) {
+        let factors = &self.factors[symbol];
+        let base: Vec<f64> = self.base.rules()[symbol].iter().map(|p| p.weight).collect();
+        let weights = scale(&base, factors);
+        self.grammar
+            .set_weights(symbol, &weights)
+            .expect("session weights match the grammar's productions");
+
+        // Contexts of the original grammar, with the combination decay of
+        // the parent production they fall within
+        let mut contexts = HashMap::new();
+        for (context, weights) in self.base.contexts_of(symbol) {
+            let mut weights = scale(weights, factors);
+            if let Some(production) = context.production {
+                let key = (
+                    WeightContext::production(&context.parent, production),
+                    symbol.to_string(),
+                );
+                if let Some(combination) = self.combinations.get(&key) {
+                    weights = scale(&weights, combination);
+                }
+            }
+            contexts.insert(context.clone(), weights);
+        }
+
+        for ((context, child), combination) in &self.combinations {
+            if child != symbol || contexts.contains_key(context) {
+                continue;
+            }
+            // Start from the weights the original grammar would use here
+            let base = self
+                .base
+                .context_weights(&WeightContext::parent(&context.parent), symbol)
+                .unwrap_or(&base);
+            contexts.insert(context.clone(), scale(&scale(base, factors), combination));
+        }
+
+        for (context, weights) in contexts {
+            self.grammar
+                .set_context_weights(context, symbol, &weights)
+                .expect("session weights match the grammar's productions");
+        }
+    }
+}
+
+fn scale(weights: &[f64], factors: &[f64]) -> Vec<f64> {
+    weights.iter().zip(factors).map(|(w, f)| w * f).collect()
+}
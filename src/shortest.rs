@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+
+use crate::grammar::{Element, Grammar, Production, QueryAst, QueryAstNode};
+use crate::symbol_table::SymbolTable;
+use crate::utils::{GrammarError, Result};
+
+/// What a shortest derivation minimises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Measure {
+    /// Terminals in the derived sentence, then depth
+    Tokens,
+    /// Depth of the derivation tree, then terminals
+    Depth,
+}
+
+/// The size of a non-terminal's shortest derivation under one measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shortest {
+    /// Production the derivation starts with
+    pub production: usize,
+    /// Terminals in the derived sentence; layout directives do not count
+    pub tokens: usize,
+    /// Non-terminal levels in the derivation tree, 1 for a production
+    /// without non-terminals
+    pub depth: usize,
+}
+
+impl Shortest {
+    fn key(&self, measure: Measure) -> (usize, usize) {
+        match measure {
+            Measure::Tokens => (self.tokens, self.depth),
+            Measure::Depth => (self.depth, self.tokens),
+        }
+    }
+}
+
+/// Shortest derivations of every non-terminal of a grammar, by tokens and
+/// by depth.
+///
+/// Predicates are ignored, so a guarded production may be part of a
+/// shortest derivation that generation would reject.
+pub struct ShortestDerivations<'g> {
+    grammar: &'g Grammar,
+    by_tokens: BTreeMap<&'g str, Shortest>,
+    by_depth: BTreeMap<&'g str, Shortest>,
+}
+
+/// The defined non-terminal an element expands
+fn symbol_of<'g>(grammar: &Grammar, element: &'g Element) -> Option<&'g str> {
+    match element {
+        Element::NonTerminal(name) if grammar.has_non_terminal(name) => Some(name),
+        Element::Bind(binding) => symbol_of(grammar, &binding.element),
+        _ => None,
+    }
+}
+
+/// Terminals an element that is not a defined non-terminal generates
+fn tokens(element: &Element) -> usize {
+    match element {
+        Element::Layout(_) => 0,
+        Element::Bind(binding) => tokens(&binding.element),
+        Element::Classified(element, _) => tokens(element),
+        _ => 1,
+    }
+}
+
+/// Size of the shortest derivation starting with `production`, given the
+/// shortest derivations found so far
+fn measure<'g>(
+    grammar: &Grammar,
+    found: &BTreeMap<&'g str, Shortest>,
+    index: usize,
+    production: &'g Production,
+) -> Option<Shortest> {
+    let mut shortest = Shortest {
+        production: index,
+        tokens: 0,
+        depth: 1,
+    };
+    for element in &production.elements {
+        match symbol_of(grammar, element) {
+            Some(symbol) => {
+                let child = found.get(symbol)?;
+                shortest.tokens += child.tokens;
+                shortest.depth = shortest.depth.max(child.depth + 1);
+            }
+            None => shortest.tokens += tokens(element),
+        }
+    }
+    Some(shortest)
+}
+
+/// Shortest derivations under `by`, improving every symbol's until none
+/// improves. Symbols without a finite derivation are left out.
+fn solve(grammar: &Grammar, by: Measure) -> BTreeMap<&str, Shortest> {
+    let mut symbols: Vec<_> = grammar.rules().iter().collect();
+    symbols.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut found: BTreeMap<&str, Shortest> = BTreeMap::new();
+    loop {
+        let mut changed = false;
+        for (symbol, productions) in &symbols {
+            for (p, production) in productions.iter().enumerate() {
+                let Some(candidate) = measure(grammar, &found, p, production) else {
+                    continue;
+                };
+                // Both keys grow through every level of the tree, so a
+                // strict improvement never chooses a cycle
+                let better = found
+                    .get(symbol.as_str())
+                    .is_none_or(|current| candidate.key(by) < current.key(by));
+                if better {
+                    found.insert(symbol.as_str(), candidate);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return found;
+        }
+    }
+}
+
+/// Builds the derivation tree of a shortest derivation
+struct Builder<'g> {
+    grammar: &'g Grammar,
+    chosen: &'g BTreeMap<&'g str, Shortest>,
+    symbols: SymbolTable,
+}
+
+impl Builder<'_> {
+    fn expand(&mut self, symbol: &str) -> QueryAstNode {
+        let production = self.chosen[symbol].production;
+        let mut node = QueryAstNode::new("non_terminal", symbol);
+        node.production = Some(production);
+
+        self.symbols.push_scope(symbol);
+        for element in &self.grammar.rules()[symbol][production].elements {
+            self.element(element, &mut node);
+        }
+        self.symbols.pop_scope();
+        node
+    }
+
+    fn element(&mut self, element: &Element, parent: &mut QueryAstNode) {
+        let node = match element {
+            Element::NonTerminal(name) if self.grammar.has_non_terminal(name) => self.expand(name),
+            Element::NonTerminal(name) => QueryAstNode::new("undefined", name),
+            Element::Terminal(text) => QueryAstNode::new("terminal", text),
+            Element::Regex(regex) => QueryAstNode::new("terminal", &regex.shortest()),
+            Element::Bind(binding) => {
+                let first = parent.children.len();
+                self.element(&binding.element, parent);
+                let mut captured = QueryAstNode::new("non_terminal", "");
+                captured.children = parent.children[first..].to_vec();
+                let value = self.grammar.render(&captured);
+                self.symbols
+                    .bind(&binding.variable, value, binding.scope.as_deref());
+                return;
+            }
+            Element::Variable(name) => {
+                let value = self.symbols.lookup(name).map(str::to_string);
+                variable(name, value)
+            }
+            Element::Pick(name) => {
+                let value = self.symbols.visible(name).last().map(|v| v.to_string());
+                variable(name, value)
+            }
+            Element::Classified(element, class) => {
+                let first = parent.children.len();
+                self.element(element, parent);
+                for child in &mut parent.children[first..] {
+                    if child.element_type == "terminal" {
+                        child.class = Some(*class);
+                    }
+                }
+                return;
+            }
+            Element::Layout(layout) => QueryAstNode::new("layout", &layout.to_string()),
+        };
+        parent.children.push(node);
+    }
+}
+
+/// A variable's value, or an undefined `$name` node when it is unbound
+fn variable(name: &str, value: Option<String>) -> QueryAstNode {
+    match value {
+        Some(value) => QueryAstNode::new("terminal", &value),
+        None => QueryAstNode::new("undefined", &format!("${}", name)),
+    }
+}
+
+impl<'g> ShortestDerivations<'g> {
+    /// The shortest derivation of `symbol` under `measure`, or `None` when
+    /// it has no finite derivation or is not defined
+    pub fn get(&self, symbol: &str, measure: Measure) -> Option<&Shortest> {
+        self.chosen(measure).get(symbol)
+    }
+
+    /// Defined symbols with no finite derivation, in sorted order
+    pub fn unproductive(&self) -> Vec<&'g str> {
+        let mut symbols: Vec<&str> = self
+            .grammar
+            .rules()
+            .keys()
+            .map(String::as_str)
+            .filter(|symbol| !self.by_tokens.contains_key(symbol))
+            .collect();
+        symbols.sort();
+        symbols
+    }
+
+    /// The example sentence and derivation tree of the shortest derivation
+    /// of `symbol` under `measure`.
+    ///
+    /// Regexes give the shortest strings they generate, and variables take
+    /// the values bound earlier in the example, like in generation.
+    pub fn example(&self, symbol: &str, measure: Measure) -> Option<QueryAst> {
+        self.get(symbol, measure)?;
+        let mut builder = Builder {
+            grammar: self.grammar,
+            chosen: self.chosen(measure),
+            symbols: SymbolTable::new(),
+        };
+        let root = builder.expand(symbol);
+        Some(QueryAst {
+            text: self.grammar.render(&root),
+            type_name: symbol.to_string(),
+            root,
+        })
+    }
+
+    fn chosen(&self, measure: Measure) -> &BTreeMap<&'g str, Shortest> {
+        match measure {
+            Measure::Tokens => &self.by_tokens,
+            Measure::Depth => &self.by_depth,
+        }
+    }
+}
+
+impl Grammar {
+    /// Shortest derivations of every non-terminal, by tokens and by depth
+    pub fn shortest_derivations(&self) -> ShortestDerivations<'_> {
+        ShortestDerivations {
+            grammar: self,
+            by_tokens: solve(self, Measure::Tokens),
+            by_depth: solve(self, Measure::Depth),
+        }
+    }
+
+    /// The example of `symbol` with the fewest tokens; see
+    /// [`ShortestDerivations::example`]
+    pub fn minimal_example(&self, symbol: &str) -> Result<QueryAst> {
+        if !self.has_non_terminal(symbol) {
+            return Err(GrammarError::UnknownNonTerminal(symbol.to_string()));
+        }
+        self.shortest_derivations()
+            .example(symbol, Measure::Tokens)
+            .ok_or_else(|| {
+                GrammarError::InvalidGrammar(format!("<{}> has no finite derivation", symbol))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_and_depth_differ() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<columns>", "FROM", "t"])
+            .unwrap()
+            .add_rule("columns", vec!["<column>", ",", "<columns>"])
+            .unwrap()
+            .add_rule("columns", vec!["<column>"])
+            .unwrap()
+            .add_rule("columns", vec!["a", ",", "b"])
+            .unwrap()
+            .add_rule("column", vec!["<name>"])
+            .unwrap()
+            .add_rule("name", vec!["id"])
+            .unwrap();
+        let shortest = grammar.shortest_derivations();
+
+        assert_eq!(
+            shortest.get("columns", Measure::Tokens),
+            Some(&Shortest {
+                production: 1,
+                tokens: 1,
+                depth: 3
+            })
+        );
+        assert_eq!(
+            shortest.get("columns", Measure::Depth),
+            Some(&Shortest {
+                production: 2,
+                tokens: 3,
+                depth: 1
+            })
+        );
+        assert_eq!(shortest.get("query", Measure::Tokens).unwrap().tokens, 4);
+
+        let example = shortest.example("query", Measure::Tokens).unwrap();
+        assert_eq!(example.text, "SELECT id FROM t");
+        assert_eq!(example.root.children[1].production, Some(1));
+        let example = shortest.example("query", Measure::Depth).unwrap();
+        assert_eq!(example.text, "SELECT a, b FROM t");
+        assert!(shortest.unproductive().is_empty());
+    }
+
+    #[test]
+    fn test_unproductive_symbols() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("loop", vec!["<loop>", "x"])
+            .unwrap()
+            .add_rule("uses_loop", vec!["(", "<loop>", ")"])
+            .unwrap()
+            .add_rule("ok", vec!["<uses_loop>"])
+            .unwrap()
+            .add_rule("ok", vec!["<undefined>"])
+            .unwrap();
+        let shortest = grammar.shortest_derivations();
+
+        assert_eq!(shortest.unproductive(), ["loop", "uses_loop"]);
+        assert_eq!(shortest.get("ok", Measure::Tokens).unwrap().production, 1);
+        assert!(grammar.minimal_example("loop").is_err());
+        assert!(grammar.minimal_example("missing").is_err());
+        assert_eq!(grammar.minimal_example("ok").unwrap().text, "<undefined>");
+    }
+
+    #[test]
+    fn test_example_binds_variables() {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule(
+                "query",
+                vec![
+                    "SELECT",
+                    "$t",
+                    "FROM",
+                    "<table> as $t",
+                    "@newline",
+                    "/[a-z]+[0-9]*/",
+                ],
+            )
+            .unwrap()
+            .add_rule("table", vec!["users"])
+            .unwrap();
+
+        let example = grammar.minimal_example("query").unwrap();
+        assert_eq!(example.root.children[1].element_type, "undefined");
+        assert_eq!(example.root.children[4].element_type, "layout");
+        assert_eq!(example.root.children[5].value, "a");
+        assert_eq!(
+            grammar.shortest_derivations().get("query", Measure::Tokens),
+            Some(&Shortest {
+                production: 0,
+                tokens: 5,
+                depth: 2
+            })
+        );
+
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["SELECT", "<table> as $t", ",", "$t"])
+            .unwrap()
+            .add_rule("table", vec!["users"])
+            .unwrap();
+        assert_eq!(
+            grammar.minimal_example("query").unwrap().text,
+            "SELECT users, users"
+        );
+    }
+
+    #[test]
+    fn test_example_regexes_are_shortest() {
+        let grammar: Grammar = "<n> ::= [/0x[0-9A-F]{1,8}/]".parse().unwrap();
+        assert_eq!(grammar.minimal_example("n").unwrap().text, "0x0");
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::grammar::{Element, Grammar};
+use crate::utils::{GrammarError, Result};
+
+/// The rules of a grammar reachable from a start symbol
+#[derive(Debug, Clone)]
+pub struct Slice {
+    /// The grammar with only the reachable rules
+    pub grammar: Grammar,
+    /// Excluded symbols the slice still refers to, which are now undefined,
+    /// each with the symbols whose productions refer to it
+    pub excluded: BTreeMap<String, BTreeSet<String>>,
+    /// Symbols the original grammar already left undefined, each with the
+    /// symbols whose productions refer to it
+    pub undefined: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// The non-terminal an element refers to
+pub(crate) fn reference(element: &Element) -> Option<&str> {
+    match element {
+        Element::NonTerminal(name) => Some(name),
+        Element::Bind(binding) => reference(&binding.element),
+        _ => None,
+    }
+}
+
+impl Grammar {
+    /// The rules reachable from `start_symbol`; see [`Grammar::slice_excluding`]
+    pub fn slice(&self, start_symbol: &str) -> Result<Slice> {
+        self.slice_excluding(start_symbol, &[])
+    }
+
+    /// The rules reachable from `start_symbol` without going through the
+    /// rules of `excluded`.
+    ///
+    /// References to excluded symbols stay in place, so they become
+    /// undefined and the slice reports where they are used. Predicates,
+    /// attributes, configuration and the renderer are kept, and context
+    /// weights are kept when both their symbol and parent are.
+    pub fn slice_excluding(&self, start_symbol: &str, excluded: &[&str]) -> Result<Slice> {
+        if !self.has_non_terminal(start_symbol) {
+            return Err(GrammarError::UnknownNonTerminal(start_symbol.to_string()));
+        }
+        if excluded.contains(&start_symbol) {
+            return Err(GrammarError::InvalidGrammar(format!(
+                "The start symbol <{}> cannot be excluded",
+                start_symbol
+            )));
+        }
+
+        let mut reached = BTreeSet::from([start_symbol]);
+        let mut stack = vec![start_symbol];
+        let mut lost: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut undefined: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        while let Some(symbol) = stack.pop() {
+            let elements = self.rules()[symbol].iter().flat_map(|p| &p.elements);
+            for target in elements.filter_map(reference) {
+                let report = if excluded.contains(&target) {
+                    &mut lost
+                } else if !self.has_non_terminal(target) {
+                    &mut undefined
+                } else {
+                    if reached.insert(target) {
+                        stack.push(target);
+                    }
+                    continue;
+                };
+                report
+                    .entry(target.to_string())
+                    .or_default()
+                    .insert(symbol.to_string());
+            }
+        }
+
+        let mut grammar = self.clone();
+        grammar.retain_rules(|symbol| reached.contains(symbol));
+        Ok(Slice {
+            grammar,
+            excluded: lost,
+            undefined,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weights::WeightContext;
+
+    fn grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar
+            .add_rule("query", vec!["<select_query>"])
+            .unwrap()
+            .add_rule("query", vec!["<insert_query>"])
+            .unwrap()
+            .add_rule("select_query", vec!["SELECT", "<column>", "<where>"])
+            .unwrap()
+            .add_rule("where", vec!["WHERE", "<column>", "=", "<subquery>"])
+            .unwrap()
+            .add_rule("where", vec!["WHERE", "<column>", "=", "<value>"])
+            .unwrap()
+            .add_rule("subquery", vec!["(", "<select_query>", ")"])
+            .unwrap()
+            .add_rule("column", vec!["id"])
+            .unwrap()
+            .add_rule("insert_query", vec!["INSERT", "<column>"])
+            .unwrap();
+        grammar
+    }
+
+    #[test]
+    fn test_slice_keeps_reachable_rules() {
+        let mut grammar = grammar();
+        grammar
+            .set_context_weights(WeightContext::parent("where"), "column", &[2.0])
+            .unwrap()
+            .set_context_weights(WeightContext::parent("insert_query"), "column", &[3.0])
+            .unwrap();
+
+        let slice = grammar.slice("select_query").unwrap();
+        let mut symbols: Vec<_> = slice.grammar.rules().keys().cloned().collect();
+        symbols.sort();
+        assert_eq!(symbols, ["column", "select_query", "subquery", "where"]);
+        assert!(slice.excluded.is_empty());
+        assert_eq!(
+            slice.undefined,
+            BTreeMap::from([("value".to_string(), BTreeSet::from(["where".to_string()]))])
+        );
+
+        let weights = |parent| {
+            slice
+                .grammar
+                .context_weights(&WeightContext::parent(parent), "column")
+        };
+        assert_eq!(weights("where"), Some(&[2.0][..]));
+        assert_eq!(weights("insert_query"), None);
+        assert!(grammar.slice("missing").is_err());
+    }
+
+    #[test]
+    fn test_slice_excluding_reports_lost_symbols() {
+        let grammar = grammar();
+        let slice = grammar
+            .slice_excluding("query", &["subquery", "insert_query"])
+            .unwrap();
+
+        assert!(!slice.grammar.has_non_terminal("subquery"));
+        assert!(!slice.grammar.has_non_terminal("insert_query"));
+        assert!(slice.grammar.has_non_terminal("where"));
+        assert_eq!(
+            slice.excluded.keys().collect::<Vec<_>>(),
+            ["insert_query", "subquery"]
+        );
+        assert_eq!(
+            slice.excluded["subquery"],
+            BTreeSet::from(["where".to_string()])
+        );
+        assert!(grammar.slice_excluding("query", &["query"]).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+
+use crate::render::{Piece, Render, Renderer};
+
+/// Bytes rendered before they are written out
+const CHUNK_SIZE: usize = 8192;
+
+/// Renders pieces into a writer in bounded chunks
+pub(crate) struct TokenSink<'w> {
+    render: Render<'w>,
+    chunk: String,
+    out: &'w mut dyn Write,
+    /// The first write error; later pieces are dropped
+    error: Option<io::Error>,
+}
+
+impl<'w> TokenSink<'w> {
+    pub(crate) fn new(
+        out: &'w mut dyn Write,
+        renderer: &'w dyn Renderer,
+        auto_spacing: bool,
+    ) -> Self {
+        TokenSink {
+            render: Render::new(renderer, auto_spacing),
+            chunk: String::with_capacity(CHUNK_SIZE),
+            out,
+            error: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, piece: &Piece) {
+        self.render.push(piece, &mut self.chunk);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.write_chunk();
+        }
+    }
+
+    fn write_chunk(&mut self) {
+        if self.error.is_none()
+            && let Err(e) = self.out.write_all(self.chunk.as_bytes())
+        {
+            self.error = Some(e);
+        }
+        self.chunk.clear();
+    }
+
+    /// Write out what is left and flush the writer
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.write_chunk();
+        match self.error {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{render, Layout, RuleRenderer};
+
+    #[test]
+    fn test_sink_matches_render() {
+        let renderer = RuleRenderer::default();
+        let pieces: Vec<Piece> = (0..5000)
+            .flat_map(|i| {
+                [
+                    Piece::Token(format!("v{}", i)),
+                    Piece::Layout(Layout::Glue),
+                    Piece::Token(",".to_string()),
+                    Piece::Layout(Layout::Newline),
+                ]
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        let mut sink = TokenSink::new(&mut out, &renderer, true);
+        for piece in &pieces {
+            sink.push(piece);
+        }
+        sink.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            render(&renderer, true, &pieces)
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use crate::grammar::Element;
+
+/// A grammar element whose generated text is captured into a variable.
+///
+/// Written as `<table_name> as $t` in a production. With a scope suffix,
+/// `<alias> as $aliases in <select_statement>`, the value is bound in the
+/// nearest enclosing `<select_statement>` expansion instead of the current
+/// one, so siblings of that symbol can see it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    /// The element whose generated text is captured
+    pub element: Box<Element>,
+    /// Variable name, without the leading `$`
+    pub variable: String,
+    /// Non-terminal whose scope receives the binding (defaults to the current one)
+    pub scope: Option<String>,
+}
+
+/// A scope opened for each expanded non-terminal
+#[derive(Debug, Clone)]
+struct Scope {
+    id: usize,
+    symbol: String,
+    bindings: HashMap<String, Vec<String>>,
+}
+
+/// Stack of scopes tracking the values bound during a single generation.
+///
+/// A scope is pushed when a non-terminal starts expanding and popped when it
+/// finishes, so names bound inside a subquery or CTE body do not leak into the
+/// enclosing statement. Lookups search from the innermost scope outwards.
+///
+/// Every binding is journaled so a failed expansion attempt can be undone
+/// with [`SymbolTable::rollback`] when the generator backtracks.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    scopes: Vec<Scope>,
+    next_id: usize,
+    /// (scope id, variable) for every binding, in order
+    journal: Vec<(usize, String)>,
+}
+
+impl SymbolTable {
+    /// Create an empty symbol table
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    /// Open a scope for the expansion of `symbol`
+    pub fn push_scope(&mut self, symbol: &str) {
+        self.scopes.push(Scope {
+            id: self.next_id,
+            symbol: symbol.to_string(),
+            bindings: HashMap::new(),
+        });
+        self.next_id += 1;
+    }
+
+    /// Close the innermost scope, discarding its bindings
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Current nesting depth
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    /// Bind `value` to `variable`.
+    ///
+    /// With `scope` set, the value goes to the nearest enclosing scope opened
+    /// for that symbol, falling back to the outermost scope when there is none.
+    pub fn bind(&mut self, variable: &str, value: String, scope: Option<&str>) {
+        let index = match scope {
+            Some(symbol) => self
+                .scopes
+                .iter()
+                .rposition(|s| s.symbol == symbol)
+                .unwrap_or(0),
+            None => self.scopes.len().saturating_sub(1),
+        };
+
+        if let Some(scope) = self.scopes.get_mut(index) {
+            scope
+                .bindings
+                .entry(variable.to_string())
+                .or_default()
+                .push(value);
+            self.journal.push((scope.id, variable.to_string()));
+        }
+    }
+
+    /// A marker that [`SymbolTable::rollback`] can return to
+    pub fn checkpoint(&self) -> usize {
+        self.journal.len()
+    }
+
+    /// Undo every binding made since `checkpoint` in scopes that are still open
+    pub fn rollback(&mut self, checkpoint: usize) {
+        while self.journal.len() > checkpoint {
+            let Some((id, variable)) = self.journal.pop() else {
+                break;
+            };
+            if let Some(values) = self
+                .scopes
+                .iter_mut()
+                .find(|s| s.id == id)
+                .and_then(|s| s.bindings.get_mut(&variable))
+            {
+                values.pop();
+            }
+        }
+    }
+
+    /// The most recently bound visible value of `variable`
+    pub fn lookup(&self, variable: &str) -> Option<&str> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|s| s.bindings.get(variable).and_then(|v| v.last()))
+            .map(|v| v.as_str())
+    }
+
+    /// All visible values of `variable`, outermost scope first
+    pub fn visible(&self, variable: &str) -> Vec<&str> {
+        self.scopes
+            .iter()
+            .filter_map(|s| s.bindings.get(variable))
+            .flatten()
+            .map(|v| v.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_hide_inner_bindings() {
+        let mut table = SymbolTable::new();
+        table.push_scope("query");
+        table.bind("t", "users".to_string(), None);
+
+        table.push_scope("subquery");
+        table.bind("t", "orders".to_string(), None);
+        assert_eq!(table.lookup("t"), Some("orders"));
+        assert_eq!(table.visible("t"), vec!["users", "orders"]);
+
+        table.pop_scope();
+        assert_eq!(table.lookup("t"), Some("users"));
+        assert_eq!(table.visible("t"), vec!["users"]);
+    }
+
+    #[test]
+    fn test_bind_into_named_scope() {
+        let mut table = SymbolTable::new();
+        table.push_scope("select_statement");
+        table.push_scope("table_reference");
+        table.bind("aliases", "a".to_string(), Some("select_statement"));
+        table.pop_scope();
+
+        assert_eq!(table.lookup("aliases"), Some("a"));
+        assert_eq!(table.lookup("missing"), None);
+    }
+
+    #[test]
+    fn test_rollback_undoes_bindings() {
+        let mut table = SymbolTable::new();
+        table.push_scope("query");
+        table.bind("t", "users".to_string(), None);
+
+        let checkpoint = table.checkpoint();
+        table.push_scope("from");
+        table.bind("t", "orders".to_string(), Some("query"));
+        table.pop_scope();
+        assert_eq!(table.visible("t"), vec!["users", "orders"]);
+
+        table.rollback(checkpoint);
+        assert_eq!(table.visible("t"), vec!["users"]);
+    }
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::utils::GrammarError;
+
+/// The lexical class of a generated terminal, declared in the grammar with
+/// `:class` after the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenClass {
+    Keyword,
+    Identifier,
+    Literal,
+    Punctuation,
+    Operator,
+}
+
+impl TokenClass {
+    /// Guess the class of an untagged terminal from its text: quoted strings
+    /// and numbers are literals, brackets, commas and the like punctuation,
+    /// other symbols operators, and words keywords when upper case
+    pub fn infer(text: &str) -> Self {
+        let text = text.trim();
+        let quoted = text.len() >= 2
+            && ((text.starts_with('\'') && text.ends_with('\''))
+                || (text.starts_with('"') && text.ends_with('"')));
+
+        if quoted || is_number(text) {
+            TokenClass::Literal
+        } else if !text.is_empty() && text.chars().all(|c| "()[]{},;.'\"".contains(c)) {
+            TokenClass::Punctuation
+        } else if !text.chars().any(|c| c.is_alphanumeric() || c == '_') {
+            TokenClass::Operator
+        } else if text.chars().any(char::is_alphabetic) && !text.chars().any(char::is_lowercase) {
+            TokenClass::Keyword
+        } else {
+            TokenClass::Identifier
+        }
+    }
+
+    /// The name used in grammar files
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenClass::Keyword => "keyword",
+            TokenClass::Identifier => "identifier",
+            TokenClass::Literal => "literal",
+            TokenClass::Punctuation => "punctuation",
+            TokenClass::Operator => "operator",
+        }
+    }
+}
+
+impl fmt::Display for TokenClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for TokenClass {
+    type Err = GrammarError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keyword" => Ok(TokenClass::Keyword),
+            "identifier" => Ok(TokenClass::Identifier),
+            "literal" => Ok(TokenClass::Literal),
+            "punctuation" => Ok(TokenClass::Punctuation),
+            "operator" => Ok(TokenClass::Operator),
+            _ => Err(GrammarError::Parse(format!(
+                "Unknown token class '{}', expected keyword, identifier, literal, punctuation or operator",
+                name
+            ))),
+        }
+    }
+}
+
+/// A terminal of generated output together with its class
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryToken {
+    pub text: String,
+    pub class: TokenClass,
+}
+
+/// Whether `text` is a decimal number: an optional sign, digits with an
+/// optional fraction, and an optional exponent. Unlike parsing an `f64`,
+/// this rejects words such as `inf` and `NaN`.
+pub(crate) fn is_number(text: &str) -> bool {
+    fn digits(s: &str) -> bool {
+        s.chars().all(|c| c.is_ascii_digit())
+    }
+    fn unsigned(s: &str) -> &str {
+        s.strip_prefix(['+', '-']).unwrap_or(s)
+    }
+
+    let text = unsigned(text);
+    let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(unsigned(exponent))),
+        None => (text, None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    digits(whole)
+        && digits(fraction)
+        && whole.len() + fraction.len() > 0
+        && exponent.is_none_or(|exponent| !exponent.is_empty() && digits(exponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infer_classes() {
+        let cases = [
+            ("SELECT", TokenClass::Keyword),
+            ("ORDER BY", TokenClass::Keyword),
+            ("COUNT(*)", TokenClass::Keyword),
+            ("users", TokenClass::Identifier),
+            ("t01", TokenClass::Identifier),
+            ("42", TokenClass::Literal),
+            ("-3.5e+10", TokenClass::Literal),
+            (".5", TokenClass::Literal),
+            ("1.", TokenClass::Literal),
+            ("inf", TokenClass::Identifier),
+            ("NaN", TokenClass::Identifier),
+            ("INFINITY", TokenClass::Keyword),
+            ("1e", TokenClass::Identifier),
+            ("1.2.3", TokenClass::Identifier),
+            ("'bob'", TokenClass::Literal),
+            ("(", TokenClass::Punctuation),
+            (",", TokenClass::Punctuation),
+            ("'", TokenClass::Punctuation),
+            (">=", TokenClass::Operator),
+            ("*", TokenClass::Operator),
+        ];
+        for (text, class) in cases {
+            assert_eq!(TokenClass::infer(text), class, "{}", text);
+        }
+    }
+}
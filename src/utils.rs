@@ -13,6 +13,9 @@ pub enum GrammarError {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("Binary encoding error: {0}")]
+    Binary(String),
+
     #[error("Parse error: {0}")]
     Parse(String),
 
@@ -30,6 +33,12 @@ pub enum GrammarError {
 
     #[error("Validator error: {0}")]
     ValidatorError(String),
+
+    #[error("No valid derivation: {0}")]
+    NoValidDerivation(String),
+
+    #[error("Generation worker panicked: {0}")]
+    WorkerPanicked(String),
 }
 
 /// Result type for grammar operations
@@ -48,6 +57,12 @@ pub trait GrammarValidator: Send + Sync + fmt::Debug {
         true // By default, applies to all text
     }
 
+    /// Whether the validator leaves all text unchanged, so generated text
+    /// can be written before it is complete
+    fn is_noop(&self) -> bool {
+        false
+    }
+
     /// Clone this validator as a box
     fn clone_box(&self) -> Box<dyn GrammarValidator>;
 }
@@ -88,6 +103,7 @@ impl ValidatorChain {
     }
 
     /// Add another validator to the chain
+    #[allow(clippy::should_implement_trait)]
     pub fn add<V: GrammarValidator + 'static>(mut self, validator: V) -> Self {
         self.name = format!("{}+{}", self.name, validator.name());
         self.validators.push(Box::new(validator));
@@ -135,6 +151,10 @@ impl GrammarValidator for NoopValidator {
         "noop"
     }
 
+    fn is_noop(&self) -> bool {
+        true
+    }
+
     fn clone_box(&self) -> Box<dyn GrammarValidator> {
         Box::new(self.clone())
     }
@@ -147,8 +167,7 @@ pub struct SqlNullValidator;
 impl GrammarValidator for SqlNullValidator {
     fn validate(&self, sql: &str) -> String {
         // Replace incorrect NULL comparisons with correct IS NULL or IS NOT NULL
-        let sql = sql
-            .replace(" = NULL", " IS NULL")
+        sql.replace(" = NULL", " IS NULL")
             .replace(" = null", " IS NULL")
             .replace(" != NULL", " IS NOT NULL")
             .replace(" != null", " IS NOT NULL")
@@ -161,9 +180,7 @@ impl GrammarValidator for SqlNullValidator {
             .replace(" >= NULL", " IS NOT NULL")
             .replace(" >= null", " IS NOT NULL")
             .replace(" <= NULL", " IS NOT NULL")
-            .replace(" <= null", " IS NOT NULL");
-
-        sql
+            .replace(" <= null", " IS NOT NULL")
     }
 
     fn name(&self) -> &str {
@@ -300,7 +317,7 @@ impl GrammarValidator for SqlKeywordValidator {
     }
 }
 
-/// Factory functions for creating common validators
+// Factory functions for creating common validators
 
 /// Create an SQL NULL handling validator
 pub fn sql_null_validator() -> Box<dyn GrammarValidator> {
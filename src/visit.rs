@@ -0,0 +1,387 @@
+use crate::grammar::{QueryAst, QueryAstNode};
+
+/// How a walk proceeds after a hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    Continue,
+    /// Do not descend into this node's children; only meaningful from `enter`
+    SkipChildren,
+    /// End the whole walk
+    Stop,
+}
+
+/// Where a visited node sits in the tree
+pub struct VisitContext<'a> {
+    pub(crate) ancestors: &'a [String],
+    pub(crate) index: usize,
+}
+
+impl VisitContext<'_> {
+    /// Values of the enclosing nodes, outermost first
+    pub fn ancestors(&self) -> &[String] {
+        self.ancestors
+    }
+
+    /// Value of the parent node, or `None` at the root
+    pub fn parent(&self) -> Option<&str> {
+        self.ancestors.last().map(String::as_str)
+    }
+
+    /// Number of enclosing nodes, 0 at the root
+    pub fn depth(&self) -> usize {
+        self.ancestors.len()
+    }
+
+    /// Position among the parent's children, 0 at the root
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Read-only walk over an AST, depth first in output order
+pub trait Visitor {
+    /// Called before a node's children are visited
+    fn enter(&mut self, _node: &QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+        Walk::Continue
+    }
+
+    /// Called after a node's children were visited
+    fn exit(&mut self, _node: &QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+        Walk::Continue
+    }
+}
+
+/// Walk over an AST that may modify nodes in place.
+///
+/// Changes `enter` makes to a node's children are what the walk descends into.
+pub trait VisitorMut {
+    /// Called before a node's children are visited
+    fn enter(&mut self, _node: &mut QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+        Walk::Continue
+    }
+
+    /// Called after a node's children were visited
+    fn exit(&mut self, _node: &mut QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+        Walk::Continue
+    }
+}
+
+/// Rebuilds an AST node by node, taking ownership of each.
+///
+/// Nodes the fold does not reach, below a node whose children are skipped
+/// or after it stops, are kept as they are.
+pub trait Fold {
+    /// Rewrite a node before its children are folded
+    fn enter(&mut self, node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+        node
+    }
+
+    /// How the fold proceeds with a node `enter` returned. On `Walk::Stop`
+    /// no further hooks are called, not even `exit` for this node.
+    fn proceed(&mut self, _node: &QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+        Walk::Continue
+    }
+
+    /// Rewrite a node whose children have been folded
+    fn exit(&mut self, node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+        node
+    }
+}
+
+impl QueryAstNode {
+    /// Walk this subtree with `visitor`, returning `Walk::Stop` if it ended early
+    pub fn walk<V: Visitor + ?Sized>(&self, visitor: &mut V) -> Walk {
+        walk(visitor, self, &mut Vec::new(), 0)
+    }
+
+    /// Walk this subtree with `visitor`, which may modify it
+    pub fn walk_mut<V: VisitorMut + ?Sized>(&mut self, visitor: &mut V) -> Walk {
+        walk_mut(visitor, self, &mut Vec::new(), 0)
+    }
+
+    /// Rebuild this subtree with `folder`
+    pub fn fold<F: Fold + ?Sized>(self, folder: &mut F) -> QueryAstNode {
+        fold(folder, self, &mut Vec::new(), 0, &mut false)
+    }
+}
+
+impl QueryAst {
+    /// Walk the AST with `visitor`, returning `Walk::Stop` if it ended early
+    pub fn walk<V: Visitor + ?Sized>(&self, visitor: &mut V) -> Walk {
+        self.root.walk(visitor)
+    }
+
+    /// Walk the AST with `visitor`, which may modify it, then re-render its
+    /// text. Returns `Walk::Stop` if the walk ended early.
+    pub fn walk_mut<V: VisitorMut + ?Sized>(&mut self, visitor: &mut V) -> Walk {
+        let walk = self.root.walk_mut(visitor);
+        self.text = self.root.to_string();
+        walk
+    }
+
+    /// Rebuild the AST with `folder`, re-rendering its text
+    pub fn fold<F: Fold + ?Sized>(&self, folder: &mut F) -> QueryAst {
+        self.with_root(self.root.clone().fold(folder))
+    }
+}
+
+fn walk<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    node: &QueryAstNode,
+    path: &mut Vec<String>,
+    index: usize,
+) -> Walk {
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    match visitor.enter(node, &ctx) {
+        Walk::Stop => return Walk::Stop,
+        Walk::SkipChildren => {}
+        Walk::Continue => {
+            path.push(node.value.clone());
+            let stopped = node
+                .children
+                .iter()
+                .enumerate()
+                .any(|(i, child)| walk(visitor, child, path, i) == Walk::Stop);
+            path.pop();
+            if stopped {
+                return Walk::Stop;
+            }
+        }
+    }
+
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    match visitor.exit(node, &ctx) {
+        Walk::Stop => Walk::Stop,
+        _ => Walk::Continue,
+    }
+}
+
+fn walk_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    node: &mut QueryAstNode,
+    path: &mut Vec<String>,
+    index: usize,
+) -> Walk {
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    match visitor.enter(node, &ctx) {
+        Walk::Stop => return Walk::Stop,
+        Walk::SkipChildren => {}
+        Walk::Continue => {
+            path.push(node.value.clone());
+            let stopped = node
+                .children
+                .iter_mut()
+                .enumerate()
+                .any(|(i, child)| walk_mut(visitor, child, path, i) == Walk::Stop);
+            path.pop();
+            if stopped {
+                return Walk::Stop;
+            }
+        }
+    }
+
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    match visitor.exit(node, &ctx) {
+        Walk::Stop => Walk::Stop,
+        _ => Walk::Continue,
+    }
+}
+
+fn fold<F: Fold + ?Sized>(
+    folder: &mut F,
+    node: QueryAstNode,
+    path: &mut Vec<String>,
+    index: usize,
+    stopped: &mut bool,
+) -> QueryAstNode {
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    let mut node = folder.enter(node, &ctx);
+
+    match folder.proceed(&node, &ctx) {
+        Walk::Stop => {
+            *stopped = true;
+            return node;
+        }
+        Walk::SkipChildren => {}
+        Walk::Continue => {
+            path.push(node.value.clone());
+            node.children = std::mem::take(&mut node.children)
+                .into_iter()
+                .enumerate()
+                .map(|(i, child)| {
+                    if *stopped {
+                        child
+                    } else {
+                        fold(folder, child, path, i, stopped)
+                    }
+                })
+                .collect();
+            path.pop();
+            if *stopped {
+                return node;
+            }
+        }
+    }
+
+    let ctx = VisitContext {
+        ancestors: path,
+        index,
+    };
+    folder.exit(node, &ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (SELECT (cols a , b) FROM t)
+    fn tree() -> QueryAstNode {
+        let mut cols = QueryAstNode::new("non_terminal", "cols");
+        for value in ["a", ",", "b"] {
+            cols.children.push(QueryAstNode::new("terminal", value));
+        }
+        let mut query = QueryAstNode::new("non_terminal", "query");
+        query.children = vec![
+            QueryAstNode::new("terminal", "SELECT"),
+            cols,
+            QueryAstNode::new("terminal", "FROM"),
+            QueryAstNode::new("terminal", "t"),
+        ];
+        query
+    }
+
+    #[test]
+    fn test_visitor_context_and_early_stop() {
+        #[derive(Default)]
+        struct Trace(Vec<String>);
+
+        impl Visitor for Trace {
+            fn enter(&mut self, node: &QueryAstNode, ctx: &VisitContext<'_>) -> Walk {
+                self.0.push(format!(
+                    "{}:{}:{}:{}",
+                    node.value,
+                    ctx.depth(),
+                    ctx.index(),
+                    ctx.parent().unwrap_or("-")
+                ));
+                match node.value.as_str() {
+                    "cols" => Walk::SkipChildren,
+                    "FROM" => Walk::Stop,
+                    _ => Walk::Continue,
+                }
+            }
+
+            fn exit(&mut self, node: &QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+                self.0.push(format!("/{}", node.value));
+                Walk::Continue
+            }
+        }
+
+        let mut trace = Trace::default();
+        assert_eq!(tree().walk(&mut trace), Walk::Stop);
+        assert_eq!(
+            trace.0,
+            [
+                "query:0:0:-",
+                "SELECT:1:0:query",
+                "/SELECT",
+                "cols:1:1:query",
+                "/cols",
+                "FROM:1:2:query"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_visitor_mut_and_fold() {
+        struct Upper;
+
+        impl VisitorMut for Upper {
+            fn enter(&mut self, node: &mut QueryAstNode, ctx: &VisitContext<'_>) -> Walk {
+                if ctx.parent() == Some("cols") {
+                    node.value = node.value.to_uppercase();
+                }
+                Walk::Continue
+            }
+        }
+
+        /// Drops commas and counts children bottom-up
+        struct Prune;
+
+        impl Fold for Prune {
+            fn enter(&mut self, mut node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+                node.children.retain(|child| child.value != ",");
+                node
+            }
+
+            fn exit(&mut self, mut node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+                let size = node.children.len() as i64;
+                node.attributes.insert("size".to_string(), size.into());
+                node
+            }
+        }
+
+        let mut root = tree();
+        root.walk_mut(&mut Upper);
+        assert_eq!(root.to_string(), "SELECT A, B FROM t");
+
+        let root = root.fold(&mut Prune);
+        assert_eq!(root.to_string(), "SELECT A B FROM t");
+        assert_eq!(root.children[1].attribute("size"), Some(&2.into()));
+
+        let mut ast = QueryAst {
+            text: String::new(),
+            type_name: "query".to_string(),
+            root: tree(),
+        };
+        assert_eq!(ast.walk_mut(&mut Upper), Walk::Continue);
+        assert_eq!(ast.text, "SELECT A, B FROM t");
+    }
+
+    #[test]
+    fn test_fold_skip_and_stop() {
+        /// Upper-cases terminals, leaving `cols` alone and stopping at `FROM`
+        struct Upper(Vec<String>);
+
+        impl Fold for Upper {
+            fn enter(&mut self, mut node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+                node.value = node.value.to_uppercase();
+                node
+            }
+
+            fn proceed(&mut self, node: &QueryAstNode, _ctx: &VisitContext<'_>) -> Walk {
+                match node.value.as_str() {
+                    "COLS" => Walk::SkipChildren,
+                    "FROM" => Walk::Stop,
+                    _ => Walk::Continue,
+                }
+            }
+
+            fn exit(&mut self, node: QueryAstNode, _ctx: &VisitContext<'_>) -> QueryAstNode {
+                self.0.push(node.value.clone());
+                node
+            }
+        }
+
+        let mut upper = Upper(Vec::new());
+        let root = tree().fold(&mut upper);
+        assert_eq!(root.to_string(), "SELECT a, b FROM t");
+        assert_eq!(root.children[1].value, "COLS");
+        assert_eq!(upper.0, ["SELECT", "COLS"]);
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::utils::{GrammarError, Result};
+
+/// Where a non-terminal is expanded, for context-conditioned weights.
+///
+/// A context names the parent symbol, optionally narrowed to one of its
+/// productions and to the element position within that production. When
+/// choosing a production, the most specific matching context wins, falling
+/// back to the unconditioned weights.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WeightContext {
+    /// The non-terminal being expanded around the symbol
+    pub parent: String,
+    /// Index of the parent's production
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub production: Option<usize>,
+    /// Index of the symbol among the elements of the parent's production
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+}
+
+impl WeightContext {
+    /// Any occurrence within `parent`
+    pub fn parent(parent: &str) -> Self {
+        WeightContext {
+            parent: parent.to_string(),
+            production: None,
+            position: None,
+        }
+    }
+
+    /// Any occurrence within a given production of `parent`
+    pub fn production(parent: &str, production: usize) -> Self {
+        WeightContext {
+            production: Some(production),
+            ..Self::parent(parent)
+        }
+    }
+
+    /// Restrict the context to one element position of the parent's production
+    pub fn at(mut self, position: usize) -> Self {
+        self.position = Some(position);
+        self
+    }
+}
+
+/// Production probabilities for a symbol within one parent context
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContextWeights {
+    #[serde(flatten)]
+    pub context: WeightContext,
+    pub symbol: String,
+    pub weights: Vec<f64>,
+}
+
+/// A weight file turning a grammar into a probabilistic CFG.
+///
+/// Weights are listed per non-terminal in production order, the order in
+/// which rules appear in the grammar file or were added with `add_rule`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProductionWeights {
+    /// Production weights per non-terminal
+    pub symbols: BTreeMap<String, Vec<f64>>,
+    /// Weights that replace `symbols` when the non-terminal is expanded
+    /// within a given parent production
+    #[serde(default)]
+    pub contexts: Vec<ContextWeights>,
+}
+
+impl ProductionWeights {
+    /// Load weights from a JSON file
+    pub fn from_json_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::from_json_str(&content)
+    }
+
+    /// Load weights from a JSON string
+    pub fn from_json_str(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(GrammarError::Json)
+    }
+
+    /// Serialize the weights as pretty-printed JSON
+    pub fn to_json_string(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(GrammarError::Json)
+    }
+
+    /// Write the weights to a JSON file
+    pub fn to_json_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json_string()?)?;
+        Ok(())
+    }
+}
@@ -1,5 +1,15 @@
+use grammar_gen::grammar::{QueryAst, QueryAstNode};
 use grammar_gen::utils::SqlNullValidator;
-use grammar_gen::{Grammar, GrammarConfig};
+use grammar_gen::weights::ContextWeights;
+use grammar_gen::{
+    AmbiguityConfig, AttrValue, DedupConfig, DedupKey, DocConfig, Element, Fold, FormatConfig,
+    GenerationSession, Grammar, GrammarConfig, GrammarError, InferConfig, Measure, Production,
+    ProductionWeights, RuleRenderer, SeenFilter, Selector, SessionConfig, SqlFormatter, TokenClass,
+    Trainer, VisitContext, Visitor, Walk, WeightContext,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
@@ -106,6 +116,7 @@ fn test_null_handling() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default)]
 fn test_grammar_config() {
     // Test custom configuration
     let mut config = GrammarConfig::default();
@@ -453,3 +464,1476 @@ fn test_fts_with_relevance_scoring() {
         assert!(query.text.contains("DESC"));
     }
 }
+
+#[test]
+fn test_regex_terminals_from_file() {
+    let grammar_content = r#"
+       <literal> ::= [/0x[0-9a-f]{2,4}/]
+       <literal> ::= [/'[a-z ,\]]*'/]
+       <ratio>   ::= [<literal>, /, <literal>]
+       <assign>  ::= [a, /=, b]
+       <comment> ::= [/*, x, */]
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+
+    let grammar = Grammar::from_file(file.path()).unwrap();
+    let literal = regex::Regex::new(r"^(0x[0-9a-f]{2,4}|'[a-z ,\]]*')$").unwrap();
+
+    for _ in 0..50 {
+        let result = grammar.generate("literal");
+        assert!(literal.is_match(&result.text), "{}", result.text);
+    }
+
+    // A lone slash is still the division operator
+    let result = grammar.generate("ratio");
+    assert!(result.text.contains(" / "), "{}", result.text);
+
+    // So is a slash that no other slash closes before the element ends
+    assert_eq!(grammar.generate("assign").text, "a /= b");
+    assert_eq!(grammar.generate("comment").text, "/* x */");
+}
+
+#[test]
+fn test_regex_terminals_with_add_rule() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("ident", vec!["/[a-z_][a-z0-9_]{0,7}/"])
+        .unwrap();
+
+    let ident = regex::Regex::new(r"^[a-z_][a-z0-9_]{0,7}$").unwrap();
+    for _ in 0..50 {
+        let result = grammar.generate("ident");
+        assert!(ident.is_match(&result.text), "{}", result.text);
+    }
+
+    assert!(grammar.add_rule("broken", vec!["/[a-z/"]).is_err());
+    assert!(grammar.add_rule("anchored", vec!["/a^b/"]).is_err());
+    assert!("<x> ::= [/a$b/]".parse::<Grammar>().is_err());
+}
+
+#[test]
+fn test_bound_aliases_are_in_scope() {
+    let grammar_content = r#"
+       <query>      ::= [FROM, <from_list>, SELECT, <columns>]
+       <from_list>  ::= [<table>]
+       <from_list>  ::= [<table>, ',', <from_list>]
+       <table>      ::= [<table_name>, /t[0-9]{2}/ as $aliases in <query>]
+       <table_name> ::= [users]
+       <table_name> ::= [orders]
+       <columns>    ::= [@pick($aliases), ., id]
+       <columns>    ::= [@pick($aliases), ., id, ',', <columns>]
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+    let grammar = Grammar::from_file(file.path()).unwrap();
+
+    for _ in 0..20 {
+        let result = grammar.generate("query");
+        let (from, select) = result.text.split_once(" SELECT ").unwrap();
+        for column in select.split(", ") {
+            let alias = column.strip_suffix(" . id").unwrap();
+            assert!(
+                from.contains(alias),
+                "{} not in scope: {}",
+                alias,
+                result.text
+            );
+        }
+    }
+}
+
+#[test]
+fn test_as_and_in_are_terminals_unless_binding() {
+    let grammar: Grammar = r#"
+       <q>     ::= [x as y, z]
+       <c>     ::= [SELECT, *, FROM, <t> as alias]
+       <stock> ::= [<t> as $t in stock]
+       <t>     ::= [users]
+       "#
+    .parse()
+    .unwrap();
+
+    assert_eq!(grammar.generate("q").text, "x as y z");
+    assert_eq!(grammar.generate("c").text, "SELECT * FROM users as alias");
+    assert_eq!(grammar.generate("stock").text, "users in stock");
+    assert!(matches!(
+        grammar.rules()["stock"][0].elements[0],
+        Element::Bind(_)
+    ));
+}
+
+#[test]
+fn test_bindings_are_scoped_per_non_terminal() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule(
+            "query",
+            vec!["WITH", "<cte>", "SELECT", "*", "FROM", "$cte", "<tail>"],
+        )
+        .unwrap();
+    grammar
+        .add_rule(
+            "cte",
+            vec![
+                "<cte_name> as $cte in <query>",
+                "AS",
+                "(",
+                "<subquery>",
+                ")",
+            ],
+        )
+        .unwrap();
+    grammar
+        .add_rule(
+            "subquery",
+            vec!["SELECT", "$t", "FROM", "<table_name> as $t"],
+        )
+        .unwrap();
+    grammar
+        .add_rule(
+            "subquery",
+            vec!["SELECT", "*", "FROM", "<table_name> as $t", "WHERE", "$t"],
+        )
+        .unwrap();
+    grammar.add_rule("tail", vec!["WHERE", "$t"]).unwrap();
+    grammar.add_rule("cte_name", vec!["temp1"]).unwrap();
+    grammar.add_rule("table_name", vec!["users"]).unwrap();
+
+    let result = grammar.generate("query");
+    assert!(
+        result.text.starts_with("WITH temp1 AS (SELECT ")
+            && result.text.contains("FROM temp1 WHERE <$t>"),
+        "{}",
+        result.text
+    );
+    // `$t` is only visible after it is bound, and never outside the subquery
+    assert!(
+        result.text.contains("SELECT <$t> FROM users") || result.text.contains("WHERE users)"),
+        "{}",
+        result.text
+    );
+}
+
+#[test]
+fn test_ast_matches_generated_text() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["SELECT", "<columns>", "FROM", "users"])
+        .unwrap();
+    grammar.add_rule("columns", vec!["id"]).unwrap();
+    grammar
+        .add_rule("columns", vec!["id", ",", "<columns>"])
+        .unwrap();
+
+    let result = grammar.generate("query");
+    assert_eq!(result.root.value, "query");
+    assert_eq!(
+        result.root.to_string().replace(' ', ""),
+        result.text.replace(' ', "")
+    );
+    assert!(!result.find_nodes("non_terminal").is_empty());
+}
+
+#[test]
+fn test_predicates_from_file() {
+    let grammar_content = r#"
+       <query> ::= [SELECT, id, FROM, users, <limit>]
+       <limit> ::= [LIMIT, /[0-9]{1,4}/] if small_limit
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+
+    let mut grammar = Grammar::from_file(file.path()).unwrap();
+    grammar.add_predicate("small_limit", |ctx| {
+        let value = ctx.node().children[1].value.parse::<u32>().unwrap();
+        ctx.symbol() == "limit" && value < 1000
+    });
+
+    for _ in 0..50 {
+        let result = grammar.try_generate("query").unwrap();
+        let limit: u32 = result.text.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(limit < 1000, "{}", result.text);
+    }
+}
+
+#[test]
+fn test_predicates_see_partial_derivation() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule(
+            "query",
+            vec!["SELECT", "<select_list>", "FROM", "t", "<tail>"],
+        )
+        .unwrap();
+    grammar.add_rule("select_list", vec!["id"]).unwrap();
+    grammar.add_rule("select_list", vec!["COUNT(*)"]).unwrap();
+    grammar
+        .add_guarded_rule("tail", vec!["GROUP", "BY", "id"], "has_aggregate")
+        .unwrap();
+    grammar.add_rule("tail", vec!["ORDER", "BY", "id"]).unwrap();
+    grammar.add_predicate("has_aggregate", |ctx| {
+        ctx.ancestors() == ["query"] && ctx.generated_text().contains("COUNT(")
+    });
+
+    let mut grouped = false;
+    for _ in 0..100 {
+        let result = grammar.try_generate("query").unwrap();
+        if result.text.contains("GROUP BY") {
+            grouped = true;
+            assert!(result.text.contains("COUNT(*)"), "{}", result.text);
+        }
+    }
+    assert!(grouped);
+}
+
+#[test]
+fn test_unsatisfiable_predicates_report_an_error() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["SELECT", "<n>", "FROM", "t"])
+        .unwrap();
+    grammar
+        .add_guarded_rule("n", vec!["/[0-9]/"], "too_large")
+        .unwrap();
+    grammar.add_predicate("too_large", |ctx| ctx.text().len() > 1);
+
+    match grammar.try_generate("query") {
+        Err(GrammarError::NoValidDerivation(message)) => {
+            assert!(message.contains("'too_large' on <n>"), "{}", message)
+        }
+        other => panic!("expected NoValidDerivation, got {:?}", other),
+    }
+    assert_eq!(grammar.generate("query").text, "<no_valid_derivation>");
+
+    grammar.add_guarded_rule("n", vec!["1"], "missing").unwrap();
+    assert!(matches!(
+        grammar.try_generate("query"),
+        Err(GrammarError::InvalidGrammar(_))
+    ));
+    assert!(matches!(
+        grammar.generate_batch("query", 4, 7, 2),
+        Err(GrammarError::InvalidGrammar(_))
+    ));
+}
+
+#[test]
+#[should_panic(expected = "Unknown predicate 'missing'")]
+fn test_generate_panics_on_invalid_grammar() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_guarded_rule("query", vec!["SELECT", "1"], "missing")
+        .unwrap();
+    grammar.generate("query");
+}
+
+#[test]
+fn test_synthesized_and_inherited_attributes() {
+    let mut grammar = Grammar::new();
+    grammar.add_rule("expr", vec!["<literal>"]).unwrap();
+    grammar
+        .add_rule("expr", vec!["(", "<expr>", "+", "<expr>", ")"])
+        .unwrap();
+    grammar.add_rule("literal", vec!["/[0-9]{1,3}/"]).unwrap();
+    grammar.add_rule("literal", vec!["1.5"]).unwrap();
+    grammar.set_recursion_depth(40);
+
+    grammar.add_inherited("expr", "depth", |ctx| {
+        let depth = ctx.inherited("depth").and_then(AttrValue::as_int);
+        AttrValue::Int(depth.map_or(0, |d| d + 1))
+    });
+    grammar.add_synthesized("literal", "type", |ctx| {
+        let is_int = ctx.text().parse::<i64>().is_ok();
+        AttrValue::from(if is_int { "int" } else { "float" })
+    });
+    grammar.add_synthesized("expr", "type", |ctx| {
+        let types = ctx.children("type");
+        let float = types.iter().any(|t| t.as_str() == Some("float"));
+        AttrValue::from(if float { "float" } else { "int" })
+    });
+
+    fn check(node: &QueryAstNode, depth: i64) {
+        if node.value == "expr" {
+            assert_eq!(node.attribute("depth"), Some(&AttrValue::Int(depth)));
+            for child in &node.children {
+                check(child, depth + 1);
+            }
+        }
+    }
+
+    for _ in 0..20 {
+        let result = grammar.generate("expr");
+        check(&result.root, 0);
+        // Unexpanded symbols have no synthesized attributes
+        if !result.text.contains("<recursion_limit_exceeded>") {
+            let expected = if result.text.contains('.') {
+                "float"
+            } else {
+                "int"
+            };
+            assert_eq!(
+                result.root.attribute("type").and_then(AttrValue::as_str),
+                Some(expected),
+                "{}",
+                result.text
+            );
+        }
+    }
+}
+
+#[test]
+fn test_inherited_attributes_constrain_children() {
+    // Enough budget that deep nesting never leaves <limit> unexpanded
+    let mut grammar = Grammar::with_config(GrammarConfig {
+        max_recursion_depth: 10_000,
+        ..GrammarConfig::default()
+    });
+    grammar
+        .add_rule("query", vec!["SELECT", "<limit>"])
+        .unwrap();
+    grammar
+        .add_rule("query", vec!["SELECT", "(", "<query>", ")", "<limit>"])
+        .unwrap();
+    grammar
+        .add_guarded_rule("limit", vec!["LIMIT", "/[0-9]/"], "within_max")
+        .unwrap();
+
+    grammar.add_inherited("limit", "max", |ctx| {
+        // Nested queries only get small limits
+        let nested = ctx.inherited("nested").and_then(AttrValue::as_bool);
+        AttrValue::Int(if nested == Some(true) { 2 } else { 9 })
+    });
+    grammar.add_inherited("query", "nested", |ctx| {
+        AttrValue::Bool(ctx.parent().is_some())
+    });
+    grammar.add_predicate("within_max", |ctx| {
+        let max = ctx.node().attribute("max").and_then(AttrValue::as_int);
+        let value = ctx.node().children[1].value.parse::<i64>().unwrap();
+        max.is_some_and(|max| value <= max)
+    });
+
+    for _ in 0..20 {
+        let result = grammar.try_generate("query").unwrap();
+        for node in result.find_nodes("non_terminal") {
+            if node.value == "limit" {
+                let value: i64 = node.children[1].value.parse().unwrap();
+                assert!(value <= node.attribute("max").unwrap().as_int().unwrap());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_learned_weights_round_trip() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["SELECT", "<columns>", "FROM", "users"])
+        .unwrap();
+    grammar.add_rule("columns", vec!["*"]).unwrap();
+    grammar
+        .add_rule("columns", vec!["id", ",", "name"])
+        .unwrap();
+
+    let mut corpus = vec!["SELECT * FROM users"; 8];
+    corpus.extend(["select id, name from users", "SELECT id, name FROM users"]);
+    corpus.push("DELETE FROM users");
+
+    let mut trainer = Trainer::new(&grammar, "query").unwrap();
+    let report = trainer.train_corpus(&corpus);
+    assert_eq!(report.parsed, 10);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, 10);
+
+    let weights = trainer.weights();
+    assert_eq!(weights.symbols["query"], vec![1.0]);
+    assert_eq!(weights.symbols["columns"], vec![9.0 / 12.0, 3.0 / 12.0]);
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    weights.to_json_file(file.path()).unwrap();
+    grammar.load_weights(file.path()).unwrap();
+    assert_eq!(grammar.rules()["columns"][0].weight, 0.75);
+
+    let star = (0..400)
+        .filter(|_| grammar.generate("query").text.contains('*'))
+        .count();
+    assert!((240..=360).contains(&star), "{}", star);
+}
+
+#[test]
+fn test_weights_conditioned_on_parent_production() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["SELECT", "<value>", "FROM", "t"])
+        .unwrap();
+    grammar
+        .add_rule(
+            "query",
+            vec!["SELECT", "*", "FROM", "t", "LIMIT", "<value>"],
+        )
+        .unwrap();
+    grammar.add_rule("value", vec!["id"]).unwrap();
+    grammar.add_rule("value", vec!["/[0-9]+/"]).unwrap();
+
+    let mut trainer = Trainer::new(&grammar, "query")
+        .unwrap()
+        .condition_on_parent(true)
+        .smoothing(0.0);
+    let report = trainer.train_corpus([
+        "SELECT id FROM t",
+        "SELECT * FROM t LIMIT 10",
+        "SELECT * FROM t LIMIT 5",
+    ]);
+    assert!(report.failed.is_empty());
+
+    let weights = trainer.weights();
+    assert_eq!(weights.contexts.len(), 2);
+    grammar.apply_weights(&weights).unwrap();
+
+    let limit = regex::Regex::new(r"LIMIT \d+$").unwrap();
+    for _ in 0..50 {
+        let text = grammar.generate("query").text;
+        assert!(
+            text == "SELECT id FROM t" || limit.is_match(&text),
+            "{}",
+            text
+        );
+    }
+
+    assert!(grammar.set_weights("value", &[1.0]).is_err());
+    assert!(grammar.set_weights("value", &[1.0, -1.0]).is_err());
+}
+
+#[test]
+fn test_context_weights_from_file() {
+    let grammar_content = r#"
+       <query>     ::= [SELECT, *, FROM, t, <having>, <join>]
+       <having>    ::= [HAVING, <condition>]
+       <join>      ::= [JOIN, u, ON, <condition>]
+       <condition> ::= [a, =, b] weight 0 when <having> production 0
+       <condition> ::= [COUNT(*), ">", 1] weight 0 weight 3 when <having> production 0
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+
+    let grammar = Grammar::from_file(file.path()).unwrap();
+    assert_eq!(grammar.rules()["condition"][1].weight, 0.0);
+
+    for _ in 0..50 {
+        let text = grammar.generate("query").text;
+        assert_eq!(
+            text, "SELECT * FROM t HAVING COUNT(*) > 1 JOIN u ON a = b",
+            "{}",
+            text
+        );
+    }
+
+    let mut broken = tempfile::NamedTempFile::new().unwrap();
+    broken.write_all(b"<a> ::= [x] weight -1\n").unwrap();
+    assert!(Grammar::from_file(broken.path()).is_err());
+}
+
+#[test]
+fn test_context_weights_by_position() {
+    let mut grammar = Grammar::new();
+    grammar.add_rule("pair", vec!["<x>", ",", "<x>"]).unwrap();
+    grammar.add_rule("x", vec!["1"]).unwrap();
+    grammar.add_rule("x", vec!["2"]).unwrap();
+    grammar.add_rule("x", vec!["3"]).unwrap();
+
+    grammar
+        .set_context_weights(WeightContext::parent("pair"), "x", &[0.0, 0.0, 1.0])
+        .unwrap();
+    grammar
+        .set_context_weights(
+            WeightContext::production("pair", 0).at(0),
+            "x",
+            &[1.0, 0.0, 0.0],
+        )
+        .unwrap();
+
+    for _ in 0..20 {
+        assert_eq!(grammar.generate("pair").text, "1, 3");
+    }
+
+    let weights = ProductionWeights {
+        contexts: vec![ContextWeights {
+            context: WeightContext::production("pair", 0).at(2),
+            symbol: "x".to_string(),
+            weights: vec![0.0, 1.0, 0.0],
+        }],
+        ..ProductionWeights::default()
+    };
+    let json = weights.to_json_string().unwrap();
+    assert!(json.contains("\"position\": 2"), "{}", json);
+    grammar
+        .apply_weights(&ProductionWeights::from_json_str(&json).unwrap())
+        .unwrap();
+
+    assert_eq!(grammar.generate("pair").text, "1, 2");
+}
+
+#[test]
+fn test_session_explores_unused_productions() {
+    let mut grammar = Grammar::new();
+    for table in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+        grammar.add_rule("table", vec![table]).unwrap();
+    }
+
+    let config = SessionConfig {
+        decay: 1e-6,
+        min_factor: 1e-9,
+        ..SessionConfig::default()
+    };
+    let mut session = GenerationSession::with_config(grammar.clone(), config);
+
+    let mut seen = HashSet::new();
+    for _ in 0..8 {
+        seen.insert(session.generate("table").text);
+    }
+    assert_eq!(seen.len(), 8);
+    assert_eq!(session.coverage(), 1.0);
+
+    // The wrapped grammar keeps its own weights
+    assert!(grammar.rules()["table"].iter().all(|p| p.weight == 1.0));
+}
+
+#[test]
+fn test_session_decays_combinations_and_snapshots() {
+    let mut grammar = Grammar::new();
+    grammar.add_rule("query", vec!["SELECT", "<expr>"]).unwrap();
+    grammar.add_rule("query", vec!["DELETE", "<expr>"]).unwrap();
+    grammar.add_rule("expr", vec!["1"]).unwrap();
+    grammar.add_rule("expr", vec!["2"]).unwrap();
+
+    let mut session = GenerationSession::new(grammar);
+    let ast = session.generate("query");
+    let (query, expr) = (
+        ast.root.production.unwrap(),
+        ast.root.children[1].production.unwrap(),
+    );
+    assert_eq!(session.generated(), 1);
+
+    let context = WeightContext::production("query", query);
+    let combination = session.grammar().context_weights(&context, "expr").unwrap();
+    assert_eq!(combination[expr], 0.25);
+    assert_eq!(combination[1 - expr], 1.0);
+    assert_eq!(session.grammar().rules()["expr"][expr].weight, 0.5);
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    session.save_snapshot(file.path()).unwrap();
+    let snapshot = ProductionWeights::from_json_file(file.path()).unwrap();
+    assert_eq!(snapshot.symbols["query"][query], 0.5);
+    assert_eq!(snapshot.contexts.len(), 1);
+
+    session.reset();
+    assert_eq!(session.generated(), 0);
+    assert_eq!(session.coverage(), 0.0);
+    assert!(session
+        .grammar()
+        .context_weights(&context, "expr")
+        .is_none());
+}
+
+#[test]
+fn test_session_decays_position_contexts() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["<expr>", "=", "<expr>"])
+        .unwrap()
+        .add_rule("expr", vec!["1"])
+        .unwrap()
+        .add_rule("expr", vec!["2"])
+        .unwrap()
+        .set_context_weights(
+            WeightContext::production("query", 0).at(2),
+            "expr",
+            &[2.0, 2.0],
+        )
+        .unwrap();
+
+    let mut session = GenerationSession::new(grammar);
+    session.generate("query");
+
+    let combination = session
+        .grammar()
+        .context_weights(&WeightContext::production("query", 0), "expr")
+        .unwrap()
+        .to_vec();
+    let position = session
+        .grammar()
+        .context_weights(&WeightContext::production("query", 0).at(2), "expr")
+        .unwrap();
+    assert!(combination.iter().any(|&weight| weight < 1.0));
+    assert_eq!(position, [2.0 * combination[0], 2.0 * combination[1]]);
+    assert_eq!(session.snapshot().contexts.len(), 2);
+}
+
+#[test]
+fn test_generate_unique_until_exhausted() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule("query", vec!["SELECT", "<column>", "FROM", "<table>"])
+        .unwrap();
+    grammar.add_rule("column", vec!["id"]).unwrap();
+    grammar.add_rule("column", vec!["name"]).unwrap();
+    grammar.add_rule("table", vec!["users"]).unwrap();
+    grammar.add_rule("table", vec!["orders"]).unwrap();
+
+    for filter in [
+        SeenFilter::Exact,
+        SeenFilter::Bloom {
+            capacity: 100,
+            false_positive_rate: 0.001,
+        },
+    ] {
+        let config = DedupConfig {
+            filter,
+            max_attempts: 200,
+            ..DedupConfig::default()
+        };
+        let mut unique = grammar.generate_unique("query", config);
+        let texts: HashSet<String> = unique.by_ref().map(|ast| ast.text).collect();
+
+        assert_eq!(texts.len(), 4);
+        assert!(unique.exhausted());
+        assert!(unique.duplicates() >= 200);
+    }
+}
+
+#[test]
+fn test_generate_unique_by_structure() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule(
+            "query",
+            vec!["SELECT", "*", "FROM", "t", "LIMIT", "/[0-9]{1,3}/"],
+        )
+        .unwrap();
+    grammar
+        .add_rule(
+            "query",
+            vec![
+                "SELECT",
+                "*",
+                "FROM",
+                "t",
+                "WHERE",
+                "name",
+                "=",
+                "/'[a-z]{4}'/",
+            ],
+        )
+        .unwrap();
+
+    let config = DedupConfig {
+        key: DedupKey::Structure,
+        max_attempts: 100,
+        ..DedupConfig::default()
+    };
+    assert_eq!(grammar.generate_unique("query", config).count(), 2);
+}
+
+#[test]
+fn test_batch_output_is_independent_of_jobs() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Grammar>();
+
+    let mut grammar = Grammar::new().with_validator(Box::new(SqlNullValidator));
+    grammar
+        .add_rule(
+            "query",
+            vec![
+                "SELECT", "<column>", "FROM", "t", "WHERE", "<column>", "=", "<value>",
+            ],
+        )
+        .unwrap();
+    grammar.add_rule("column", vec!["/[a-z]{1,8}/"]).unwrap();
+    grammar.add_rule("value", vec!["/[0-9]{1,4}/"]).unwrap();
+    grammar.add_rule("value", vec!["NULL"]).unwrap();
+
+    let texts = |seed, jobs| -> Vec<String> {
+        grammar
+            .generate_batch("query", 101, seed, jobs)
+            .unwrap()
+            .into_iter()
+            .map(|ast| ast.text)
+            .collect()
+    };
+
+    let expected = texts(42, 1);
+    assert_eq!(expected.len(), 101);
+    for jobs in [2, 3, 8, 0] {
+        assert_eq!(texts(42, jobs), expected);
+    }
+    assert_ne!(texts(43, 4), expected);
+
+    // Streaming spans several rounds of chunks and keeps index order
+    let mut streamed = Vec::new();
+    grammar
+        .generate_batch_with("query", 1000, 42, 3, |index, ast| {
+            assert_eq!(index, streamed.len());
+            streamed.push(ast.text);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(streamed[..101], expected[..]);
+    let collected = grammar.generate_batch("query", 1000, 42, 1).unwrap();
+    assert!(collected.iter().map(|ast| &ast.text).eq(streamed.iter()));
+}
+
+#[test]
+fn test_batch_reports_panicking_predicates() {
+    let mut grammar = Grammar::new();
+    grammar
+        .add_guarded_rule("query", vec!["SELECT", "1"], "broken")
+        .unwrap();
+    grammar.add_predicate("broken", |_| panic!("predicate failed"));
+
+    for jobs in [1, 3] {
+        match grammar.generate_batch("query", 10, 42, jobs) {
+            Err(GrammarError::WorkerPanicked(message)) => {
+                assert_eq!(message, "predicate failed")
+            }
+            other => panic!(
+                "expected WorkerPanicked with {} jobs, got {:?}",
+                jobs, other
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_generate_to_matches_generate() {
+    let grammar_content = r#"
+       <query>      ::= [FROM, <from_list>, SELECT, <columns>]
+       <from_list>  ::= [<table>]
+       <from_list>  ::= [<table>, ',', <from_list>]
+       <table>      ::= [<table_name>, /t[0-9]{2}/ as $aliases in <query>]
+       <table_name> ::= [users]
+       <table_name> ::= [orders]
+       <columns>    ::= [@pick($aliases), ., id]
+       <columns>    ::= [@pick($aliases), ., id, ',', <columns>]
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+    let grammar = Grammar::from_file(file.path()).unwrap();
+
+    for seed in 0..20 {
+        let expected = grammar
+            .try_generate_with_rng("query", &mut StdRng::seed_from_u64(seed))
+            .unwrap();
+
+        let mut out = Vec::new();
+        let root = grammar
+            .generate_to_with_rng("query", &mut StdRng::seed_from_u64(seed), &mut out, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected.text);
+        assert_eq!(format!("{:?}", root), format!("{:?}", expected.root));
+    }
+
+    let mut out = Vec::new();
+    grammar.generate_to("query", &mut out).unwrap();
+    assert!(String::from_utf8(out).unwrap().starts_with("FROM "));
+}
+
+#[test]
+fn test_generate_to_writes_incrementally() {
+    /// Records the size of every write
+    struct Chunks(Vec<usize>, Vec<u8>);
+
+    impl Write for Chunks {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut grammar = Grammar::with_config(GrammarConfig {
+        max_recursion_depth: 1_000_000,
+        ..GrammarConfig::default()
+    });
+    for (symbol, child) in [
+        ("script", "<block>"),
+        ("block", "<rows>"),
+        ("rows", "<row>"),
+    ] {
+        grammar.add_rule(symbol, vec![child; 16]).unwrap();
+    }
+    grammar
+        .add_rule(
+            "row",
+            vec!["INSERT INTO t VALUES", "(", "/[0-9]{3}/", ")", ";"],
+        )
+        .unwrap();
+
+    let mut chunks = Chunks(Vec::new(), Vec::new());
+    grammar
+        .generate_to_with_rng("script", &mut StdRng::seed_from_u64(3), &mut chunks, false)
+        .unwrap();
+
+    let expected = grammar
+        .try_generate_with_rng("script", &mut StdRng::seed_from_u64(3))
+        .unwrap();
+    assert_eq!(String::from_utf8(chunks.1).unwrap(), expected.text);
+    assert!(expected.text.len() > 100_000);
+    assert!(chunks.0.len() > 10);
+    assert!(chunks.0.iter().all(|&len| len < 10_000));
+
+    // Output guarded by predicates is held back, but comes out the same
+    grammar.add_predicate("even", |ctx| {
+        ctx.text().ends_with(['0', '2', '4', '6', '8'])
+    });
+    grammar
+        .add_guarded_rule("rows", vec!["<row>", "<row>", "(", "/[0-9]/", ")"], "even")
+        .unwrap();
+    for seed in 0..10 {
+        let mut chunks = Chunks(Vec::new(), Vec::new());
+        grammar
+            .generate_to_with_rng(
+                "script",
+                &mut StdRng::seed_from_u64(seed),
+                &mut chunks,
+                false,
+            )
+            .unwrap();
+        let expected = grammar
+            .try_generate_with_rng("script", &mut StdRng::seed_from_u64(seed))
+            .unwrap();
+        assert_eq!(String::from_utf8(chunks.1).unwrap(), expected.text);
+    }
+}
+
+#[test]
+fn test_stream_deep_derivation() {
+    // Expansion doesn't recurse, so the depth is bounded by the budget
+    // rather than the thread's stack
+    let mut grammar: Grammar = "<l> ::= [a, <l>]".parse().unwrap();
+    grammar.set_recursion_depth(100_000);
+
+    let mut out = Vec::new();
+    grammar
+        .generate_to_with_rng("l", &mut StdRng::seed_from_u64(0), &mut out, false)
+        .unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("a a a"));
+    assert!(text.ends_with("a <recursion_limit_exceeded>"));
+    assert_eq!(
+        text.split_whitespace()
+            .filter(|&token| token == "a")
+            .count(),
+        50_000
+    );
+}
+
+#[test]
+fn test_deep_derivation_scopes() {
+    // Each level binds its own $v, visible again once the inner levels'
+    // scopes are closed, so the output reads the same both ways
+    let mut grammar: Grammar = "<l> ::= [/[a-z]/ as $v, <l>, $v]".parse().unwrap();
+    grammar.set_recursion_depth(30_000);
+
+    let mut out = Vec::new();
+    grammar
+        .generate_to_with_rng("l", &mut StdRng::seed_from_u64(1), &mut out, false)
+        .unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let letters = text.strip_suffix(" <recursion_limit_exceeded>").unwrap();
+    assert_eq!(letters.len(), 2 * 2 * 10_000 - 1);
+    assert_eq!(letters.chars().rev().collect::<String>(), letters);
+}
+
+#[test]
+fn test_token_stream_with_classes() {
+    let grammar_content = r#"
+       <query>  ::= [select :keyword, <column>, from :keyword, <table>, <filter>]
+       <column> ::= [/[a-z]{3}/ :identifier as $column in <query>, ',', count, '(', *, ')']
+       <table>  ::= [users]
+       <filter> ::= [WHERE, $column :identifier, '=', /[0-9]{2}/]
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+    let grammar = Grammar::from_file(file.path()).unwrap();
+
+    let ast = grammar.generate("query");
+    let tokens = ast.tokens();
+    let classes: Vec<TokenClass> = tokens.iter().map(|token| token.class).collect();
+    use TokenClass::*;
+    assert_eq!(
+        classes,
+        [
+            Keyword,
+            Identifier,
+            Punctuation,
+            Identifier,
+            Punctuation,
+            Operator,
+            Punctuation,
+            Keyword,
+            Identifier,
+            Keyword,
+            Identifier,
+            Operator,
+            Literal
+        ]
+    );
+    assert_eq!(tokens[0].text, "select");
+    assert_eq!(tokens[1].text, tokens[10].text);
+
+    let text: String = tokens.iter().map(|token| token.text.as_str()).collect();
+    assert_eq!(text, ast.text.replace(' ', ""));
+
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule(
+            "query",
+            vec!["limit :keyword", "/[0-9]/ :literal", "$x :identifier"],
+        )
+        .unwrap();
+    let tokens = grammar.generate("query").tokens();
+    assert_eq!(tokens[0].class, Keyword);
+    assert_eq!(tokens[1].class, Literal);
+    assert_eq!(tokens[2].text, "<$x>");
+
+    assert!(grammar.add_rule("bad", vec!["<query> :keyword"]).is_err());
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"<q> ::= [SELECT :verb]\n").unwrap();
+    assert!(Grammar::from_file(file.path()).is_err());
+}
+
+#[test]
+fn test_renderer_layout_directives() {
+    let grammar_content = r#"
+       <query>   ::= [SELECT, @indent, <columns>, @dedent, @newline, FROM, <table>]
+       <columns> ::= [@newline, <column>]
+       <columns> ::= [@newline, <column>, ',', <columns>]
+       <column>  ::= [$, ~, /[a-z]{2}/, '~', x]
+       <table>   ::= [t, ~, ., ~, /[0-9]/, @space, ',']
+       "#;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(grammar_content.as_bytes()).unwrap();
+    let mut grammar = Grammar::from_file(file.path()).unwrap();
+    grammar.set_config(GrammarConfig {
+        max_recursion_depth: 10_000,
+        ..GrammarConfig::default()
+    });
+
+    let column = regex::Regex::new(r"^  \$[a-z]{2} ~ x,?$").unwrap();
+    for _ in 0..10 {
+        let ast = grammar.generate("query");
+        let lines: Vec<&str> = ast.text.lines().collect();
+        assert_eq!(lines[0], "SELECT", "{}", ast.text);
+        for line in &lines[1..lines.len() - 1] {
+            assert!(column.is_match(line), "{}", ast.text);
+        }
+        assert!(
+            lines[lines.len() - 1].starts_with("FROM t."),
+            "{}",
+            ast.text
+        );
+        assert!(lines[lines.len() - 1].ends_with(" ,"));
+
+        // Re-rendering the AST, or a parse of the text, gives the same text
+        assert_eq!(ast.root.to_string(), ast.text);
+        let parsed = grammar.parse("query", &ast.text).unwrap();
+        assert_eq!(grammar.render(&parsed), ast.text);
+    }
+
+    let renderer = RuleRenderer {
+        no_space_after: vec!["(".to_string(), "$".to_string()],
+        indent: "\t".to_string(),
+        ..RuleRenderer::default()
+    };
+    let grammar = Grammar::from_file(file.path())
+        .unwrap()
+        .with_renderer(Box::new(renderer));
+    let ast = grammar.generate("query");
+    assert!(ast.text.starts_with("SELECT\n\t$"), "{}", ast.text);
+    assert_eq!(grammar.render(&ast.root), ast.text);
+
+    let mut grammar = Grammar::with_config(GrammarConfig {
+        auto_spacing: false,
+        ..GrammarConfig::default()
+    });
+    grammar
+        .add_rule("call", vec!["f", "~", "(", "x", ",", "y", ")"])
+        .unwrap();
+    assert_eq!(grammar.generate("call").text, "f( x , y )");
+}
+
+#[test]
+fn test_pretty_formatter_preserves_tokens() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let formatter = SqlFormatter::new(FormatConfig {
+        line_width: 40,
+        ..FormatConfig::default()
+    });
+    let squash = |text: &str| text.split_whitespace().collect::<String>();
+
+    let mut nested = 0;
+    for seed in 0..50 {
+        let ast = grammar.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+        let pretty = formatter.format(&ast);
+        assert_eq!(squash(&pretty), squash(&ast.text));
+
+        let lines: Vec<&str> = pretty.lines().collect();
+        if ast.text.starts_with("SELECT") {
+            assert!(lines[0].starts_with("SELECT"), "{}", pretty);
+            assert!(
+                lines.iter().any(|line| line.starts_with("FROM ")),
+                "{}",
+                pretty
+            );
+        }
+        nested += lines
+            .iter()
+            .filter(|line| line.starts_with("  SELECT"))
+            .count();
+    }
+    assert!(nested > 0);
+
+    let mut grammar = Grammar::new();
+    grammar
+        .add_rule(
+            "query",
+            vec!["select :keyword", "a", ",", "b", "from :keyword", "t"],
+        )
+        .unwrap();
+    let ast = grammar.generate("query");
+    assert_eq!(formatter.format(&ast), "SELECT a, b\nFROM t");
+    let lower = SqlFormatter::new(FormatConfig {
+        uppercase_keywords: false,
+        line_width: 8,
+        indent_width: 4,
+    });
+    assert_eq!(lower.format(&ast), "select\n    a,\n    b\nfrom t");
+}
+
+#[test]
+fn test_visitors_over_generated_queries() {
+    /// Table names, stopping at the first join
+    #[derive(Default)]
+    struct Tables {
+        names: Vec<String>,
+        joined: bool,
+    }
+
+    impl Visitor for Tables {
+        fn enter(&mut self, node: &QueryAstNode, ctx: &VisitContext<'_>) -> Walk {
+            if node.value == "join_clause" {
+                self.joined = true;
+                return Walk::Stop;
+            }
+            if ctx.parent() == Some("table_name") {
+                self.names.push(node.value.clone());
+            }
+            Walk::Continue
+        }
+    }
+
+    /// Renames every table
+    struct Rename;
+
+    impl Fold for Rename {
+        fn exit(&mut self, mut node: QueryAstNode, ctx: &VisitContext<'_>) -> QueryAstNode {
+            if ctx.parent() == Some("table_name") {
+                node.value = format!("{}_v2", node.value);
+            }
+            node
+        }
+    }
+
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let mut stopped = HashSet::new();
+    for seed in 0..100 {
+        let ast = grammar.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+
+        let mut tables = Tables::default();
+        let walk = ast.walk(&mut tables);
+        assert_eq!(walk == Walk::Stop, tables.joined);
+        stopped.insert(tables.joined);
+
+        let renamed = ast.fold(&mut Rename);
+        let mut all = Tables::default();
+        renamed.walk(&mut all);
+        for name in all.names.iter().take(tables.names.len()) {
+            assert!(name.ends_with("_v2"), "{}", renamed.text);
+        }
+        assert_eq!(
+            renamed.text.matches("_v2").count(),
+            ast.find_nodes("non_terminal")
+                .iter()
+                .filter(|node| node.value == "table_name" && !node.children.is_empty())
+                .count()
+        );
+    }
+    assert_eq!(stopped.len(), 2);
+}
+
+#[test]
+fn test_selectors_over_generated_queries() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let second: Selector = "<join_clause> <column_reference>:nth(2)".parse().unwrap();
+    let mut joined = false;
+    for seed in 0..100 {
+        let mut ast = grammar.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+
+        for node in ast.select("<having_clause> <condition>").unwrap() {
+            assert_eq!(node.value, "condition");
+        }
+        let joins = ast.select("<join_clause>").unwrap().len();
+        let seconds = second.select(&ast.root);
+        assert!(
+            seconds.len()
+                <= ast
+                    .select("<join_clause> <column_reference>")
+                    .unwrap()
+                    .len()
+        );
+        assert!(seconds.iter().all(|node| node.value == "column_reference"));
+        joined |= joins > 0;
+
+        let tables = ast.select("<table_name> > *").unwrap().len();
+        let replaced = ast
+            .replace("<table_name> > *", |_| {
+                QueryAstNode::new("terminal", "tbl_x")
+            })
+            .unwrap();
+        assert_eq!(replaced, tables);
+        assert_eq!(ast.text, ast.root.to_string());
+        assert_eq!(ast.text.matches("tbl_x").count(), tables);
+    }
+    assert!(joined);
+}
+
+#[test]
+fn test_ast_serialization_round_trips() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    for seed in 0..50 {
+        let ast = grammar.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+
+        let json = ast.to_json_string().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], grammar_gen::AST_SCHEMA_VERSION);
+        assert_eq!(value["root"]["span"][1], ast.text.len());
+
+        for loaded in [
+            QueryAst::from_json_str(&json).unwrap(),
+            QueryAst::from_binary(&ast.to_binary().unwrap()).unwrap(),
+        ] {
+            assert_eq!(loaded.text, ast.text);
+            assert_eq!(loaded.type_name, ast.type_name);
+            assert_eq!(loaded.root.to_debug_string(), ast.root.to_debug_string());
+            assert_eq!(loaded.root.to_string(), ast.text);
+        }
+    }
+}
+
+#[test]
+fn test_derivation_tree_graphs() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    for seed in 0..20 {
+        let ast = grammar.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+        let nodes = ast.select("*").unwrap().len();
+
+        let dot = ast.to_dot();
+        assert!(dot.starts_with("digraph AST {"));
+        assert_eq!(dot.matches("[label=").count(), nodes);
+        assert_eq!(dot.matches(" -> ").count(), nodes - 1);
+
+        let mut written = Vec::new();
+        ast.write_mermaid(&mut written).unwrap();
+        let mermaid = String::from_utf8(written).unwrap();
+        assert_eq!(mermaid, ast.to_mermaid());
+        assert_eq!(mermaid.matches(" --> ").count(), nodes - 1);
+    }
+
+    let dot = grammar.to_dot();
+    assert!(dot.contains("\"query\" -> \"select_query\""));
+    assert_eq!(dot, grammar.to_dot());
+}
+
+#[test]
+fn test_html_documentation() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let config = DocConfig {
+        samples: 2,
+        ..DocConfig::default()
+    };
+    let html = grammar.to_html(&config).unwrap();
+
+    assert_eq!(html.matches("<section id=").count(), grammar.rules().len());
+    assert_eq!(html.matches("<svg").count(), grammar.rules().len());
+    assert_eq!(html.matches("<pre>").count(), 2 * grammar.rules().len());
+    for symbol in grammar.rules().keys() {
+        assert!(
+            html.contains(&format!("id=\"sym-{}\"", symbol)),
+            "{}",
+            symbol
+        );
+    }
+    assert!(html.contains("<a href=\"#sym-join_clause\">"));
+    assert_eq!(html, grammar.to_html(&config).unwrap());
+}
+
+#[test]
+fn test_html_documentation_of_guarded_grammar() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"<query> ::= [SELECT, <n>] if small\n<n> ::= [1]\n")
+        .unwrap();
+    let grammar = Grammar::from_file(file.path()).unwrap();
+    assert!(matches!(
+        grammar.to_html(&DocConfig::default()),
+        Err(GrammarError::InvalidGrammar(_))
+    ));
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_grammar-gen"))
+        .arg("doc")
+        .arg(file.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unknown predicate 'small'"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn test_normalised_grammar_restores_trees() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let transformed = grammar
+        .eliminate_left_recursion()
+        .unwrap()
+        .factor_prefixes()
+        .unwrap();
+    let result = transformed.grammar();
+    for (symbol, productions) in result.rules() {
+        for production in productions {
+            assert_ne!(
+                production.elements.first(),
+                Some(&Element::NonTerminal(symbol.clone()))
+            );
+        }
+    }
+
+    for seed in 0..20 {
+        let ast = result.generate_with_rng("query", &mut StdRng::seed_from_u64(seed));
+        let restored = transformed.restore_ast(&ast).unwrap();
+        assert_eq!(grammar.render(&restored.root), ast.text);
+        for node in restored.select("*").unwrap() {
+            if let Some(p) = node.production {
+                assert!(p < grammar.rules()[&node.value].len());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sql_grammar_ambiguity() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let ambiguity = grammar
+        .find_ambiguity("condition", &AmbiguityConfig::default())
+        .unwrap()
+        .unwrap();
+
+    // <number> lists some digits twice
+    assert_eq!(ambiguity.sentence, "id = 1");
+    let [first, second] = ambiguity.divergence();
+    assert_eq!(first.value, "number");
+    assert_ne!(first.production, second.production);
+    assert!(ambiguity.to_string().contains("differing at <number> #0 and #7"));
+}
+
+#[test]
+fn test_grammar_text_round_trips() {
+    let text = r#"
+       <query>  ::= [SELECT :keyword, @indent, <column> as $c in <query>, @dedent, ~, <from>] if has_table weight 2
+       <query>  ::= [SELECT, $c, "it's", '<', ',', \', /[a-z]{2}/, @pick($c), ' ', '@space']
+       <column> ::= [id] weight 0.5 weight 3 when <query> production 0 position 3
+       <column> ::= [name] weight 0 when <query>
+       <from>   ::= [FROM, t1]
+    "#;
+    let grammar: Grammar = text.parse().unwrap();
+    let written = grammar.to_text();
+    let reread: Grammar = written.parse().unwrap();
+    assert_eq!(reread.to_text(), written);
+
+    for (symbol, productions) in grammar.rules() {
+        let reread = &reread.rules()[symbol];
+        assert_eq!(reread.len(), productions.len());
+        for (a, b) in productions.iter().zip(reread) {
+            assert_eq!(a.elements, b.elements);
+            assert_eq!(a.guard, b.guard);
+            assert_eq!(a.weight, b.weight);
+        }
+    }
+    let context = WeightContext::production("query", 0).at(3);
+    assert_eq!(reread.context_weights(&context, "column"), Some(&[3.0, 1.0][..]));
+    assert_eq!(
+        reread.context_weights(&WeightContext::parent("query"), "column"),
+        Some(&[0.5, 0.0][..])
+    );
+
+    let sql = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let slice = sql
+        .slice_excluding("select_statement", &["join_clause"])
+        .unwrap();
+    assert!(slice.excluded["join_clause"].contains("table_reference"));
+    let reread: Grammar = slice.grammar.to_text().parse().unwrap();
+    assert!(!reread.has_non_terminal("join_clause"));
+    assert!(!reread.has_non_terminal("insert_query"));
+    assert_eq!(reread.rules().len(), slice.grammar.rules().len());
+}
+
+#[test]
+fn test_added_rules_extend_context_weights() {
+    let mut grammar: Grammar = r#"
+       <q> ::= [<c>]
+       <c> ::= [x] weight 100 when <q>
+       <c> ::= [y]
+    "#
+    .parse()
+    .unwrap();
+    grammar.add_rule("c", vec!["z"]).unwrap();
+    grammar.add_guarded_rule("c", vec!["w"], "never").unwrap();
+    grammar.add_production(
+        "c",
+        Production {
+            elements: vec![Element::Terminal("v".to_string())],
+            guard: None,
+            weight: 2.0,
+        },
+    );
+
+    let context = WeightContext::parent("q");
+    assert_eq!(
+        grammar.context_weights(&context, "c"),
+        Some(&[100.0, 1.0, 1.0, 1.0, 2.0][..])
+    );
+    let reread: Grammar = grammar.to_text().parse().unwrap();
+    assert_eq!(
+        reread.context_weights(&context, "c"),
+        grammar.context_weights(&context, "c")
+    );
+}
+
+#[test]
+fn test_quoted_terminals_round_trip() {
+    let grammar: Grammar = r#"
+       <q> ::= [x 'as' $v, "it's \"quoted\"", 'a b\\', '\d+', in]
+    "#
+    .parse()
+    .unwrap();
+    let expected = [
+        Element::Terminal("x".to_string()),
+        Element::Terminal("as".to_string()),
+        Element::Variable("v".to_string()),
+        Element::Terminal(r#"it's "quoted""#.to_string()),
+        Element::Terminal(r"a b\".to_string()),
+        Element::Terminal(r"\d+".to_string()),
+        Element::Terminal("in".to_string()),
+    ];
+    assert_eq!(grammar.rules()["q"][0].elements, expected);
+
+    let written = grammar.to_text();
+    let reread: Grammar = written.parse().unwrap();
+    assert_eq!(reread.rules()["q"][0].elements, expected);
+    assert_eq!(reread.to_text(), written);
+}
+
+#[test]
+fn test_minimal_examples_parse_back() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let shortest = grammar.shortest_derivations();
+    assert!(shortest.unproductive().is_empty());
+
+    for symbol in grammar.rules().keys() {
+        for measure in [Measure::Tokens, Measure::Depth] {
+            let example = shortest.example(symbol, measure).unwrap();
+            let size = shortest.get(symbol, measure).unwrap();
+            assert_eq!(example.tokens().len(), size.tokens, "<{}>", symbol);
+            grammar.parse(symbol, &example.text).unwrap();
+        }
+    }
+
+    let query = grammar.minimal_example("select_statement").unwrap();
+    assert!(query.text.starts_with("SELECT"));
+}
+
+#[test]
+fn test_text_probability_covers_its_derivations() {
+    let grammar = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+
+    for symbol in ["select_statement", "expression", "table_reference"] {
+        let example = grammar.minimal_example(symbol).unwrap();
+        let tree = grammar.log_probability(&example.root).unwrap();
+        let text = grammar.text_log_probability(symbol, &example.text).unwrap();
+        assert!(tree.is_finite() && tree < 0.0, "<{}>", symbol);
+        assert!(text >= tree - 1e-9 && text <= 0.0, "<{}>", symbol);
+    }
+
+    for seed in 0..10 {
+        let ast = grammar
+            .try_generate_with_rng("query", &mut StdRng::seed_from_u64(seed))
+            .unwrap();
+        let score = grammar.log_probability(&ast.root).unwrap();
+        assert!(score.is_finite() && score < 0.0, "{}", ast.text);
+    }
+}
+
+#[test]
+fn test_inferred_grammar_derives_its_corpus() {
+    let sql = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+    let corpus: Vec<String> = (0..30)
+        .map(|seed| {
+            sql.try_generate_with_rng("query", &mut StdRng::seed_from_u64(seed))
+                .unwrap()
+                .text
+        })
+        .collect();
+
+    let grammar = Grammar::infer(&corpus, &InferConfig::default()).unwrap();
+    assert!(grammar.rules().len() > 2);
+    for query in &corpus {
+        grammar.parse("start", query).unwrap();
+    }
+
+    // The inferred grammar reads back and generates
+    let reloaded: Grammar = grammar.to_text().parse().unwrap();
+    assert_eq!(reloaded.rules().len(), grammar.rules().len());
+    reloaded
+        .try_generate_with_rng("start", &mut StdRng::seed_from_u64(0))
+        .unwrap();
+}
+
+#[test]
+fn test_grammar_diff_of_edited_sql_grammar() {
+    let old = Grammar::from_file("examples/sql_grammar.txt").unwrap();
+
+    // Writing the grammar out sorts and reformats its rules
+    let rewritten: Grammar = old.to_text().parse().unwrap();
+    assert!(old.diff(&rewritten).is_empty());
+
+    let mut new = old.clone();
+    new.add_rule("table_name", vec!["audit_log"])
+        .unwrap()
+        .add_rule("select_statement", vec!["SELECT", "<missing>"])
+        .unwrap();
+    let diff = old.diff(&new);
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+    assert_eq!(diff.changed["table_name"].added, ["[audit_log]"]);
+    assert!(diff.undefined.contains_key("missing"));
+    assert!(new.diff(&old).undefined.is_empty());
+}